@@ -1,14 +1,13 @@
 use super::CPU;
 use super::CpuUpdateTypes;
-use winRing0::WinRing0;
+use win_ring0::Ring0Session;
 use x86::msr::IA32_PACKAGE_THERM_STATUS;
 use x86::msr::MSR_TEMPERATURE_TARGET;
 use raw_cpuid::CpuId;
-use std::rc::Rc;
 
 pub struct IntelCPU {
     tj_max: u32,
-    driver: Option<&'static Rc<WinRing0>>,
+    driver: Option<Ring0Session>,
     cores: u8
 }
 
@@ -32,13 +31,13 @@ impl<'a> IntelCPU {
             let drv = self.driver.as_ref();
             out = drv.unwrap().readMsr(MSR_TEMPERATURE_TARGET).unwrap();
         
-            println!("Output of READ_MSR: {}", out);
+            tracing::trace!(msr = MSR_TEMPERATURE_TARGET, out, "read MSR_TEMPERATURE_TARGET");
             //eax = x.checked_shl(32).unwrap_or(0);
             edx = ((out >> 32) & 0xFFFFFFFF) as u32;
             eax = (out & 0xFFFFFFFF) as u32;
     
     
-            println!("eax: {} - edx: {}", eax, edx);
+            tracing::trace!(eax, edx, "MSR_TEMPERATURE_TARGET registers");
         
             result = (eax >> 16) & 0xff;
         }
@@ -56,7 +55,7 @@ impl CPU for IntelCPU {
         return 3;
     }
 
-    fn set_driver(&mut self, driver: &'static Rc<WinRing0>) {
+    fn set_driver(&mut self, driver: Ring0Session) {
         self.driver = Some(driver);
     }
 }
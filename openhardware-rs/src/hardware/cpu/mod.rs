@@ -1,7 +1,5 @@
-use winRing0::WinRing0;
+use win_ring0::Ring0Session;
 extern crate raw_cpuid;
-use raw_cpuid::CpuId;
-use std::rc::Rc;
 
 pub mod intel;
 use intel::IntelCPU;
@@ -13,35 +11,45 @@ pub enum CpuUpdateTypes {
     All
 }
 pub struct CPUDevice { 
-    driver: Option<Rc<WinRing0>>,
-    cpu: Option<Rc<dyn CPU + 'static>>
+    driver: Option<Ring0Session>,
+    cpu: Option<Box<dyn CPU>>
 }
 
 impl CPUDevice {
-    pub fn new(self) -> Self {
+    pub fn new() -> Self {
         CPUDevice {
             driver: None,
             cpu: None
         }
     }
 
+    /// Read the CPU through `driver`. Takes effect on the next [CPUDevice::init].
+    pub fn set_driver(&mut self, driver: Ring0Session) {
+        self.driver = Some(driver);
+    }
+
+    pub fn update(&mut self, update_type: CpuUpdateTypes) {
+        if let Some(cpu) = self.cpu.as_mut() {
+            cpu.update(update_type);
+        }
+    }
+
     pub fn init(&mut self) -> Result<(), String> {
         let cpuid = raw_cpuid::CpuId::new();
         let vendor_info = cpuid.get_vendor_info().unwrap();
         let ex_vendor_info = cpuid.get_feature_info().unwrap();
         let family_id = ex_vendor_info.family_id();
-        println!("vendor info: {} - family id: {}", vendor_info.as_string(), family_id);
+        tracing::debug!(vendor = vendor_info.as_string(), family_id, "detected cpu");
         
         match vendor_info.as_string() {
             "GenuineIntel" => {
-                let cpu = IntelCPU::new();
+                let mut cpu = IntelCPU::new();
 
-                if self.driver.is_some() {
-                    let drv = self.driver.as_ref();
-                    cpu.set_driver(drv.unwrap());
+                if let Some(driver) = self.driver.as_ref() {
+                    cpu.set_driver(driver.clone());
                 }
 
-                self.cpu = Some(Rc::from(cpu));
+                self.cpu = Some(Box::new(cpu));
 
                 return Ok(());
             },
@@ -52,10 +60,10 @@ impl CPUDevice {
     }
 }
 
-pub trait CPU {
+pub trait CPU: Send {
     fn update(&mut self, updateType: CpuUpdateTypes);
     fn cores(&mut self) -> u8;
-    fn set_driver(&mut self, driver: &Rc<WinRing0>);
+    fn set_driver(&mut self, driver: Ring0Session);
 }
 
 
@@ -2,3 +2,16 @@ pub mod cpu;
 pub use cpu::CPU;
 pub use cpu::CPUDevice;
 pub use cpu::CpuUpdateTypes;
+
+use win_ring0::WinRing0;
+
+/// Detect the CPU, reading it through a session on the winRing0 driver
+pub fn get_cpu(r0: &WinRing0) -> Result<CPUDevice, String> {
+    let session = r0.session().map_err(|err| err.to_string())?;
+
+    let mut cpu = CPUDevice::new();
+    cpu.set_driver(session);
+    cpu.init()?;
+
+    Ok(cpu)
+}
@@ -1,15 +1,14 @@
 use openhardware::hardware::get_cpu;
-use openhardware::hardware::cpu::UpdateTypes;
-use openhardware::hardware::cpu::CPU;
+use openhardware::hardware::CpuUpdateTypes;
 use win_ring0::WinRing0;
 
 fn main() {
 
-    let mut r0: Box<WinRing0> = Box::from(WinRing0::new());
+    let mut r0: Box<WinRing0> = Box::from(WinRing0::new().unwrap());
 
     println!("Installing ring0 driver");
     match r0.install() {
-        Ok(()) => { println!("Driver installed"); }
+        Ok(_) => { println!("Driver installed"); }
         Err(err) => { println!("Error: {}", err); }
     }
 
@@ -20,8 +19,8 @@ fn main() {
     }
 
     {
-        let mut cpu = get_cpu(&mut r0).unwrap();
-        cpu.update(UpdateTypes::All);
+        let mut cpu = get_cpu(&r0).unwrap();
+        cpu.update(CpuUpdateTypes::All);
     }
 
     println!("Closing ring0 driver");
@@ -0,0 +1,194 @@
+//! Running code on a specific logical processor
+//!
+//! Some registers exist once per core, like most MSRs. A driver reads them on the
+//! processor the calling thread happens to run on, so the thread has to be pinned to
+//! the right one first. A [ThreadAffinity] does that through an [AffinityBackend]:
+//! [ProcessorGroupBackend] on Windows, [SchedAffinityBackend] on Linux.
+//!
+//! Logical processors are numbered from 0 across the whole machine. On Windows machines
+//! with more than 64 of them, they are spread over processor groups; the index
+//! counts through the groups in order.
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use tracing::trace;
+
+use crate::error::{Error, Result};
+
+#[cfg(windows)]
+mod win32;
+#[cfg(target_os = "linux")]
+mod sched;
+mod unsupported;
+
+#[cfg(windows)]
+pub use win32::ProcessorGroupBackend;
+#[cfg(not(windows))]
+pub use unsupported::ProcessorGroupBackend;
+#[cfg(target_os = "linux")]
+pub use sched::SchedAffinityBackend;
+#[cfg(not(target_os = "linux"))]
+pub use unsupported::SchedAffinityBackend;
+
+/// A way to pin the calling thread to a logical processor
+pub trait AffinityBackend: Send + Sync {
+    /// Number of logical processors threads can be pinned to
+    fn processor_count(&self) -> Result<usize>;
+
+    /// Index of the logical processor the calling thread is running on
+    fn current_processor(&self) -> Result<usize>;
+
+    /// Pin the calling thread to logical processor `cpu`. The previous affinity is
+    /// restored when the token is dropped.
+    fn pin(&self, cpu: usize) -> Result<Box<dyn AffinityToken>>;
+}
+
+/// Proof that the calling thread is pinned. Its previous affinity is restored when
+/// the token is dropped.
+pub trait AffinityToken {}
+
+/// Pins the calling thread to logical processors.
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use win_kernel_driver::{AffinityBackend, AffinityToken, Result, ThreadAffinity};
+///
+/// // A machine with 4 logical processors, which only remembers where the thread runs
+/// #[derive(Default)]
+/// struct FourProcessors {
+///     current: Arc<AtomicUsize>
+/// }
+///
+/// struct Restore {
+///     current: Arc<AtomicUsize>,
+///     previous: usize
+/// }
+///
+/// impl AffinityToken for Restore {}
+///
+/// impl Drop for Restore {
+///     fn drop(&mut self) {
+///         self.current.store(self.previous, Ordering::SeqCst);
+///     }
+/// }
+///
+/// impl AffinityBackend for FourProcessors {
+///     fn processor_count(&self) -> Result<usize> {
+///         Ok(4)
+///     }
+///
+///     fn current_processor(&self) -> Result<usize> {
+///         Ok(self.current.load(Ordering::SeqCst))
+///     }
+///
+///     fn pin(&self, cpu: usize) -> Result<Box<dyn AffinityToken>> {
+///         let previous = self.current.swap(cpu, Ordering::SeqCst);
+///         Ok(Box::new(Restore { current: self.current.clone(), previous }))
+///     }
+/// }
+///
+/// let affinity = ThreadAffinity::new().set_backend(FourProcessors::default());
+///
+/// for cpu in 0..affinity.processor_count().unwrap() {
+///     let current = affinity.run_on(cpu, || affinity.current_processor().unwrap()).unwrap();
+///     assert_eq!(current, cpu);
+/// }
+///
+/// assert_eq!(affinity.current_processor().unwrap(), 0);
+/// assert!(affinity.pin(4).is_err());
+/// ```
+#[derive(Clone)]
+pub struct ThreadAffinity {
+    backend: Arc<dyn AffinityBackend>
+}
+
+impl ThreadAffinity {
+    /// Use the backend of the current platform: [ProcessorGroupBackend] on Windows,
+    /// [SchedAffinityBackend] on Linux
+    pub fn new() -> Self {
+        #[cfg(target_os = "linux")]
+        let backend: Arc<dyn AffinityBackend> = Arc::new(SchedAffinityBackend);
+        #[cfg(not(target_os = "linux"))]
+        let backend: Arc<dyn AffinityBackend> = Arc::new(ProcessorGroupBackend);
+
+        ThreadAffinity { backend }
+    }
+
+    /// Pin threads through `backend` instead
+    pub fn set_backend<B: AffinityBackend + 'static>(mut self, backend: B) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Number of logical processors
+    pub fn processor_count(&self) -> Result<usize> {
+        self.backend.processor_count()
+    }
+
+    /// Index of the logical processor the calling thread is running on. Unless the
+    /// thread is pinned, it may have moved by the time this returns.
+    pub fn current_processor(&self) -> Result<usize> {
+        self.backend.current_processor()
+    }
+
+    /// Pin the calling thread to logical processor `cpu` until the guard is dropped.
+    /// Fails with [Error::InvalidProcessor] if there is no such processor.
+    pub fn pin(&self, cpu: usize) -> Result<AffinityGuard> {
+        let count = self.backend.processor_count()?;
+        if cpu >= count {
+            return Err(Error::InvalidProcessor { cpu, count });
+        }
+
+        let token = self.backend.pin(cpu)?;
+        trace!(cpu, "thread pinned");
+
+        Ok(AffinityGuard {
+            cpu,
+            _token: token,
+            _not_send: PhantomData
+        })
+    }
+
+    /// Run `f` on logical processor `cpu`, then move the calling thread back to the
+    /// processors it could run on before
+    pub fn run_on<T, F: FnOnce() -> T>(&self, cpu: usize, f: F) -> Result<T> {
+        let _guard = self.pin(cpu)?;
+        Ok(f())
+    }
+}
+
+impl Default for ThreadAffinity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ThreadAffinity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadAffinity").finish()
+    }
+}
+
+/// The calling thread pinned by [ThreadAffinity::pin]. Affinity belongs to a thread,
+/// so the guard can't be sent to another one.
+pub struct AffinityGuard {
+    cpu: usize,
+    _token: Box<dyn AffinityToken>,
+    _not_send: PhantomData<*const ()>
+}
+
+impl AffinityGuard {
+    /// The logical processor the thread is pinned to
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+}
+
+impl fmt::Debug for AffinityGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AffinityGuard").field("cpu", &self.cpu).finish()
+    }
+}
@@ -0,0 +1,170 @@
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::sync::OnceLock;
+
+use crate::error::{Error, Result};
+use super::{AffinityBackend, AffinityToken};
+
+/// Pins threads with `sched_setaffinity`.
+///
+/// Logical processors are the CPUs in the affinity mask of the first thread that asks
+/// for them, numbered from 0 in the order of their kernel CPU ids. With CPUs offline or
+/// the process started through `taskset`, index `n` is not kernel CPU `n`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SchedAffinityBackend;
+
+struct PreviousAffinity {
+    set: libc::cpu_set_t
+}
+
+impl AffinityToken for PreviousAffinity {}
+
+impl Drop for PreviousAffinity {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &self.set);
+        }
+    }
+}
+
+/// The affinity mask of the calling thread
+fn thread_affinity() -> io::Result<libc::cpu_set_t> {
+    unsafe {
+        let mut set: libc::cpu_set_t = zeroed();
+        if libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(set)
+    }
+}
+
+/// Kernel CPU id of every logical processor, by index
+fn kernel_cpus() -> Result<&'static [usize]> {
+    static CPUS: OnceLock<Vec<usize>> = OnceLock::new();
+
+    if let Some(cpus) = CPUS.get() {
+        return Ok(cpus);
+    }
+
+    let set = thread_affinity().map_err(|source| Error::Affinity { cpu: 0, source })?;
+    let cpus = (0..libc::CPU_SETSIZE as usize)
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+        .collect();
+
+    Ok(CPUS.get_or_init(|| cpus))
+}
+
+impl AffinityBackend for SchedAffinityBackend {
+    fn processor_count(&self) -> Result<usize> {
+        Ok(kernel_cpus()?.len())
+    }
+
+    fn current_processor(&self) -> Result<usize> {
+        let id = match unsafe { libc::sched_getcpu() } {
+            -1 => { return Err(Error::Affinity { cpu: 0, source: io::Error::last_os_error() }); }
+            id => id as usize
+        };
+
+        kernel_cpus()?.iter().position(|cpu| *cpu == id).ok_or_else(|| Error::Affinity {
+            cpu: id,
+            source: io::Error::other(format!("running on CPU {} outside of the affinity mask", id))
+        })
+    }
+
+    fn pin(&self, cpu: usize) -> Result<Box<dyn AffinityToken>> {
+        let cpus = kernel_cpus()?;
+        let id = *cpus.get(cpu).ok_or(Error::InvalidProcessor { cpu, count: cpus.len() })?;
+        let affinity_error = |source| Error::Affinity { cpu, source };
+
+        let previous = thread_affinity().map_err(affinity_error)?;
+
+        unsafe {
+            let mut pinned: libc::cpu_set_t = zeroed();
+            libc::CPU_SET(id, &mut pinned);
+            if libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &pinned) != 0 {
+                return Err(affinity_error(io::Error::last_os_error()));
+            }
+        }
+
+        Ok(Box::new(PreviousAffinity { set: previous }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::affinity::ThreadAffinity;
+
+    fn mask_cpus() -> Vec<usize> {
+        let set = thread_affinity().unwrap();
+        (0..libc::CPU_SETSIZE as usize).filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) }).collect()
+    }
+
+    fn affinity() -> ThreadAffinity {
+        ThreadAffinity::new().set_backend(SchedAffinityBackend)
+    }
+
+    #[test]
+    fn processors_come_from_the_affinity_mask() {
+        // A fresh thread has the process mask, whatever other tests pinned
+        thread::spawn(|| {
+            assert_eq!(kernel_cpus().unwrap(), &mask_cpus()[..]);
+            assert_eq!(affinity().processor_count().unwrap(), mask_cpus().len());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn guard_restores_original_affinity() {
+        thread::spawn(|| {
+            let affinity = affinity();
+            let original = mask_cpus();
+            let last = affinity.processor_count().unwrap() - 1;
+
+            {
+                let guard = affinity.pin(last).unwrap();
+                assert_eq!(guard.cpu(), last);
+                assert_eq!(mask_cpus(), vec![kernel_cpus().unwrap()[last]]);
+                assert_eq!(affinity.current_processor().unwrap(), last);
+            }
+
+            assert_eq!(mask_cpus(), original);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn nested_guards_restore_in_order() {
+        thread::spawn(|| {
+            let affinity = affinity();
+            let original = mask_cpus();
+            let count = affinity.processor_count().unwrap();
+
+            let outer = affinity.pin(0).unwrap();
+            let outer_mask = mask_cpus();
+            {
+                let _inner = affinity.pin(count - 1).unwrap();
+                assert_eq!(mask_cpus(), vec![kernel_cpus().unwrap()[count - 1]]);
+            }
+            assert_eq!(mask_cpus(), outer_mask);
+
+            drop(outer);
+            assert_eq!(mask_cpus(), original);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn run_on_restores_affinity_after_failed_pin() {
+        thread::spawn(|| {
+            let affinity = affinity();
+            let original = mask_cpus();
+            let count = affinity.processor_count().unwrap();
+
+            match affinity.run_on(count, || ()) {
+                Err(Error::InvalidProcessor { cpu, count: actual }) => assert_eq!((cpu, actual), (count, count)),
+                other => panic!("unexpected {:?}", other)
+            }
+            assert_eq!(mask_cpus(), original);
+        }).join().unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+use crate::error::{Error, Result};
+use super::{AffinityBackend, AffinityToken};
+
+/// Stands in for the Win32 processor group affinity on other platforms. Every call
+/// fails with [Error::UnsupportedPlatform]; use a
+/// [SchedAffinityBackend](crate::SchedAffinityBackend) on Linux instead.
+#[cfg(not(windows))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcessorGroupBackend;
+
+#[cfg(not(windows))]
+impl AffinityBackend for ProcessorGroupBackend {
+    fn processor_count(&self) -> Result<usize> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn current_processor(&self) -> Result<usize> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn pin(&self, _cpu: usize) -> Result<Box<dyn AffinityToken>> {
+        Err(Error::UnsupportedPlatform)
+    }
+}
+
+/// Stands in for `sched_setaffinity` on platforms other than Linux. Every call fails
+/// with [Error::UnsupportedPlatform].
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SchedAffinityBackend;
+
+#[cfg(not(target_os = "linux"))]
+impl AffinityBackend for SchedAffinityBackend {
+    fn processor_count(&self) -> Result<usize> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn current_processor(&self) -> Result<usize> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn pin(&self, _cpu: usize) -> Result<Box<dyn AffinityToken>> {
+        Err(Error::UnsupportedPlatform)
+    }
+}
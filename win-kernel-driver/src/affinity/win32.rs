@@ -0,0 +1,86 @@
+use std::io;
+use std::mem::zeroed;
+use std::ptr::null_mut;
+
+use winapi::um::processthreadsapi;
+use winapi::um::processtopologyapi;
+use winapi::um::winbase;
+use winapi::um::winnt;
+
+use crate::error::{Error, Result};
+use super::{AffinityBackend, AffinityToken};
+
+/// Pins threads with `SetThreadGroupAffinity`, so every logical processor can be
+/// reached on machines with several processor groups
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcessorGroupBackend;
+
+struct PreviousAffinity {
+    affinity: winnt::GROUP_AFFINITY
+}
+
+impl AffinityToken for PreviousAffinity {}
+
+impl Drop for PreviousAffinity {
+    fn drop(&mut self) {
+        unsafe {
+            processtopologyapi::SetThreadGroupAffinity(processthreadsapi::GetCurrentThread(), &self.affinity, null_mut());
+        }
+    }
+}
+
+/// Number of active logical processors in each processor group
+fn group_sizes() -> Vec<usize> {
+    let groups = unsafe { winbase::GetActiveProcessorGroupCount() };
+
+    (0..groups)
+        .map(|group| unsafe { winbase::GetActiveProcessorCount(group) } as usize)
+        .collect()
+}
+
+impl AffinityBackend for ProcessorGroupBackend {
+    fn processor_count(&self) -> Result<usize> {
+        Ok(group_sizes().iter().sum())
+    }
+
+    fn current_processor(&self) -> Result<usize> {
+        let mut number: winnt::PROCESSOR_NUMBER = unsafe { zeroed() };
+        unsafe { processthreadsapi::GetCurrentProcessorNumberEx(&mut number) };
+
+        let before: usize = group_sizes().iter().take(number.Group as usize).sum();
+        Ok(before + number.Number as usize)
+    }
+
+    fn pin(&self, cpu: usize) -> Result<Box<dyn AffinityToken>> {
+        // Find the group the processor is in, and its number within it
+        let mut number = cpu;
+        let mut group = None;
+        for (index, size) in group_sizes().into_iter().enumerate() {
+            if number < size {
+                group = Some(index);
+                break;
+            }
+            number -= size;
+        }
+
+        let group = match group {
+            Some(group) => group,
+            None => { return Err(Error::Affinity { cpu, source: io::Error::from(io::ErrorKind::InvalidInput) }); }
+        };
+
+        let mut affinity: winnt::GROUP_AFFINITY = unsafe { zeroed() };
+        affinity.Mask = 1 << number;
+        affinity.Group = group as u16;
+
+        let mut previous: winnt::GROUP_AFFINITY = unsafe { zeroed() };
+        let pinned = unsafe {
+            processtopologyapi::SetThreadGroupAffinity(processthreadsapi::GetCurrentThread(), &affinity, &mut previous)
+        };
+
+        if pinned == 0 {
+            return Err(Error::Affinity { cpu, source: io::Error::last_os_error() });
+        }
+
+        Ok(Box::new(PreviousAffinity { affinity: previous }))
+    }
+}
@@ -0,0 +1,436 @@
+//! Async IO on a kernel driver
+//!
+//! An [AsyncDriver] owns its own handle to the device, opened for overlapped IO, and
+//! returns an [IoFuture] for every command. The futures only depend on `std`, so they
+//! work with tokio or any other executor.
+use std::future::Future;
+use std::mem::size_of;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, trace};
+
+use crate::error::{Error, Result, ERROR_OPERATION_ABORTED};
+use crate::ioctl::{IoControlCode, Method};
+use crate::pod::{self, Pod};
+use crate::timer;
+use crate::transport::{OwnedDevice, PendingIo, Transport};
+
+/// An async handle to a driver, made by [WinKernelDriver::open_async](crate::WinKernelDriver::open_async).
+///
+/// Clones share the same handle, which is closed once the last clone and the last
+/// [IoFuture] are gone.
+///
+/// # Example
+/// ```
+/// # fn block_on<F: std::future::Future>(future: F) -> F::Output {
+/// #     use std::sync::Arc;
+/// #     use std::task::{Context, Poll, Wake};
+/// #     struct Unpark(std::thread::Thread);
+/// #     impl Wake for Unpark { fn wake(self: Arc<Self>) { self.0.unpark(); } }
+/// #     let waker = Arc::new(Unpark(std::thread::current())).into();
+/// #     let mut cx = Context::from_waker(&waker);
+/// #     let mut future = Box::pin(future);
+/// #     loop {
+/// #         if let Poll::Ready(output) = future.as_mut().poll(&mut cx) { return output; }
+/// #         std::thread::park();
+/// #     }
+/// # }
+/// use std::time::Duration;
+/// use win_kernel_driver::{DriverBuilder, Error, MockTransport, io_control_code, Method, Access};
+///
+/// let read_msr = io_control_code(40000, 0x821, Method::BUFFERED, Access::ANY);
+///
+/// let mock = MockTransport::new();
+/// mock.expect(read_msr).with_typed_input(&0x1a2u32).returns_typed(&0x0064_0000_0000_0000u64);
+/// mock.expect(read_msr).delay(Duration::from_secs(10)).returns_typed(&0u64);
+///
+/// let driver = DriverBuilder::new()
+///     .set_device_id("WinRing0_1_2_0")
+///     .set_driver_path("WinRing0x64.sys".into())
+///     .set_transport(mock)
+///     .build().unwrap();
+///
+/// let device = driver.open_async().unwrap();
+///
+/// // Inside an async fn this is `device.io(read_msr, 0x1a2).await`
+/// assert_eq!(block_on(device.io(read_msr, 0x1a2)).unwrap(), 0x0064_0000_0000_0000);
+///
+/// let slow = device.io(read_msr, 0x19c).timeout(Duration::from_millis(20));
+/// match block_on(slow) {
+///     Err(Error::Timeout { .. }) => { },
+///     other => panic!("expected a timeout, got {:?}", other)
+/// }
+/// ```
+#[derive(Clone)]
+pub struct AsyncDriver {
+    device: Arc<OwnedDevice>
+}
+
+impl AsyncDriver {
+    pub(crate) fn open(transport: Arc<dyn Transport>, path: &str) -> Result<Self> {
+        Ok(AsyncDriver {
+            device: Arc::new(OwnedDevice::open_overlapped(transport, path)?)
+        })
+    }
+
+    /// Start an IO command sending a single `u32` and reading back up to 8 bytes,
+    /// like [WinKernelDriver::io](crate::WinKernelDriver::io)
+    pub fn io<C: Into<IoControlCode>>(&self, ioctl_code: C, in_buffer: u32) -> IoFuture<u64> {
+        self.submit(ioctl_code.into(), in_buffer.to_le_bytes().to_vec(), size_of::<u64>(), decode_u64)
+    }
+
+    /// Start an IO command with arbitrary input, allowing the driver to write up to
+    /// `output_len` bytes. The future resolves to the bytes actually written.
+    pub fn io_bytes<C: Into<IoControlCode>>(&self, ioctl_code: C, in_buffer: &[u8], output_len: usize) -> IoFuture<Vec<u8>> {
+        self.submit(ioctl_code.into(), in_buffer.to_vec(), output_len, |_, output| Ok(output))
+    }
+
+    /// Start an IO command with typed input and output structures, like
+    /// [WinKernelDriver::io_typed](crate::WinKernelDriver::io_typed)
+    pub fn io_typed<C: Into<IoControlCode>, I: Pod, O: Pod>(&self, ioctl_code: C, input: &I) -> IoFuture<O> {
+        self.submit(ioctl_code.into(), pod::bytes_of(input).to_vec(), size_of::<O>(), decode_typed::<O>)
+    }
+
+    fn submit<T>(&self, ioctl_code: IoControlCode, input: Vec<u8>, output_len: usize, decode: Decoder<T>) -> IoFuture<T> {
+        let submitted = match ioctl_code.method() {
+            Method::INDIRECT | Method::OUTDIRECT if output_len == 0 => {
+                Err(Error::InvalidBuffer { ioctl: ioctl_code, reason: "direct IO needs an output buffer" })
+            },
+            _ => self.device.transport().submit(self.device.raw(), ioctl_code, input, output_len)
+        };
+
+        let (io, error) = match submitted {
+            Ok(io) => (Some(io), None),
+            Err(err) => (None, Some(err))
+        };
+
+        IoFuture {
+            io,
+            _device: self.device.clone(),
+            error,
+            ioctl_code,
+            submitted: Instant::now(),
+            decode,
+            timeout: None,
+            timed_out: false,
+            cancelled: Arc::new(AtomicBool::new(false))
+        }
+    }
+}
+
+type Decoder<T> = fn(IoControlCode, Vec<u8>) -> Result<T>;
+
+fn decode_u64(_ioctl_code: IoControlCode, output: Vec<u8>) -> Result<u64> {
+    let mut out_buffer = [0u8; size_of::<u64>()];
+    out_buffer[..output.len()].copy_from_slice(&output);
+
+    Ok(u64::from_le_bytes(out_buffer))
+}
+
+fn decode_typed<O: Pod>(ioctl_code: IoControlCode, output: Vec<u8>) -> Result<O> {
+    if output.len() < size_of::<O>() {
+        return Err(Error::ShortOutput { ioctl: ioctl_code, expected: size_of::<O>(), actual: output.len() });
+    }
+
+    let mut typed: O = pod::zeroed();
+    pod::bytes_of_mut(&mut typed).copy_from_slice(&output[..size_of::<O>()]);
+
+    Ok(typed)
+}
+
+/// An IO command in flight, started by one of the [AsyncDriver] functions.
+///
+/// The command is sent to the driver right away; the future resolves once the driver
+/// completes it. Dropping the future cancels the command and waits for the driver to
+/// let go of its buffers.
+#[must_use = "the command is cancelled when the future is dropped"]
+pub struct IoFuture<T> {
+    // Declared before the device so it is dropped while the handle is still open
+    io: Option<Arc<dyn PendingIo>>,
+    _device: Arc<OwnedDevice>,
+    error: Option<Error>,
+    ioctl_code: IoControlCode,
+    submitted: Instant,
+    decode: Decoder<T>,
+    timeout: Option<(Duration, Instant)>,
+    timed_out: bool,
+    cancelled: Arc<AtomicBool>
+}
+
+impl<T> IoFuture<T> {
+    /// Cancel the command if it hasn't completed `timeout` from now. The future then
+    /// fails with [Error::Timeout].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some((timeout, Instant::now() + timeout));
+        self
+    }
+
+    /// A handle to cancel the command from elsewhere, e.g. while the future is
+    /// awaited. The future then fails with [Error::Cancelled].
+    pub fn canceller(&self) -> Canceller {
+        let io = match &self.io {
+            Some(io) => Arc::downgrade(io),
+            None => Weak::<NoIo>::new()
+        };
+
+        Canceller { io, cancelled: self.cancelled.clone() }
+    }
+
+    /// The IO control code this command was sent with
+    pub fn ioctl_code(&self) -> IoControlCode {
+        self.ioctl_code
+    }
+}
+
+impl<T> Future for IoFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        let this = self.get_mut();
+
+        if let Some(err) = this.error.take() {
+            return Poll::Ready(Err(err));
+        }
+
+        let io = this.io.as_ref().expect("IoFuture polled after completion");
+
+        if let Some((_, deadline)) = this.timeout {
+            if this.timed_out {
+                // Waiting for the driver to acknowledge the cancellation
+            } else if Instant::now() >= deadline {
+                this.timed_out = true;
+                io.cancel();
+            } else {
+                timer::wake_at(deadline, cx.waker());
+            }
+        }
+
+        let result = match io.poll(cx) {
+            Poll::Pending => { return Poll::Pending; }
+            Poll::Ready(result) => result
+        };
+        this.io = None;
+
+        let ioctl = this.ioctl_code;
+        let elapsed_us = this.submitted.elapsed().as_micros() as u64;
+
+        match &result {
+            Ok(output) => trace!(ioctl = %ioctl, written = output.len(), elapsed_us, "async ioctl"),
+            Err(err) => debug!(ioctl = %ioctl, elapsed_us, timed_out = this.timed_out, error = %err, "async ioctl failed")
+        }
+
+        let result = match result {
+            Err(Error::IoctlFailed { code: ERROR_OPERATION_ABORTED, .. }) if this.timed_out => {
+                Err(Error::Timeout { ioctl, timeout: this.timeout.map(|(timeout, _)| timeout).unwrap_or_default() })
+            },
+            Err(Error::IoctlFailed { code: ERROR_OPERATION_ABORTED, .. }) if this.cancelled.load(Ordering::SeqCst) => {
+                Err(Error::Cancelled { ioctl })
+            },
+            other => other
+        };
+
+        Poll::Ready(result.and_then(|output| (this.decode)(ioctl, output)))
+    }
+}
+
+impl<T> Drop for IoFuture<T> {
+    fn drop(&mut self) {
+        // Still in flight: the driver must not complete it into a future nobody polls
+        if let Some(io) = self.io.take() {
+            io.cancel();
+        }
+    }
+}
+
+/// Cancels the [IoFuture] it was taken from, see [IoFuture::canceller]
+#[derive(Clone)]
+pub struct Canceller {
+    io: Weak<dyn PendingIo>,
+    cancelled: Arc<AtomicBool>
+}
+
+impl Canceller {
+    /// Ask the driver to abandon the command. A command that already completed is
+    /// not affected.
+    pub fn cancel(&self) {
+        if let Some(io) = self.io.upgrade() {
+            self.cancelled.store(true, Ordering::SeqCst);
+            io.cancel();
+        }
+    }
+}
+
+/// Stands in for the request of a future that failed before reaching the driver
+struct NoIo;
+
+impl PendingIo for NoIo {
+    fn poll(&self, _cx: &mut Context<'_>) -> Poll<Result<Vec<u8>>> {
+        Poll::Ready(Err(Error::NotOpen))
+    }
+
+    fn cancel(&self) { }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+    use std::task::{Wake, Waker};
+
+    use super::*;
+    use crate::error::ERROR_INVALID_FUNCTION;
+    use crate::ioctl::Access;
+    use crate::transport::{MockTransport, RawDevice};
+
+    fn read_msr() -> IoControlCode {
+        IoControlCode::new(40000, 0x821, Method::BUFFERED, Access::ANY)
+    }
+
+    /// Counts how often it was woken
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn poll<F: Future + Unpin>(future: &mut F, waker: &Arc<CountingWaker>) -> Poll<F::Output> {
+        let waker = Waker::from(waker.clone());
+        Pin::new(future).poll(&mut Context::from_waker(&waker))
+    }
+
+    /// A request completed by the test rather than by time passing
+    #[derive(Default)]
+    struct ManualIo {
+        result: Mutex<Option<Result<Vec<u8>>>>,
+        waker: Mutex<Option<Waker>>,
+        cancels: AtomicUsize
+    }
+
+    impl ManualIo {
+        fn complete(&self, output: &[u8]) {
+            *self.result.lock().unwrap() = Some(Ok(output.to_vec()));
+            if let Some(waker) = self.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl PendingIo for ManualIo {
+        fn poll(&self, cx: &mut Context<'_>) -> Poll<Result<Vec<u8>>> {
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            match self.result.lock().unwrap().take() {
+                Some(result) => Poll::Ready(result),
+                None => Poll::Pending
+            }
+        }
+
+        fn cancel(&self) {
+            self.cancels.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Hands out [ManualIo] requests and keeps them for the test to complete
+    #[derive(Default)]
+    struct ManualTransport {
+        submitted: Mutex<Vec<Arc<ManualIo>>>
+    }
+
+    impl ManualTransport {
+        fn last(&self) -> Arc<ManualIo> {
+            self.submitted.lock().unwrap().last().unwrap().clone()
+        }
+    }
+
+    impl Transport for ManualTransport {
+        fn open(&self, _path: &str) -> Result<RawDevice> {
+            Ok(RawDevice(1))
+        }
+
+        fn ioctl(&self, _device: RawDevice, ioctl_code: IoControlCode, _in_buffer: &[u8], _out_buffer: &mut [u8]) -> Result<usize> {
+            Err(Error::IoctlFailed { ioctl: ioctl_code, code: ERROR_INVALID_FUNCTION })
+        }
+
+        fn close(&self, _device: RawDevice) -> Result<()> {
+            Ok(())
+        }
+
+        fn submit(&self, _device: RawDevice, _ioctl_code: IoControlCode, _input: Vec<u8>, _output_len: usize) -> Result<Arc<dyn PendingIo>> {
+            let io = Arc::new(ManualIo::default());
+            self.submitted.lock().unwrap().push(io.clone());
+            Ok(io)
+        }
+    }
+
+    fn manual_driver() -> (AsyncDriver, Arc<ManualTransport>) {
+        let transport = Arc::new(ManualTransport::default());
+        let driver = AsyncDriver::open(transport.clone(), r"\\.\WinRing0_1_2_0").unwrap();
+        (driver, transport)
+    }
+
+    #[test]
+    fn canceller_resolves_future_to_cancelled() {
+        let mock = MockTransport::new();
+        mock.expect(read_msr()).delay(Duration::from_secs(10)).returns_typed(&0u64);
+        let driver = AsyncDriver::open(Arc::new(mock), r"\\.\WinRing0_1_2_0").unwrap();
+        let waker = Arc::new(CountingWaker::default());
+
+        let mut future = driver.io(read_msr(), 0x1a2);
+        let canceller = future.canceller();
+        assert!(poll(&mut future, &waker).is_pending());
+
+        canceller.cancel();
+        assert!(waker.0.load(Ordering::SeqCst) >= 1);
+        match poll(&mut future, &waker) {
+            Poll::Ready(Err(Error::Cancelled { ioctl })) => assert_eq!(ioctl, read_msr()),
+            other => panic!("expected a cancellation, got {:?}", other.map(|r| r.map(|_| ())))
+        }
+    }
+
+    #[test]
+    fn dropping_in_flight_future_cancels_it() {
+        let (driver, transport) = manual_driver();
+        let waker = Arc::new(CountingWaker::default());
+
+        let mut future = driver.io(read_msr(), 0x1a2);
+        assert!(poll(&mut future, &waker).is_pending());
+        let io = transport.last();
+
+        drop(future);
+        assert_eq!(io.cancels.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dropping_completed_future_does_not_cancel() {
+        let (driver, transport) = manual_driver();
+        let waker = Arc::new(CountingWaker::default());
+
+        let mut future = driver.io(read_msr(), 0x1a2);
+        let io = transport.last();
+        io.complete(&0x64u64.to_le_bytes());
+        assert!(matches!(poll(&mut future, &waker), Poll::Ready(Ok(0x64))));
+
+        drop(future);
+        assert_eq!(io.cancels.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn completion_wakes_registered_task() {
+        let (driver, transport) = manual_driver();
+        let waker = Arc::new(CountingWaker::default());
+
+        let mut future = driver.io_bytes(read_msr(), &0x1a2u32.to_le_bytes(), 8);
+        assert!(poll(&mut future, &waker).is_pending());
+        assert_eq!(waker.0.load(Ordering::SeqCst), 0);
+
+        transport.last().complete(&[1, 2, 3]);
+        assert_eq!(waker.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(poll(&mut future, &waker), Poll::Ready(Ok(output)) if output == vec![1, 2, 3]));
+    }
+}
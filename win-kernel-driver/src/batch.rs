@@ -0,0 +1,40 @@
+//! Results of [WinKernelDriver::io_batch](crate::WinKernelDriver::io_batch)
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::ioctl::IoControlCode;
+
+/// The outcome of one request in a batch
+#[derive(Debug)]
+pub struct IoResult {
+    pub ioctl_code: IoControlCode,
+    /// The number of bytes the driver wrote to the request's output buffer
+    pub result: Result<usize>,
+    /// Time spent in the driver for this request
+    pub elapsed: Duration
+}
+
+/// The outcome of a whole batch, one [IoResult] per request in the order they were sent
+#[derive(Debug)]
+pub struct IoBatch {
+    pub results: Vec<IoResult>,
+    /// Time spent on the whole batch
+    pub elapsed: Duration
+}
+
+impl IoBatch {
+    /// The requests that failed, with their position in the batch
+    pub fn failures(&self) -> impl Iterator<Item = (usize, &IoResult)> {
+        self.results.iter().enumerate().filter(|(_, r)| r.result.is_err())
+    }
+
+    /// True if every request succeeded
+    pub fn all_succeeded(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// The number of bytes written for every request, `None` for the ones that failed
+    pub fn written(&self) -> Vec<Option<usize>> {
+        self.results.iter().map(|r| r.result.as_ref().ok().copied()).collect()
+    }
+}
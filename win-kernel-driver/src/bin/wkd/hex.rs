@@ -0,0 +1,74 @@
+//! Hex input and hex dumps
+
+/// Parse bytes written in hex, e.g. `a2 01 00 00` or `a2010000`. Spaces, colons and
+/// dashes between bytes are ignored.
+pub fn parse(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = text.chars()
+        .filter(|c| !matches!(c, ' ' | ':' | '-'))
+        .collect();
+
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits in {:?}", text));
+    }
+
+    digits.chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16).map_err(|_| format!("invalid hex byte {:?} in {:?}", byte, text))
+        })
+        .collect()
+}
+
+/// Format `bytes` as lines of 16, with the offset in front and the printable
+/// characters at the end
+pub fn dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for (index, byte) in chunk.iter().enumerate() {
+            if index == 8 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", byte));
+        }
+
+        let ascii: String = chunk.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+
+        out.push_str(&format!("{:08x}  {:<49} |{}|\n", line * 16, hex, ascii));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ignores_separators() {
+        assert_eq!(parse("a2 01 00 00").unwrap(), vec![0xa2, 0x01, 0x00, 0x00]);
+        assert_eq!(parse("a2:01-FF").unwrap(), vec![0xa2, 0x01, 0xff]);
+        assert_eq!(parse("").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn parse_rejects_bad_input() {
+        assert!(parse("a2 0").unwrap_err().contains("odd number of hex digits"));
+        assert!(parse("zz").unwrap_err().contains("invalid hex byte \"zz\""));
+    }
+
+    #[test]
+    fn dump_formats_lines_of_16() {
+        let bytes: Vec<u8> = (0x30..0x42).collect();
+
+        assert_eq!(dump(&bytes), "\
+00000000  30 31 32 33 34 35 36 37  38 39 3a 3b 3c 3d 3e 3f  |0123456789:;<=>?|
+00000010  40 41                                             |@A|
+");
+        assert_eq!(dump(&[0x00, b' ', 0x7f]), format!("00000000  {:<49} |. .|\n", "00 20 7f "));
+        assert_eq!(dump(&[]), "");
+    }
+}
@@ -0,0 +1,525 @@
+//! `wkd` manages kernel driver services and sends raw IO control codes to their devices.
+//!
+//! Run `wkd --help` for the commands. With `--script FILE` it runs against a scripted
+//! fake device and service manager instead of Windows, see [script].
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error as StdError;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use win_kernel_driver::{
+    find_driver_services, remove_driver_service, DriverBuilder, Error, FakeServiceManager, InstallOutcome, IoControlCode, MockTransport, OpenOptions,
+    OwnedDevice, ServiceControlManager, StartType, Transport, Win32ServiceManager, Win32Transport,
+    WinKernelDriver
+};
+
+mod hex;
+mod script;
+
+const USAGE: &str = "\
+Usage: wkd [--script FILE] <command> [arguments]
+
+Commands:
+  install <driver.sys> [--name NAME] [--display-name TEXT] [--start auto|demand|disabled]
+      Install the driver as a service and start it. NAME defaults to the file name
+      without its extension.
+  start <service>       Start an installed driver service
+  stop <service>        Stop a driver service
+  uninstall <service>   Stop and delete a driver service
+  status <service>      Show the state and driver file of a service
+  services [PATTERN]    List kernel driver services whose name matches PATTERN, which may
+                        contain * and ?. Lists every driver service without a pattern.
+  cleanup <PATTERN> [--orphaned] [--staging-dir DIR] [--dry-run]
+      Stop and delete the driver services matching PATTERN. --orphaned only removes the
+      ones whose driver file is gone. Driver files directly in DIR are deleted too.
+  ioctl <device> <code> [--input HEX | --input-u32 VALUE] [--output-len N] [--read-only]
+      Open \\\\.\\<device>, send one IO control code and dump the output. <code> is a raw
+      code (0x9C402084) or device_type,function,method,access (40000,0x821,buffered,any).
+      The output buffer is 8 bytes unless --output-len is given.
+
+Options:
+  --script FILE   Use a fake device and service manager scripted by FILE
+  -h, --help      Show this help
+";
+
+/// Options taking a value
+const VALUE_OPTIONS: &[&str] = &[
+    "--script", "--name", "--display-name", "--start", "--input", "--input-u32", "--output-len", "--staging-dir"
+];
+/// Options without a value
+const FLAGS: &[&str] = &["--read-only", "--orphaned", "--dry-run", "--help", "-h"];
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => { usage_error(&err); }
+    };
+
+    if args.flag("--help") || args.flag("-h") {
+        print!("{}", USAGE);
+        return;
+    }
+
+    let backend = match args.option("--script") {
+        Some(path) => {
+            let text = fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("error: unable to read script {}: {}", path, err);
+                process::exit(1);
+            });
+            match script::load(&text) {
+                Ok((transport, scm)) => Backend::Script { transport, scm },
+                Err(err) => {
+                    eprintln!("error: {}: {}", path, err);
+                    process::exit(1);
+                }
+            }
+        },
+        None => Backend::Windows
+    };
+
+    match run(&args, &backend) {
+        Ok(()) => { },
+        Err(Failure::Usage(err)) => { usage_error(&err); }
+        Err(Failure::Driver(err)) => {
+            eprintln!("error: {}", err);
+            let mut source = err.source();
+            while let Some(cause) = source {
+                eprintln!("  caused by: {}", cause);
+                source = cause.source();
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn usage_error(err: &str) -> ! {
+    eprintln!("error: {}\n\n{}", err, USAGE);
+    process::exit(2);
+}
+
+/// Why a command failed
+enum Failure {
+    /// The command line was wrong
+    Usage(String),
+    /// The driver or service operation failed
+    Driver(Error)
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        Failure::Driver(err)
+    }
+}
+
+/// Where the commands are sent
+enum Backend {
+    Windows,
+    Script { transport: MockTransport, scm: FakeServiceManager }
+}
+
+impl Backend {
+    fn builder(&self) -> DriverBuilder {
+        match self {
+            Backend::Windows => DriverBuilder::new(),
+            Backend::Script { transport, scm } => DriverBuilder::new()
+                .set_transport(transport.clone())
+                .set_service_manager(scm.clone())
+        }
+    }
+
+    fn scm(&self) -> &dyn ServiceControlManager {
+        match self {
+            Backend::Windows => &Win32ServiceManager,
+            Backend::Script { scm, .. } => scm
+        }
+    }
+
+    fn transport(&self) -> Arc<dyn Transport> {
+        match self {
+            Backend::Windows => Arc::new(Win32Transport),
+            Backend::Script { transport, .. } => Arc::new(transport.clone())
+        }
+    }
+}
+
+/// The command line, split into positional arguments, options and flags
+struct Args {
+    positional: Vec<String>,
+    options: BTreeMap<&'static str, String>,
+    flags: Vec<&'static str>
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut raw: I) -> Result<Self, String> {
+        let mut args = Args { positional: vec![], options: BTreeMap::new(), flags: vec![] };
+
+        while let Some(arg) = raw.next() {
+            if let Some(name) = VALUE_OPTIONS.iter().find(|name| **name == arg) {
+                let value = raw.next().ok_or_else(|| format!("{} needs a value", name))?;
+                args.options.insert(name, value);
+            } else if let Some(name) = FLAGS.iter().find(|name| **name == arg) {
+                args.flags.push(name);
+            } else if arg.starts_with("--") {
+                return Err(format!("unknown option {}", arg));
+            } else {
+                args.positional.push(arg);
+            }
+        }
+
+        Ok(args)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(&name)
+    }
+
+    /// Fail on options that `command` doesn't take
+    fn allow(&self, command: &str, allowed: &[&str]) -> Result<(), Failure> {
+        let given = self.options.keys().copied().chain(self.flags.iter().copied());
+
+        for name in given.filter(|name| *name != "--script") {
+            if !allowed.contains(&name) {
+                return Err(Failure::Usage(format!("{} does not take {}", command, name)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn run(args: &Args, backend: &Backend) -> Result<(), Failure> {
+    let words: Vec<&str> = args.positional.iter().map(String::as_str).collect();
+
+    match words.as_slice() {
+        ["install", path] => {
+            args.allow("install", &["--name", "--display-name", "--start"])?;
+            install(args, backend, Path::new(path))
+        },
+        ["start", name] => {
+            args.allow("start", &[])?;
+            installed(backend, name)?.start()?;
+            println!("Started {}", name);
+            Ok(())
+        },
+        ["stop", name] => {
+            args.allow("stop", &[])?;
+            installed(backend, name)?.stop()?;
+            println!("Stopped {}", name);
+            Ok(())
+        },
+        ["uninstall", name] => {
+            args.allow("uninstall", &[])?;
+            installed(backend, name)?.uninstall()?;
+            println!("Uninstalled {}", name);
+            Ok(())
+        },
+        ["status", name] => {
+            args.allow("status", &[])?;
+            status(backend, name)
+        },
+        ["services"] | ["services", _] => {
+            args.allow("services", &[])?;
+            services(backend, words.get(1).copied().unwrap_or("*"))
+        },
+        ["cleanup", pattern] => {
+            args.allow("cleanup", &["--orphaned", "--staging-dir", "--dry-run"])?;
+            cleanup(args, backend, pattern)
+        },
+        ["ioctl", device, code] => {
+            args.allow("ioctl", &["--input", "--input-u32", "--output-len", "--read-only"])?;
+            ioctl(args, backend, device, code)
+        },
+        [] => Err(Failure::Usage("no command given".to_owned())),
+        [command, ..] => Err(Failure::Usage(format!("unknown command or wrong arguments for {:?}", command)))
+    }
+}
+
+fn install(args: &Args, backend: &Backend, path: &Path) -> Result<(), Failure> {
+    let name = match args.option("--name") {
+        Some(name) => name.to_owned(),
+        None => path.file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .ok_or_else(|| Failure::Usage(format!("can't derive a service name from {:?}, use --name", path)))?
+    };
+
+    let start_type = match args.option("--start") {
+        None | Some("demand") => StartType::OnDemand,
+        Some("auto") => StartType::Auto,
+        Some("disabled") => StartType::Disabled,
+        Some(other) => { return Err(Failure::Usage(format!("unknown start type {:?}", other))); }
+    };
+
+    // The service control manager needs an absolute path
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
+    };
+
+    let mut driver = backend.builder()
+        .set_service_name(name.as_str())
+        .set_display_name(args.option("--display-name").unwrap_or(&name))
+        .set_start_type(start_type)
+        .set_driver_path(path)
+        .build()?;
+
+    match driver.install()? {
+        InstallOutcome::Created => println!("Created and started {}", name),
+        InstallOutcome::Reused => println!("Reused existing service {}", name),
+        InstallOutcome::Recreated => println!("Replaced service {} pointing at another driver file", name)
+    }
+
+    Ok(())
+}
+
+/// A driver for a service that is already installed
+fn installed(backend: &Backend, name: &str) -> Result<WinKernelDriver, Failure> {
+    let details = backend.scm().query(name)?
+        .ok_or_else(|| Error::not_installed(name))?;
+
+    Ok(backend.builder()
+        .set_service_name(name)
+        .set_driver_path(details.binary_path)
+        .build()?)
+}
+
+fn status(backend: &Backend, name: &str) -> Result<(), Failure> {
+    let details = backend.scm().query(name)?
+        .ok_or_else(|| Error::not_installed(name))?;
+
+    println!("{}: {:?}", name, details.state);
+    println!("  driver: {}", details.binary_path.display());
+    if details.marked_for_deletion {
+        println!("  marked for deletion, waiting for open handles to close");
+    }
+
+    Ok(())
+}
+
+fn services(backend: &Backend, pattern: &str) -> Result<(), Failure> {
+    for service in find_driver_services(backend.scm(), pattern)? {
+        let mut state = format!("{:?}", service.state);
+        if service.marked_for_deletion {
+            state.push_str(", marked for deletion");
+        }
+        let file = if service.file_exists { "" } else { " (file missing)" };
+
+        println!("{}: {}", service.name, state);
+        println!("  driver: {}{}", service.binary_path.display(), file);
+    }
+
+    Ok(())
+}
+
+fn cleanup(args: &Args, backend: &Backend, pattern: &str) -> Result<(), Failure> {
+    let staging_dir = args.option("--staging-dir").map(Path::new);
+    let mut failed = None;
+
+    let selected = find_driver_services(backend.scm(), pattern)?
+        .into_iter()
+        .filter(|service| !args.flag("--orphaned") || !service.file_exists);
+
+    for service in selected {
+        if args.flag("--dry-run") {
+            println!("Would remove {} ({})", service.name, service.binary_path.display());
+            continue;
+        }
+
+        // Keep going, one service in use shouldn't keep the others around
+        match remove_driver_service(backend.scm(), &service, staging_dir) {
+            Ok(Some(path)) => println!("Removed {} and deleted {}", service.name, path.display()),
+            Ok(None) => println!("Removed {}", service.name),
+            Err(err) => {
+                eprintln!("Unable to remove {}: {}", service.name, err);
+                failed = Some(err);
+            }
+        }
+    }
+
+    match failed {
+        Some(err) => Err(Failure::Driver(err)),
+        None => Ok(())
+    }
+}
+
+fn ioctl(args: &Args, backend: &Backend, device: &str, code: &str) -> Result<(), Failure> {
+    let code: IoControlCode = code.parse()?;
+
+    let input = match (args.option("--input"), args.option("--input-u32")) {
+        (Some(_), Some(_)) => { return Err(Failure::Usage("use either --input or --input-u32".to_owned())); }
+        (Some(bytes), None) => hex::parse(bytes).map_err(Failure::Usage)?,
+        (None, Some(value)) => parse_number(value).map_err(Failure::Usage)?.to_le_bytes().to_vec(),
+        (None, None) => vec![]
+    };
+
+    let output_len = match args.option("--output-len") {
+        Some(len) => parse_number(len).map_err(Failure::Usage)? as usize,
+        None => 8
+    };
+
+    let options = if args.flag("--read-only") { OpenOptions::read_only() } else { OpenOptions::new() };
+
+    let path = if device.starts_with(r"\\") { device.to_owned() } else { format!(r"\\.\{}", device) };
+
+    let device = OwnedDevice::open_with(backend.transport(), &path, &options)?;
+    let mut output = vec![0u8; output_len];
+    let written = device.ioctl(code, &input, &mut output)?;
+    device.close()?;
+
+    println!("{} returned {} bytes", code, written);
+    print!("{}", hex::dump(&output[..written]));
+
+    Ok(())
+}
+
+/// Parse a decimal number, or a hexadecimal one with a `0x` prefix
+fn parse_number(text: &str) -> Result<u32, String> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse()
+    };
+
+    parsed.map_err(|_| format!("invalid number {:?}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use win_kernel_driver::ServiceState;
+
+    use super::*;
+
+    fn args(line: &str) -> Args {
+        Args::parse(line.split_whitespace().map(String::from)).unwrap()
+    }
+
+    fn scripted(text: &str) -> Backend {
+        let (transport, scm) = script::load(text).unwrap();
+        Backend::Script { transport, scm }
+    }
+
+    fn fake(backend: &Backend) -> (&MockTransport, &FakeServiceManager) {
+        match backend {
+            Backend::Script { transport, scm } => (transport, scm),
+            Backend::Windows => unreachable!()
+        }
+    }
+
+    #[test]
+    fn parse_splits_positional_options_and_flags() {
+        let args = args("--script fake.txt ioctl WinRing0_1_2_0 0x9C402084 --input-u32 0x1a2 --read-only");
+
+        assert_eq!(args.positional, vec!["ioctl", "WinRing0_1_2_0", "0x9C402084"]);
+        assert_eq!(args.option("--script"), Some("fake.txt"));
+        assert_eq!(args.option("--input-u32"), Some("0x1a2"));
+        assert_eq!(args.option("--input"), None);
+        assert!(args.flag("--read-only"));
+        assert!(!args.flag("--dry-run"));
+    }
+
+    #[test]
+    fn parse_rejects_unknown_and_incomplete_options() {
+        let parse = |line: &str| Args::parse(line.split_whitespace().map(String::from)).err().unwrap();
+
+        assert_eq!(parse("status WinRing0_1_2_0 --verbose"), "unknown option --verbose");
+        assert_eq!(parse("install driver.sys --name"), "--name needs a value");
+    }
+
+    #[test]
+    fn allow_rejects_options_of_other_commands() {
+        let args = args("--script fake.txt status WinRing0_1_2_0 --dry-run");
+
+        assert!(args.allow("cleanup", &["--dry-run"]).is_ok());
+        assert!(matches!(args.allow("status", &[]), Err(Failure::Usage(err)) if err == "status does not take --dry-run"));
+    }
+
+    #[test]
+    fn parse_number_takes_decimal_and_hex() {
+        assert_eq!(parse_number("418"), Ok(418));
+        assert_eq!(parse_number("0x1a2"), Ok(0x1a2));
+        assert_eq!(parse_number("0X1A2"), Ok(0x1a2));
+        assert_eq!(parse_number("0xg"), Err("invalid number \"0xg\"".to_owned()));
+    }
+
+    #[test]
+    fn install_creates_service() {
+        let backend = scripted("");
+        let path = env::temp_dir().join("WinRing0x64.sys");
+
+        let result = run(&args(&format!("install {} --name WinRing0_1_2_0 --start auto", path.display())), &backend);
+
+        assert!(result.is_ok());
+        let (_, scm) = fake(&backend);
+        assert_eq!(scm.service("WinRing0_1_2_0").unwrap().state, ServiceState::Running);
+        assert_eq!(scm.config("WinRing0_1_2_0").unwrap().start_type, StartType::Auto);
+        assert_eq!(scm.config("WinRing0_1_2_0").unwrap().binary_path, path);
+    }
+
+    #[test]
+    fn status_and_services_query_the_service_manager() {
+        let backend = scripted(r"service WinRing0_1_2_0 C:\drivers\WinRing0x64.sys running");
+
+        assert!(run(&args("status WinRing0_1_2_0"), &backend).is_ok());
+        assert!(run(&args("services WinRing0*"), &backend).is_ok());
+        assert!(matches!(run(&args("status Missing"), &backend), Err(Failure::Driver(Error::NotInstalled { .. }))));
+    }
+
+    #[test]
+    fn cleanup_removes_orphaned_services() {
+        let present = env::current_exe().unwrap();
+        let backend = scripted(&format!("\
+service WinRing0_1_2_0 C:\\drivers\\missing\\WinRing0x64.sys stopped
+service WinRing0_1_2_1 {} stopped
+", present.display()));
+
+        assert!(run(&args("cleanup WinRing0* --orphaned --dry-run"), &backend).is_ok());
+        let (_, scm) = fake(&backend);
+        assert!(scm.service("WinRing0_1_2_0").is_some());
+
+        assert!(run(&args("cleanup WinRing0* --orphaned"), &backend).is_ok());
+        assert!(scm.service("WinRing0_1_2_0").is_none());
+        assert!(scm.service("WinRing0_1_2_1").is_some());
+    }
+
+    #[test]
+    fn ioctl_sends_input_to_device() {
+        let backend = scripted("ioctl 40000,0x821,buffered,any input a2010000 returns 00 00 64 00 00 00 00 00");
+
+        assert!(run(&args("ioctl WinRing0_1_2_0 40000,0x821,buffered,any --input-u32 0x1a2"), &backend).is_ok());
+
+        let (transport, _) = fake(&backend);
+        assert_eq!(transport.opened_paths(), vec![r"\\.\WinRing0_1_2_0"]);
+        assert_eq!(transport.calls()[0].input, vec![0xa2, 0x01, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn ioctl_rejects_conflicting_or_bad_input() {
+        let backend = scripted("");
+
+        let both = run(&args("ioctl WinRing0_1_2_0 0x9C402084 --input a2010000 --input-u32 0x1a2"), &backend);
+        assert!(matches!(both, Err(Failure::Usage(err)) if err == "use either --input or --input-u32"));
+
+        let odd = run(&args("ioctl WinRing0_1_2_0 0x9C402084 --input a2010"), &backend);
+        assert!(matches!(odd, Err(Failure::Usage(err)) if err.contains("odd number of hex digits")));
+
+        let unknown = run(&args("ioctl WinRing0_1_2_0 0x9C402084 --orphaned"), &backend);
+        assert!(matches!(unknown, Err(Failure::Usage(err)) if err == "ioctl does not take --orphaned"));
+    }
+
+    #[test]
+    fn ioctl_reports_driver_errors() {
+        let backend = scripted("open fails 5");
+        let result = run(&args("ioctl WinRing0_1_2_0 0x9C402084"), &backend);
+        assert!(matches!(result, Err(Failure::Driver(Error::OpenFailed { code: 5, .. }))));
+
+        let backend = scripted("ioctl 0x9C402084 fails 31");
+        let result = run(&args("ioctl WinRing0_1_2_0 0x9C402084"), &backend);
+        assert!(matches!(result, Err(Failure::Driver(Error::IoctlFailed { code: 31, .. }))));
+    }
+}
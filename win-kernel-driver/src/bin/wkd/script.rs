@@ -0,0 +1,134 @@
+//! Scripted fake devices
+//!
+//! With `--script FILE`, wkd talks to a [MockTransport] and a [FakeServiceManager]
+//! set up from the file instead of Windows. One entry per line:
+//!
+//! ```text
+//! # Services that exist before the command runs
+//! service WinRing0_1_2_0 C:\drivers\WinRing0x64.sys running
+//!
+//! # Answers of the device, matched in order like MockTransport::expect
+//! ioctl 40000,0x821,buffered,any input a2010000 returns 00 00 64 00 00 00 00 00
+//! ioctl 0x9C402084 fails 31
+//!
+//! # Make opening the device fail with a Win32 error
+//! open fails 5
+//! ```
+use win_kernel_driver::{FakeServiceManager, IoControlCode, MockTransport, ServiceState};
+
+use super::hex;
+use super::parse_number;
+
+/// Build the fake device and service manager described by `script`
+pub fn load(script: &str) -> Result<(MockTransport, FakeServiceManager), String> {
+    let transport = MockTransport::new();
+    let scm = FakeServiceManager::new();
+
+    for (index, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        entry(&transport, &scm, line).map_err(|err| format!("line {}: {}", index + 1, err))?;
+    }
+
+    Ok((transport, scm))
+}
+
+fn entry(transport: &MockTransport, scm: &FakeServiceManager, line: &str) -> Result<(), String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    match words.as_slice() {
+        ["service", name, path, state] => {
+            scm.add_service(name, *path, service_state(state)?);
+            Ok(())
+        },
+        ["open", "fails", code] => {
+            transport.fail_open(Some(parse_number(code)?));
+            Ok(())
+        },
+        ["ioctl", code, rest @ ..] => ioctl(transport, code, rest),
+        _ => Err(format!("unknown entry {:?}", line))
+    }
+}
+
+fn ioctl(transport: &MockTransport, code: &str, rest: &[&str]) -> Result<(), String> {
+    let code: IoControlCode = code.parse().map_err(|err| format!("{}", err))?;
+
+    let (input, rest) = match rest {
+        ["input", input, rest @ ..] => (Some(hex::parse(input)?), rest),
+        _ => (None, rest)
+    };
+
+    let mut expectation = transport.expect(code);
+    if let Some(input) = input {
+        expectation = expectation.with_input(&input);
+    }
+
+    match rest {
+        ["returns", output @ ..] => expectation.returns(&hex::parse(&output.concat())?),
+        ["fails", code] => expectation.fails(parse_number(code)?),
+        _ => { return Err("expected `returns <hex>` or `fails <error>`".to_owned()); }
+    }
+
+    Ok(())
+}
+
+fn service_state(state: &str) -> Result<ServiceState, String> {
+    match state.to_ascii_lowercase().as_str() {
+        "running" => Ok(ServiceState::Running),
+        "stopped" => Ok(ServiceState::Stopped),
+        "paused" => Ok(ServiceState::Paused),
+        "start_pending" => Ok(ServiceState::StartPending),
+        "stop_pending" => Ok(ServiceState::StopPending),
+        _ => Err(format!("unknown service state {:?}", state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use win_kernel_driver::{Error, Transport};
+
+    use super::*;
+
+    #[test]
+    fn load_sets_up_services_and_device() {
+        let (transport, scm) = load("\
+# Services
+service WinRing0_1_2_0 C:\\drivers\\WinRing0x64.sys running
+
+ioctl 40000,0x821,buffered,any input a2010000 returns 00 00 64 00 00 00 00 00
+ioctl 0x9C402084 fails 31
+").unwrap();
+
+        assert_eq!(scm.service("WinRing0_1_2_0").unwrap().state, ServiceState::Running);
+
+        let device = transport.open(r"\\.\WinRing0_1_2_0").unwrap();
+        let read_msr: IoControlCode = "40000,0x821,buffered,any".parse().unwrap();
+        let mut output = [0u8; 8];
+        assert_eq!(transport.ioctl(device, read_msr, &0x1a2u32.to_le_bytes(), &mut output).unwrap(), 8);
+        assert_eq!(u64::from_le_bytes(output), 0x0064_0000);
+
+        let failing = IoControlCode::from(0x9C402084);
+        assert!(matches!(transport.ioctl(device, failing, &[], &mut output), Err(Error::IoctlFailed { code: 31, .. })));
+    }
+
+    #[test]
+    fn open_fails_makes_opening_fail() {
+        let (transport, _) = load("open fails 5").unwrap();
+
+        assert!(matches!(transport.open(r"\\.\WinRing0_1_2_0"), Err(Error::OpenFailed { code: 5, .. })));
+    }
+
+    #[test]
+    fn load_reports_line_of_bad_entry() {
+        let err = |script| load(script).err().unwrap();
+
+        assert_eq!(err("# comment\n\nfrobnicate"), "line 3: unknown entry \"frobnicate\"");
+        assert!(err("service A C:\\a.sys sleeping").starts_with("line 1: unknown service state"));
+        assert!(err("ioctl 0x9C402084 input a2010 returns 00").contains("odd number of hex digits"));
+        assert_eq!(err("ioctl 0x9C402084"), "line 1: expected `returns <hex>` or `fails <error>`");
+        assert!(err("open fails five").contains("invalid number \"five\""));
+    }
+}
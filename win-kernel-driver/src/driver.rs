@@ -1,72 +1,42 @@
-use std::path::PathBuf;
-use std::ffi::OsString;
-use std::ptr::null_mut;
+use std::path::{Path, PathBuf};
 use std::mem::size_of;
-use std::ffi::{c_void, CString};
-use std::convert::TryFrom;
 use std::env;
-use std::fs::File;
-use std::io::Write;
-
-use windows_service::{
-    service::{ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceType, Service, ServiceStatus, ServiceState},
-    service_manager::{ServiceManager, ServiceManagerAccess}
-};
-
-use winapi::um::winnt;
-use winapi::um::fileapi;
-use winapi::um::handleapi;
-use winapi::shared::minwindef::{DWORD};
-use winapi::um::ioapiset;
-use winapi::um::errhandlingapi;
-use winapi::um::winioctl;
-
-
-/// IO Method
-#[repr(u32)]
-pub enum Method {
-    BUFFERED = 0,
-    INDIRECT = 1,
-    OUTDIRECT = 2,
-    NEITHER = 3
-}
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-/// IO Access
-#[repr(u32)]
-pub enum Access {
-    ANY = 0,
-    READ = 1,
-    WRITE = 2
-}
+use tracing::{debug, info, warn};
 
-/// Creates an IOCTL code
-/// 
-/// # Arguments
-/// 
-/// * `device_type` - The device type is a 32bit integer. It must match the kernel driver
-///                   you're loading.
-/// * `function`    - The function code you want to send to the driver. Consult your driver's
-///                   documentation for the available codes.DriverBuilder
-/// * `method`      - The IO method to use
-/// * `access`      - The access level (read/write/any) to use
-/// 
-/// # Example
-/// ```
-/// let device = 0x00000022; // FILE_DEVICE_UNKNOWN
-/// let function = 0x800; // Some function code defined by the driver
-/// 
-/// // Generate an IO control code for a buffered read to the driver.
-/// let ioctl = io_control_code(device, function, Method.BUFFERED, Access.READ);
-/// ```
-pub const fn io_control_code(device_type: u32, function: u32, method: Method, access: Access) -> u32 {
-    (device_type << 16) | ((access as u32) << 14) | (function << 2) | (method as u32)
-}
+use crate::error::{Error, Result, ERROR_FILE_NOT_FOUND, ERROR_PATH_NOT_FOUND};
+use crate::ioctl::{IoControlCode, Method};
+use crate::pod::Pod;
+use crate::protocol::DeviceIo;
+use crate::transport::{OpenOptions, OwnedDevice, Transport, Win32Transport};
+use crate::scm::{self, ErrorControl, InstallOutcome, ServiceConfig, ServiceControlManager, ServiceState, StartType, Win32ServiceManager};
+use crate::pe::{Machine, PeImage};
+use crate::staging;
+use crate::embed::EmbeddedDriver;
+use crate::async_driver::AsyncDriver;
+use crate::batch::{IoBatch, IoResult};
+use crate::session::{Concurrency, DriverSession};
+
+/// Win32 `FILE_DEVICE_UNKNOWN`
+const FILE_DEVICE_UNKNOWN: u32 = 0x22;
+/// How often [WinKernelDriver::wait_for_state] checks the service state
+const STATE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// First wait of [WinKernelDriver::open_with_retry], doubled after every attempt
+const OPEN_RETRY_INITIAL: Duration = Duration::from_millis(10);
+/// Longest wait of [WinKernelDriver::open_with_retry]
+const OPEN_RETRY_MAX: Duration = Duration::from_millis(500);
 
 /// Use this to build a kernel driver object you can interact with
 /// 
 /// # Example
-/// ```
-/// let driver_bin = include_bytes!("../winRing0.sys");
+/// ```no_run
+/// use win_kernel_driver::DriverBuilder;
+///
+/// let driver_bin = include_bytes!("../../win_ring0/WinRing0x64.sys");
 /// let driver = DriverBuilder::new()
 ///              .set_device_description("winRing0 driver")
 ///              .set_device_id("WinRing0_1_2_0")
@@ -74,82 +44,206 @@ pub const fn io_control_code(device_type: u32, function: u32, method: Method, ac
 ///              .build().unwrap();
 /// ```
 pub struct DriverBuilder {
-    device_id: &'static str,
-    device_description: &'static str,
-    device_type: DWORD,
+    service_name: String,
+    symbolic_link: Option<String>,
+    display_name: String,
+    start_type: StartType,
+    error_control: ErrorControl,
+    dependencies: Vec<String>,
+    device_type: u32,
     driver_path: PathBuf,
-    driver_bin: Vec<u8>
+    driver_bin: Vec<u8>,
+    embedded_driver: Option<&'static EmbeddedDriver>,
+    staging_dir: PathBuf,
+    transport: Arc<dyn Transport>,
+    scm: Arc<dyn ServiceControlManager>
 }
 
 impl DriverBuilder {
     pub fn new() -> Self {
         DriverBuilder {
-            device_id: "",
-            device_description: "",
-            device_type: winioctl::FILE_DEVICE_UNKNOWN,
+            service_name: String::new(),
+            symbolic_link: None,
+            display_name: String::new(),
+            start_type: StartType::OnDemand,
+            error_control: ErrorControl::Normal,
+            dependencies: vec![],
+            device_type: FILE_DEVICE_UNKNOWN,
             driver_path: PathBuf::new(),
-            driver_bin: vec![]
+            driver_bin: vec![],
+            embedded_driver: None,
+            staging_dir: env::temp_dir(),
+            transport: Arc::new(Win32Transport),
+            scm: Arc::new(Win32ServiceManager)
         }
     }
 
-    /// Set the device id (required)
-    pub fn set_device_id(mut self, device_id: &'static str) -> Self {
-        self.device_id = device_id;
-        return self;
+    /// Set the device id (required). It is used as the service name, and as the
+    /// symbolic link name unless [DriverBuilder::set_symbolic_link] is called.
+    pub fn set_device_id<S: Into<String>>(mut self, device_id: S) -> Self {
+        self.service_name = device_id.into();
+        self
+    }
+
+    /// Set the service name, same as [DriverBuilder::set_device_id]
+    pub fn set_service_name<S: Into<String>>(mut self, service_name: S) -> Self {
+        self.service_name = service_name.into();
+        self
     }
 
-    /// Set the device description (required)
-    pub fn set_device_description(mut self, device_description: &'static str) -> Self {
-        self.device_description = device_description;
-        return self;
+    /// Set the name of the symbolic link the driver creates for its device, opened as
+    /// `\\.\{symbolic_link}` (defaults to the service name)
+    pub fn set_symbolic_link<S: Into<String>>(mut self, symbolic_link: S) -> Self {
+        self.symbolic_link = Some(symbolic_link.into());
+        self
+    }
+
+    /// Set the device description, used as the service display name
+    pub fn set_device_description<S: Into<String>>(mut self, device_description: S) -> Self {
+        self.display_name = device_description.into();
+        self
+    }
+
+    /// Set the service display name, same as [DriverBuilder::set_device_description]
+    pub fn set_display_name<S: Into<String>>(mut self, display_name: S) -> Self {
+        self.display_name = display_name.into();
+        self
+    }
+
+    /// Set when the service is started (defaults to [StartType::OnDemand])
+    pub fn set_start_type(mut self, start_type: StartType) -> Self {
+        self.start_type = start_type;
+        self
+    }
+
+    /// Set what happens at boot if the service fails to start (defaults to [ErrorControl::Normal])
+    pub fn set_error_control(mut self, error_control: ErrorControl) -> Self {
+        self.error_control = error_control;
+        self
+    }
+
+    /// Add a service that must be running before the driver is started
+    pub fn add_dependency<S: Into<String>>(mut self, service_name: S) -> Self {
+        self.dependencies.push(service_name.into());
+        self
     }
 
     /// Set the device type (defaults to FILE_DEVICE_UNKNOWN (0x00000022))
-    pub fn set_device_type(mut self, device_type: DWORD) -> Self {
+    pub fn set_device_type(mut self, device_type: u32) -> Self {
         self.device_type = device_type;
-        return self;
+        self
     }
 
     /// Set the path to the driver file (optional)
     pub fn set_driver_path(mut self, driver_path: PathBuf) -> Self {
         self.driver_path = driver_path;
-        return self;
+        self
     }
 
-    /// Use a bytearray for the driver. It will be written to a temporary location
+    /// Use a bytearray for the driver. It will be written to the staging directory
     /// Useful with the !include_bin macro
     pub fn set_driver_bin(mut self, driver_bin: Vec<u8>) -> Self {
         self.driver_bin = driver_bin;
-        return self;
+        self
+    }
+
+    /// Use a driver compressed at build time, see [include_driver!](crate::include_driver).
+    /// 
+    /// It is left compressed until [WinKernelDriver::install], which decompresses it only
+    /// if it is not already in the staging directory, and writes it there after checking
+    /// it against its recorded SHA-256.
+    pub fn set_embedded_driver(mut self, driver: &'static EmbeddedDriver) -> Self {
+        self.embedded_driver = Some(driver);
+        self
+    }
+
+    /// Set the directory the driver bytearray is written to (defaults to the temp directory).
+    /// 
+    /// The file is named `{service_name}-{hash}.sys` after a SHA-256 of its content, written
+    /// atomically, and reused if an identical file is already there. It is removed again
+    /// by [WinKernelDriver::uninstall].
+    pub fn set_staging_dir(mut self, staging_dir: PathBuf) -> Self {
+        self.staging_dir = staging_dir;
+        self
+    }
+
+    /// Set the transport used to talk to the device (defaults to [Win32Transport]).
+    /// Use a [MockTransport](crate::MockTransport) to run without a real driver.
+    pub fn set_transport<T: Transport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Arc::new(transport);
+        self
+    }
+
+    /// Set the service control manager used to install the driver (defaults to
+    /// [Win32ServiceManager]). Use a [FakeServiceManager](crate::FakeServiceManager)
+    /// to run without one.
+    pub fn set_service_manager<S: ServiceControlManager + 'static>(mut self, scm: S) -> Self {
+        self.scm = Arc::new(scm);
+        self
     }
 
     /// Build a WinKernelDriver instance
-    pub fn build(&mut self) -> Result<WinKernelDriver, String> {
+    /// 
+    /// Fails with [Error::ArchitectureMismatch] if the driver image does not match
+    /// the architecture of the running Windows. An embedded driver is only checked
+    /// once [WinKernelDriver::install] decompresses it.
+    pub fn build(&mut self) -> Result<WinKernelDriver> {
 
-        if self.device_id.len() == 0 {
-            return Err("Device ID needs to be set!".to_owned());
+        if self.service_name.is_empty() {
+            return Err(Error::MissingDeviceId);
         }
 
-        if self.driver_bin.len() == 0 && self.driver_path.components().count() == 0 {
-            return Err("Either a path to the driver file, or a binary array of the driver file, must be set".to_owned());
+        if self.driver_bin.is_empty() && self.embedded_driver.is_none() && self.driver_path.components().count() == 0 {
+            return Err(Error::MissingDriver);
         }
 
-        if self.driver_bin.len() > 0 {
-            let mut dir = PathBuf::from(env::temp_dir());
-            dir.push(format!("{}.sys", self.device_id));
+        let mut staged_path = None;
+        let mut staged_hash = None;
 
-            let mut f = File::create(&dir).unwrap();
-            
-            let driver_bin_buffer: &[u8] = &&self.driver_bin;
-            f.write_all(driver_bin_buffer).unwrap();
+        // A driver bytearray takes precedence
+        let embedded_driver = self.embedded_driver.filter(|_| self.driver_bin.is_empty());
 
-            self.driver_path = dir;
+        let image = if !self.driver_bin.is_empty() {
+            Some(PeImage::parse(&self.driver_bin)?)
+        } else if embedded_driver.is_none() && self.driver_path.is_file() {
+            Some(PeImage::from_path(&self.driver_path)?)
+        } else {
+            None
+        };
+
+        if let Some(image) = image {
+            check_machine(&image)?;
+        }
+
+        if !self.driver_bin.is_empty() {
+            let path = staging::stage(&self.staging_dir, &self.service_name, &self.driver_bin)?;
+
+            self.driver_path = path.clone();
+            staged_path = Some(path);
+            staged_hash = Some(staging::sha256(&self.driver_bin));
+        } else if let Some(embedded) = embedded_driver {
+            let path = staging::staged_path(&self.staging_dir, &self.service_name, embedded.sha256());
+
+            self.driver_path = path.clone();
+            staged_path = Some(path);
+            staged_hash = Some(*embedded.sha256());
         }
 
         let driver = WinKernelDriver {
-            service_description: self.device_description,
-            driver_path: PathBuf::from(self.driver_path.clone()),
-            device_id: self.device_id,
+            display_name: self.display_name.clone(),
+            driver_path: self.driver_path.clone(),
+            staged_path,
+            staged_hash,
+            embedded_driver,
+            service_name: self.service_name.clone(),
+            symbolic_link: self.symbolic_link.clone().unwrap_or_else(|| self.service_name.clone()),
+            start_type: self.start_type,
+            error_control: self.error_control,
+            dependencies: self.dependencies.clone(),
+            transport: self.transport.clone(),
+            scm: self.scm.clone(),
+            service: DriverState::Staged,
+            install_outcome: None,
             device: None
         };
 
@@ -157,16 +251,50 @@ impl DriverBuilder {
     }
 }
 
+/// Fail with [Error::ArchitectureMismatch] unless `image` can be loaded by the running Windows
+fn check_machine(image: &PeImage) -> Result<()> {
+    let host = Machine::host();
+    if image.machine != host {
+        return Err(Error::ArchitectureMismatch { image: image.machine, host });
+    }
+
+    Ok(())
+}
+
+impl Default for DriverBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where a [WinKernelDriver] is in its lifecycle, see [WinKernelDriver::state]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DriverState {
+    /// No service has been installed through this handle yet
+    Staged,
+    /// The service is installed but stopped
+    Installed,
+    /// The service is installed and running
+    Started,
+    /// A handle to the device is open
+    Opened
+}
+
 /// A handle to a windows kernel driver.
 /// 
 /// The driver is installed as a service. Once installed io handles
 /// can be opened / closed with the driver.
 /// 
+/// The device handle is closed when the driver is dropped. The service is left
+/// installed, unless it is wrapped in a [ServiceGuard].
+/// 
 /// # Example
 /// 
-/// ```
-/// let driver_bin = include_bytes!("../winRing0.sys");
-/// let driver = DriverBuilder::new()
+/// ```no_run
+/// use win_kernel_driver::{DriverBuilder, io_control_code, Method, Access};
+///
+/// let driver_bin = include_bytes!("../../win_ring0/WinRing0x64.sys");
+/// let mut driver = DriverBuilder::new()
 ///              .set_device_description("winRing0 driver")
 ///              .set_device_id("WinRing0_1_2_0")
 ///              .set_device_type(40000)
@@ -177,9 +305,9 @@ impl DriverBuilder {
 /// driver.open().unwrap();
 /// 
 /// // Read MSR_TEMPERATURE_TARGET on intel CPUs
-/// let ioctl = io_control_code(40000, 0x800, Method::BUFFERED, Access::ANY);
-/// let out = device.io(ioctl, 0x1a2).unwrap();
-/// let edx = ((out >> 32) & 0xFFFFFFFF) as u32;
+/// let ioctl = io_control_code(40000, 0x821, Method::BUFFERED, Access::ANY);
+/// let out = driver.io(ioctl, 0x1a2).unwrap();
+/// let _edx = ((out >> 32) & 0xFFFFFFFF) as u32;
 /// let eax = (out & 0xFFFFFFFF) as u32;
 /// 
 /// let temp_target = (eax >> 16) & 0xff;
@@ -190,187 +318,708 @@ impl DriverBuilder {
 /// driver.uninstall().unwrap();
 /// ```
 pub struct WinKernelDriver {
-    service_description: &'static str,
+    display_name: String,
     driver_path: PathBuf,
-    device_id: &'static str,
-    device: Option<winnt::HANDLE>
+    staged_path: Option<PathBuf>,
+    staged_hash: Option<[u8; 32]>,
+    embedded_driver: Option<&'static EmbeddedDriver>,
+    service_name: String,
+    symbolic_link: String,
+    start_type: StartType,
+    error_control: ErrorControl,
+    dependencies: Vec<String>,
+    transport: Arc<dyn Transport>,
+    scm: Arc<dyn ServiceControlManager>,
+    service: DriverState,
+    install_outcome: Option<InstallOutcome>,
+    device: Option<OwnedDevice>
 }
 
 impl WinKernelDriver {
 
-    /// Install the driver service
-    pub fn install(&self) -> Result<(), String> {
-
-        let manager_access = ServiceManagerAccess::all();
-        let service_manager_res = ServiceManager::local_computer(None::<&str>, manager_access);
-        let service_manager: ServiceManager;
+    /// Install and start the driver service.
+    /// 
+    /// An existing service for the same driver file is reused. One left behind by a
+    /// different driver file is deleted and created again. See [InstallOutcome].
+    /// 
+    /// An embedded driver is staged first, see [DriverBuilder::set_embedded_driver]. It
+    /// fails with [Error::ArchitectureMismatch] if it does not match the running Windows.
+    /// A driver file staged by [DriverBuilder::build] is hashed again first, and fails
+    /// with [Error::ChecksumMismatch] if it was changed since it was staged.
+    pub fn install(&mut self) -> Result<InstallOutcome> {
+        if let Some(embedded) = self.embedded_driver {
+            // A freshly decompressed driver is checked before it is written, so a
+            // mismatch leaves no file behind
+            staging::stage_with(&self.driver_path, embedded.sha256(), || {
+                let bytes = embedded.decompress()?;
+                check_machine(&PeImage::parse(&bytes)?)?;
+                Ok(bytes)
+            })?;
+        } else if let Some(expected) = &self.staged_hash {
+            let actual = staging::sha256_file(&self.driver_path)
+                .map_err(|source| Error::ReadImage { path: self.driver_path.clone(), source })?;
 
-        match service_manager_res {
-            Ok(svcman) => { service_manager = svcman; },
-            Err(err) => { return Err(format!("Unable to connec to service manager: {}", err)); }
+            if actual != *expected {
+                return Err(Error::ChecksumMismatch { path: self.driver_path.clone() });
+            }
         }
 
-        let service_info = ServiceInfo {
-            name: OsString::from(self.device_id),
-            display_name: OsString::from(self.service_description),
-            service_type: ServiceType::KERNEL_DRIVER,
-            start_type: ServiceStartType::OnDemand,
-            error_control: ServiceErrorControl::Normal,
-            executable_path: self.driver_path.clone(),
-            launch_arguments: vec![],
-            dependencies: vec![],
-            account_name: None,
-            account_password: None
+        let config = ServiceConfig {
+            name: self.service_name.clone(),
+            display_name: self.display_name.clone(),
+            binary_path: self.driver_path.clone(),
+            start_type: self.start_type,
+            error_control: self.error_control,
+            dependencies: self.dependencies.clone()
         };
 
-        let service = service_manager.create_service(service_info, ServiceAccess::all());
-        match service {
-            Ok(svc) => {
-                let r = svc.start(&[OsString::from("")]);
-                match r {
-                    Ok(_) => { },
-                    Err(err) => { return Err(format!("Failed to start service: {:?}", err)); }
-                };
-            },
-            Err(err) => { return Err(format!("Service error: {:?}", err)); }
-        };
+        let outcome = scm::install(self.scm.as_ref(), &config)?;
+        info!(service = %self.service_name, ?outcome, "driver installed");
+        self.service = DriverState::Started;
+        self.install_outcome = Some(outcome);
+
+        Ok(outcome)
+    }
+
+    /// Install the driver service like [WinKernelDriver::install], and hand the driver
+    /// to a [ServiceGuard] that removes the service again when dropped, if it was
+    /// installed here.
+    pub fn install_guarded(mut self) -> Result<ServiceGuard> {
+        self.install()?;
+        Ok(ServiceGuard { driver: Some(self) })
+    }
+
+    /// Start the installed driver service again after [WinKernelDriver::stop]
+    pub fn start(&mut self) -> Result<()> {
+        scm::start(self.scm.as_ref(), &self.service_name)?;
+        self.service = DriverState::Started;
+
+        Ok(())
+    }
+
+    /// Close the device handle, if open, and stop the driver service
+    pub fn stop(&mut self) -> Result<()> {
+        self.close_device()?;
+        info!(service = %self.service_name, "stopping service");
+        self.scm.stop(&self.service_name)?;
+        self.service = DriverState::Installed;
 
         Ok(())
     }
     
-    /// Uninstall the driver service
-    pub fn uninstall(&self) -> Result<(), String> {
-        let manager_access = ServiceManagerAccess::all();
-        let service_manager_res = ServiceManager::local_computer(None::<&str>, manager_access);
-        let service_manager: ServiceManager;
-
-        match service_manager_res {
-            Ok(manager) => { service_manager = manager; },
-            Err(err) => { return Err(format!("Error getting service manager: {:?}", err)); }
+    /// Uninstall the driver service, and remove the driver file if it was staged by
+    /// [DriverBuilder::set_driver_bin] or [DriverBuilder::set_embedded_driver] and no
+    /// other service uses it. An open device handle is closed first.
+    /// 
+    /// Failing to remove the staged file is logged, not returned.
+    pub fn uninstall(&mut self) -> Result<()> {
+        self.close_device()?;
+        scm::uninstall(self.scm.as_ref(), &self.service_name)?;
+        self.service = DriverState::Staged;
+        self.install_outcome = None;
+
+        if let Some(path) = &self.staged_path {
+            self.remove_staged(path);
         }
 
-        let service: Service;
+        Ok(())
+    }
 
-        let open_res = service_manager.open_service(self.device_id, ServiceAccess::all());
-        match open_res {
-            Ok(svc) => { service = svc; },
-            Err(err) => { return Err(format!("Error opening service: {:?}", err)); }
+    /// Remove a staged driver file, unless another service loads it: staged files are
+    /// named after their content, so other processes may have installed the same one.
+    /// The service is already gone, so failures are only logged.
+    fn remove_staged(&self, path: &Path) {
+        match self.service_using(path) {
+            Ok(None) => {},
+            Ok(Some(service)) => {
+                debug!(?path, %service, "staged driver is used by another service, keeping it");
+                return;
+            },
+            Err(err) => {
+                warn!(?path, error = %err, "unable to check whether the staged driver is in use, keeping it");
+                return;
+            }
         }
 
-        let service_status: ServiceStatus;
-        match service.query_status() {
-            Ok(status) => service_status = status,
-            Err(err) => { return Err(format!("Error querying service status: {:?}", err)); }
+        if let Err(err) = staging::remove(path) {
+            warn!(?path, error = %err, "unable to remove staged driver");
         }
+    }
 
-        if service_status.current_state != ServiceState::Stopped {
-            match service.stop() {
-                Ok(_) => { },
-                Err(err) => { return Err(format!("Error stopping service: {:?}", err)); }
+    /// Another service whose driver file is `path`
+    fn service_using(&self, path: &Path) -> Result<Option<String>> {
+        for name in self.scm.driver_services()? {
+            if name == self.service_name {
+                continue;
             }
+            if let Some(details) = self.scm.query(&name)? {
+                if scm::same_binary(&details.binary_path, path) {
+                    return Ok(Some(name));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Where the driver is in its lifecycle.
+    /// 
+    /// Only reflects what was done through this handle: a driver installed by another
+    /// process is still [DriverState::Staged] here, but can be opened all the same.
+    pub fn state(&self) -> DriverState {
+        match self.device {
+            Some(_) => DriverState::Opened,
+            None => self.service
+        }
+    }
+
+    /// Current state of the driver service, as reported by the service control manager.
+    /// Fails with [Error::NotInstalled] if there is no service.
+    pub fn status(&self) -> Result<ServiceState> {
+        match self.scm.query(&self.service_name)? {
+            Some(details) => Ok(details.state),
+            None => Err(Error::not_installed(&self.service_name))
         }
+    }
+
+    /// Wait until the driver service reaches `state`, e.g. [ServiceState::Running]
+    /// before calling [WinKernelDriver::open]. Fails with [Error::ServiceStateTimeout]
+    /// if it hasn't after `timeout`.
+    /// 
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use win_kernel_driver::{DriverBuilder, FakeServiceManager, ServiceState};
+    /// 
+    /// let mut driver = DriverBuilder::new()
+    ///     .set_service_name("WinRing0_1_2_0")
+    ///     .set_symbolic_link("WinRing0_1_2_0")
+    ///     .set_display_name("WinRing0")
+    ///     .set_driver_path(r"C:\drivers\WinRing0x64.sys".into())
+    ///     .set_service_manager(FakeServiceManager::new())
+    ///     .build().unwrap();
+    /// 
+    /// driver.install().unwrap();
+    /// driver.wait_for_state(ServiceState::Running, Duration::from_secs(5)).unwrap();
+    /// ```
+    pub fn wait_for_state(&self, state: ServiceState, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+
+        loop {
+            let actual = self.status()?;
+            if actual == state {
+                return Ok(());
+            }
 
-        match service.delete() {
-            Ok(()) => { return Ok(()); },
-            Err(err) => { return Err(format!("Error deleting service: {:?}", err)); }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(Error::ServiceStateTimeout { name: self.service_name.clone(), expected: state, actual });
+            }
+
+            thread::sleep(STATE_POLL_INTERVAL.min(timeout - elapsed));
         }
     }
+
+    /// Name of the driver service
+    pub fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    /// What [WinKernelDriver::install] had to do, if it was called
+    pub fn install_outcome(&self) -> Option<InstallOutcome> {
+        self.install_outcome
+    }
     
     /// Open the driver service. Once opened the [WinKernelDriver::io()] function can be called.
-    pub fn open(&mut self) -> Result<(), String> {
+    /// 
+    /// Asks for read and write access without sharing, see [WinKernelDriver::open_with].
+    pub fn open(&mut self) -> Result<()> {
+        self.open_with(&OpenOptions::new())
+    }    
+
+    /// Open the driver service with explicit access and share modes
+    pub fn open_with(&mut self, options: &OpenOptions) -> Result<()> {
 
         if self.opened() {
-            return Err("Driver already opened".to_string());
+            return Err(Error::AlreadyOpen);
         }
 
-        let mut driver_path_t: String = r"\\.\".to_string();
-        driver_path_t.push_str(self.device_id);
-        let driver_path = driver_path_t.as_str();
-
-        unsafe {
-            let device: winnt::HANDLE = fileapi::CreateFileA(
-                CString::new(driver_path).unwrap().as_ptr(),
-                winnt::GENERIC_READ | winnt::GENERIC_WRITE,
-                0,
-                null_mut(),
-                fileapi::OPEN_EXISTING,
-                winnt::FILE_ATTRIBUTE_NORMAL,
-                null_mut()
-            );
-
-            if device == handleapi::INVALID_HANDLE_VALUE {
-                return Err("Error occurred getting handle on kernel driver".to_string());
-            } else {
-                println!("Handle created");
+        let device = OwnedDevice::open_with(self.transport.clone(), &self.device_path(), options)?;
+        self.device = Some(device);
+
+        Ok(())
+    }
+
+    /// Open the driver service, retrying while the device doesn't exist yet.
+    /// 
+    /// The driver creates its device once the service has started, which can take a
+    /// moment. Opens failing with `ERROR_FILE_NOT_FOUND` or `ERROR_PATH_NOT_FOUND` are
+    /// retried with a growing delay until `timeout` has passed; the last error is
+    /// returned then. Other errors, like access denied, are returned right away.
+    /// 
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use win_kernel_driver::{DriverBuilder, MockTransport, OpenOptions};
+    /// 
+    /// let mock = MockTransport::new();
+    /// mock.fail_opens(2, 3);
+    /// 
+    /// let mut driver = DriverBuilder::new()
+    ///     .set_device_id("WinRing0_1_2_0")
+    ///     .set_driver_path("WinRing0x64.sys".into())
+    ///     .set_transport(mock.clone())
+    ///     .build().unwrap();
+    /// 
+    /// driver.open_with_retry(&OpenOptions::read_only().set_shared(true), Duration::from_secs(5)).unwrap();
+    /// assert_eq!(mock.opened_paths().len(), 4);
+    /// ```
+    pub fn open_with_retry(&mut self, options: &OpenOptions, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        let mut delay = OPEN_RETRY_INITIAL;
+
+        loop {
+            match self.open_with(options) {
+                Err(err) if device_missing(&err) => {
+                    let elapsed = start.elapsed();
+                    if elapsed >= timeout {
+                        return Err(err);
+                    }
+
+                    debug!(error = %err, ?delay, "device not there yet, retrying");
+                    thread::sleep(delay.min(timeout - elapsed));
+                    delay = (delay * 2).min(OPEN_RETRY_MAX);
+                },
+                result => { return result; }
             }
-            
-            self.device = Some(device);
         }
+    }
 
-        Ok(())
-    }    
+    /// Open a separate handle to the driver for async IO. It does not need
+    /// [WinKernelDriver::open] and stays usable after [WinKernelDriver::close].
+    pub fn open_async(&self) -> Result<AsyncDriver> {
+        AsyncDriver::open(self.transport.clone(), &self.device_path())
+    }
+
+    /// Open a separate handle to the driver that can be cloned and shared between
+    /// threads. It does not need [WinKernelDriver::open] and stays usable after
+    /// [WinKernelDriver::close].
+    pub fn session(&self, concurrency: Concurrency) -> Result<DriverSession> {
+        DriverSession::open(self.transport.clone(), &self.device_path(), concurrency)
+    }
+
+    fn device_path(&self) -> String {
+        let mut driver_path_t: String = r"\\.\".to_string();
+        driver_path_t.push_str(&self.symbolic_link);
+        driver_path_t
+    }
 
     /// Check to see if there is an open handle to the driver
     pub fn opened(&self) -> bool {
-        match self.device {
-            Some(_) => { return true; }
-            None => { return false; }
-        }
+        self.device.is_some()
     }
     
     /// Close the open handle to the driver
-    pub fn close(&mut self) -> Result<(), String> {
+    pub fn close(&mut self) -> Result<()> {
 
-        if !self.opened() {
-            return Err("Driver not opened".to_string());
+        match self.device.take() {
+            Some(device) => device.close(),
+            None => Err(Error::NotOpen)
         }
+    }
 
-        let handle = self.device.unwrap();
-        unsafe {
-            handleapi::CloseHandle(handle);
-        }        
-
-        Ok(())
+    fn close_device(&mut self) -> Result<()> {
+        match self.device.take() {
+            Some(device) => device.close(),
+            None => Ok(())
+        }
     }
 
     /// Perform an IO command on the driver.
     /// 
     /// To know which IO commands are available, you must check with the driver
     /// you are trying to work with. IO control codes should be made with
-    /// the [io_control_code] function.
-    pub fn io(&self, ioctl_code: u32, mut in_buffer: u32) -> Result<u64, String> {
-        if !self.opened() {
-            return Err("Driver not opened!".to_string());
+    /// [IoControlCode::new] or the [io_control_code](crate::io_control_code) function.
+    /// 
+    /// Sends a single `u32` and reads back up to 8 bytes. For anything else
+    /// use [WinKernelDriver::io_bytes] or [WinKernelDriver::io_typed].
+    pub fn io<C: Into<IoControlCode>>(&self, ioctl_code: C, in_buffer: u32) -> Result<u64> {
+        let mut out_buffer = [0u8; size_of::<u64>()];
+        self.io_bytes(ioctl_code, &in_buffer.to_le_bytes(), &mut out_buffer)?;
+
+        Ok(u64::from_le_bytes(out_buffer))
+    }
+
+    /// Perform a list of `(ioctl_code, input, output)` commands back to back, like
+    /// [WinKernelDriver::io_bytes] does for one.
+    /// 
+    /// The handle is checked once up front. After that a failing command doesn't stop
+    /// the batch; its error is kept in its [IoResult](crate::IoResult) and the next one is sent.
+    /// The driver writes straight into the caller's output buffers, the batch only records
+    /// how many bytes went into each.
+    /// 
+    /// # Example
+    /// ```
+    /// use win_kernel_driver::{DriverBuilder, MockTransport, io_control_code, Method, Access};
+    ///
+    /// let read_msr = io_control_code(40000, 0x821, Method::BUFFERED, Access::ANY);
+    ///
+    /// let mock = MockTransport::new();
+    /// mock.expect(read_msr).with_input(&0x1a2u32.to_le_bytes()).returns(&0x0064_0000_0000_0000u64.to_le_bytes());
+    /// mock.expect(read_msr).with_input(&0x19cu32.to_le_bytes()).returns(&0x8838_0000u64.to_le_bytes());
+    ///
+    /// let mut driver = DriverBuilder::new()
+    ///     .set_device_id("WinRing0_1_2_0")
+    ///     .set_driver_path("WinRing0x64.sys".into())
+    ///     .set_transport(mock)
+    ///     .build().unwrap();
+    /// driver.open().unwrap();
+    ///
+    /// let inputs = [0x1a2u32.to_le_bytes(), 0x19cu32.to_le_bytes(), 0xdeadu32.to_le_bytes()];
+    /// let mut outputs = [[0u8; 8]; 3];
+    /// let batch = driver.io_batch(
+    ///     inputs.iter().zip(outputs.iter_mut()).map(|(input, output)| (read_msr, &input[..], &mut output[..]))
+    /// ).unwrap();
+    ///
+    /// assert_eq!(batch.written(), vec![Some(8), Some(8), None]);
+    /// assert_eq!(batch.failures().next().unwrap().0, 2);
+    /// assert_eq!(u64::from_le_bytes(outputs[0]), 0x0064_0000_0000_0000);
+    /// assert_eq!(u64::from_le_bytes(outputs[1]), 0x8838_0000);
+    /// ```
+    pub fn io_batch<'a, 'b, C, R>(&self, requests: R) -> Result<IoBatch>
+        where C: Into<IoControlCode>, R: IntoIterator<Item = (C, &'a [u8], &'b mut [u8])>
+    {
+        let device = self.device.as_ref().ok_or(Error::NotOpen)?;
+        let batch_start = Instant::now();
+        let requests = requests.into_iter();
+        let mut results = Vec::with_capacity(requests.size_hint().0);
+
+        for (ioctl_code, input, output) in requests {
+            let ioctl_code = ioctl_code.into();
+            let start = Instant::now();
+            let result = checked_ioctl(device, ioctl_code, input, output);
+
+            results.push(IoResult { ioctl_code, result, elapsed: start.elapsed() });
         }
 
-        let device = self.device.unwrap() as winnt::HANDLE;
+        Ok(IoBatch { results, elapsed: batch_start.elapsed() })
+    }
 
-        let mut out_buffer = [0u8; size_of::<u64>()];
-        let in_buffer_bytes = in_buffer.to_be_bytes();
-
-        let in_buffer_size = u32::try_from(in_buffer_bytes.len()).unwrap() as DWORD;
-        let out_buffer_size = out_buffer.len() as u32;
-        let mut out_buffer_written: DWORD = 0;
-
-        unsafe {
-            let res = ioapiset::DeviceIoControl(
-                device,
-                ioctl_code,
-                &mut in_buffer as *mut _ as *mut c_void,
-                in_buffer_size,
-                out_buffer.as_mut_ptr() as *mut _,
-                out_buffer_size,
-                &mut out_buffer_written,
-                null_mut()
-            );
-
-            if res != 0 {
-                let o = u64::from_le_bytes(out_buffer);
-                return Ok(o);
-            } else {
-                let last_error = errhandlingapi::GetLastError();
-                return Err(format!("DeviceIoControl - Unable to write command {:x}. Last error code: {:x}", ioctl_code, last_error));
+    /// Perform an IO command with arbitrary input and output buffers.
+    /// 
+    /// Returns the number of bytes the driver wrote to `out_buffer`. Either buffer
+    /// may be empty if the command takes no input or produces no output.
+    /// 
+    /// The transfer [Method] encoded in `ioctl_code` decides how the buffers reach the
+    /// driver. With `METHOD_IN_DIRECT` and `METHOD_OUT_DIRECT` the driver maps `out_buffer`
+    /// directly (for `IN_DIRECT` it reads from it), so it must not be empty.
+    pub fn io_bytes<C: Into<IoControlCode>>(&self, ioctl_code: C, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        let device = self.device.as_ref().ok_or(Error::NotOpen)?;
+
+        checked_ioctl(device, ioctl_code.into(), in_buffer, out_buffer)
+    }
+
+    /// Perform an IO command with typed input and output structures.
+    /// 
+    /// `I` and `O` are usually `#[repr(C)]` mirrors of the structures declared in the
+    /// driver's headers. Use `()` for commands without input. Fails with
+    /// [Error::ShortOutput] if the driver wrote less than `size_of::<O>()` bytes.
+    /// 
+    /// # Example
+    /// ```no_run
+    /// # use win_kernel_driver::{DriverBuilder, io_control_code, Method, Access};
+    /// # let mut driver = DriverBuilder::new().set_device_id("WinRing0_1_2_0").set_driver_path("WinRing0x64.sys".into()).build().unwrap();
+    /// // Read MSR_TEMPERATURE_TARGET with winRing0
+    /// let ioctl = io_control_code(40000, 0x821, Method::BUFFERED, Access::ANY);
+    /// let out: [u32; 2] = driver.io_typed(ioctl, &0x1a2u32).unwrap();
+    /// ```
+    pub fn io_typed<C: Into<IoControlCode>, I: Pod, O: Pod>(&self, ioctl_code: C, input: &I) -> Result<O> {
+        DeviceIo::io_typed(self, ioctl_code.into(), input)
+    }
+}
+
+impl DeviceIo for WinKernelDriver {
+    fn io_bytes(&self, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        WinKernelDriver::io_bytes(self, ioctl_code, in_buffer, out_buffer)
+    }
+}
+
+fn device_missing(err: &Error) -> bool {
+    match err {
+        Error::OpenFailed { code, .. } => *code == ERROR_FILE_NOT_FOUND || *code == ERROR_PATH_NOT_FOUND,
+        _ => false
+    }
+}
+
+/// Send an IO command to `device` once its buffers pass [check_buffers]
+fn checked_ioctl(device: &OwnedDevice, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+    check_buffers(ioctl_code, out_buffer)?;
+
+    device.ioctl(ioctl_code, in_buffer, out_buffer)
+}
+
+/// Check that the buffers can be used with the transfer method of `ioctl_code`
+pub(crate) fn check_buffers(ioctl_code: IoControlCode, out_buffer: &[u8]) -> Result<()> {
+    match ioctl_code.method() {
+        Method::INDIRECT | Method::OUTDIRECT if out_buffer.is_empty() => {
+            Err(Error::InvalidBuffer { ioctl: ioctl_code, reason: "direct IO needs an output buffer" })
+        },
+        _ => Ok(())
+    }
+}
+
+/// A [WinKernelDriver] whose service is removed again when the guard is dropped, made
+/// by [WinKernelDriver::install_guarded].
+/// 
+/// The service is only stopped and uninstalled if this process installed it
+/// ([InstallOutcome::Created] or [InstallOutcome::Recreated]). A service that was
+/// already there and reused is left for whoever installed it. Errors while cleaning
+/// up are ignored. The guard derefs to the driver, so it can be opened and used as usual.
+/// 
+/// Cleanup runs when the guard goes out of scope, including while unwinding from a
+/// panic, but not when the process is killed or aborts.
+/// 
+/// # Example
+/// ```
+/// use win_kernel_driver::{DriverBuilder, DriverState, FakeServiceManager, MockTransport};
+/// 
+/// let fake = FakeServiceManager::new();
+/// 
+/// let driver = DriverBuilder::new()
+///     .set_device_id("WinRing0_1_2_0")
+///     .set_driver_path(r"C:\drivers\WinRing0x64.sys".into())
+///     .set_service_manager(fake.clone())
+///     .set_transport(MockTransport::new())
+///     .build().unwrap();
+/// 
+/// {
+///     let mut driver = driver.install_guarded().unwrap();
+///     driver.open().unwrap();
+///     assert_eq!(driver.state(), DriverState::Opened);
+/// }
+/// 
+/// assert!(fake.service("WinRing0_1_2_0").is_none());
+/// ```
+pub struct ServiceGuard {
+    driver: Option<WinKernelDriver>
+}
+
+impl ServiceGuard {
+    /// Give up the guard, leaving the service installed
+    pub fn into_inner(mut self) -> WinKernelDriver {
+        self.driver.take().unwrap()
+    }
+}
+
+impl Deref for ServiceGuard {
+    type Target = WinKernelDriver;
+
+    fn deref(&self) -> &WinKernelDriver {
+        self.driver.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ServiceGuard {
+    fn deref_mut(&mut self) -> &mut WinKernelDriver {
+        self.driver.as_mut().unwrap()
+    }
+}
+
+impl Drop for ServiceGuard {
+    fn drop(&mut self) {
+        if let Some(mut driver) = self.driver.take() {
+            match driver.install_outcome {
+                Some(InstallOutcome::Created) | Some(InstallOutcome::Recreated) => {
+                    if let Err(err) = driver.uninstall() {
+                        debug!(service = %driver.service_name, error = %err, "guard could not uninstall the service");
+                    }
+                },
+                _ => { }
             }
         }
-    }    
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use crate::embed::compress_driver;
+    use crate::ioctl::{io_control_code, Access};
+    use crate::scm::FakeServiceManager;
+    use crate::transport::MockTransport;
+
+    /// The WinRing0 driver built for another architecture than the host
+    fn foreign_driver() -> &'static [u8] {
+        if Machine::host() == Machine::X86 {
+            include_bytes!("../../win_ring0/WinRing0x64.sys")
+        } else {
+            include_bytes!("../../win_ring0/WinRing0.sys")
+        }
+    }
+
+    #[test]
+    fn foreign_embedded_driver_is_not_staged() {
+        let staging_dir = env::temp_dir().join(format!("wkd-foreign-embedded-{}", process::id()));
+        let (compressed, sha256) = compress_driver(foreign_driver());
+        let embedded: &'static EmbeddedDriver = Box::leak(Box::new(
+            EmbeddedDriver::new("WinRing0.sys", Box::leak(compressed.into_boxed_slice()), sha256)
+        ));
+
+        let mut driver = DriverBuilder::new()
+            .set_device_id("WinRing0_1_2_0")
+            .set_embedded_driver(embedded)
+            .set_staging_dir(staging_dir.clone())
+            .set_service_manager(FakeServiceManager::new())
+            .build().unwrap();
+        assert!(!staging_dir.exists());
+
+        assert!(matches!(driver.install(), Err(Error::ArchitectureMismatch { .. })));
+        assert!(!staging::staged_path(&staging_dir, "WinRing0_1_2_0", &sha256).exists());
+
+        let _ = fs::remove_dir_all(staging_dir);
+    }
+
+    #[test]
+    fn embedded_driver_is_staged_on_install() {
+        let staging_dir = env::temp_dir().join(format!("wkd-embedded-install-{}", process::id()));
+        let native = native_driver();
+        let (compressed, sha256) = compress_driver(&native);
+        let embedded: &'static EmbeddedDriver = Box::leak(Box::new(
+            EmbeddedDriver::new("WinRing0.sys", Box::leak(compressed.into_boxed_slice()), sha256)
+        ));
+
+        let mut driver = DriverBuilder::new()
+            .set_device_id("WinRing0_1_2_0")
+            .set_embedded_driver(embedded)
+            .set_staging_dir(staging_dir.clone())
+            .set_service_manager(FakeServiceManager::new())
+            .build().unwrap();
+        let path = staging::staged_path(&staging_dir, "WinRing0_1_2_0", &sha256);
+        assert!(!path.exists());
+
+        driver.install().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), native);
+
+        driver.uninstall().unwrap();
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(staging_dir);
+    }
+
+    #[test]
+    fn foreign_driver_bin_is_not_staged() {
+        let staging_dir = env::temp_dir().join(format!("wkd-foreign-bin-{}", process::id()));
+
+        let result = DriverBuilder::new()
+            .set_device_id("WinRing0_1_2_0")
+            .set_driver_bin(foreign_driver().to_vec())
+            .set_staging_dir(staging_dir.clone())
+            .set_service_manager(FakeServiceManager::new())
+            .build();
+
+        assert!(matches!(result, Err(Error::ArchitectureMismatch { .. })));
+        assert!(!staging::staged_path(&staging_dir, "WinRing0_1_2_0", &staging::sha256(foreign_driver())).exists());
+
+        let _ = fs::remove_dir_all(staging_dir);
+    }
+
+    #[test]
+    fn io_batch_checks_buffers_of_every_request() {
+        let buffered = io_control_code(40000, 0x821, Method::BUFFERED, Access::ANY);
+        let direct = io_control_code(40000, 0x822, Method::OUTDIRECT, Access::ANY);
+
+        let mock = MockTransport::new();
+        mock.expect(buffered).returns(&[1, 2, 3, 4]);
+        mock.expect(direct).returns(&[5, 6]);
+
+        let mut driver = DriverBuilder::new()
+            .set_device_id("WinRing0_1_2_0")
+            .set_driver_path("WinRing0x64.sys".into())
+            .set_transport(mock.clone())
+            .build().unwrap();
+        assert!(matches!(driver.io_batch(vec![(buffered, &[][..], &mut [0u8; 4][..])]), Err(Error::NotOpen)));
+        driver.open().unwrap();
+
+        let (mut first, mut third) = ([0u8; 8], [0u8; 2]);
+        let batch = driver.io_batch(vec![
+            (buffered, &[0xa2, 0x01][..], &mut first[..]),
+            (direct, &[][..], &mut [][..]),
+            (direct, &[][..], &mut third[..])
+        ]).unwrap();
+
+        assert_eq!(batch.written(), vec![Some(4), None, Some(2)]);
+        assert!(matches!(batch.results[1].result, Err(Error::InvalidBuffer { .. })));
+        assert_eq!(batch.failures().map(|(i, r)| (i, r.ioctl_code)).collect::<Vec<_>>(), vec![(1, direct.into())]);
+        assert_eq!(first, [1, 2, 3, 4, 0, 0, 0, 0]);
+        assert_eq!(third, [5, 6]);
+        assert_eq!(mock.calls().len(), 2);
+    }
+
+    /// The WinRing0 driver built for the host
+    fn native_driver() -> Vec<u8> {
+        if Machine::host() == Machine::X86 {
+            include_bytes!("../../win_ring0/WinRing0.sys").to_vec()
+        } else {
+            include_bytes!("../../win_ring0/WinRing0x64.sys").to_vec()
+        }
+    }
+
+    fn staged_driver(staging_dir: &Path, fake: &FakeServiceManager) -> WinKernelDriver {
+        DriverBuilder::new()
+            .set_device_id("WinRing0_1_2_0")
+            .set_driver_bin(native_driver())
+            .set_staging_dir(staging_dir.to_path_buf())
+            .set_service_manager(fake.clone())
+            .build().unwrap()
+    }
+
+    #[test]
+    fn uninstall_removes_staged_file() {
+        let staging_dir = env::temp_dir().join(format!("wkd-uninstall-removes-{}", process::id()));
+        let fake = FakeServiceManager::new();
+        let mut driver = staged_driver(&staging_dir, &fake);
+        let path = driver.driver_path.clone();
+
+        driver.install().unwrap();
+        driver.uninstall().unwrap();
+
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(staging_dir);
+    }
+
+    #[test]
+    fn uninstall_keeps_staged_file_used_by_another_service() {
+        let staging_dir = env::temp_dir().join(format!("wkd-uninstall-shared-{}", process::id()));
+        let fake = FakeServiceManager::new();
+        let mut driver = staged_driver(&staging_dir, &fake);
+        let path = driver.driver_path.clone();
+        fake.add_service("OtherTool", format!(r"\??\{}", path.display()), ServiceState::Running);
+
+        driver.install().unwrap();
+        driver.uninstall().unwrap();
+
+        assert!(path.exists());
+        assert!(fake.service("WinRing0_1_2_0").is_none());
+        let _ = fs::remove_dir_all(staging_dir);
+    }
+
+    #[test]
+    fn uninstall_succeeds_when_staged_file_cant_be_removed() {
+        let staging_dir = env::temp_dir().join(format!("wkd-uninstall-locked-{}", process::id()));
+        let fake = FakeServiceManager::new();
+        let mut driver = staged_driver(&staging_dir, &fake);
+        let path = driver.driver_path.clone();
+
+        driver.install().unwrap();
+        // A directory in place of the file can't be removed like one
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+
+        driver.uninstall().unwrap();
+        assert!(path.is_dir());
+        assert!(fake.service("WinRing0_1_2_0").is_none());
+        let _ = fs::remove_dir_all(staging_dir);
+    }
 }
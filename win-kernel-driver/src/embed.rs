@@ -0,0 +1,157 @@
+//! Driver payloads embedded in the program
+//!
+//! Embedding a driver with `include_bytes!` stores it uncompressed in every binary.
+//! Instead a build script compresses it with [embed_driver], which also records its
+//! SHA-256, and [include_driver!](crate::include_driver) turns the result into an
+//! [EmbeddedDriver]. It is only decompressed when a driver built with
+//! [DriverBuilder::set_embedded_driver](crate::DriverBuilder::set_embedded_driver) is
+//! installed and has to write the driver file, and the bytes are checked against the hash first.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+
+use crate::error::{Error, Result};
+use crate::staging;
+
+/// Compression level used by [embed_driver], the highest miniz supports
+const COMPRESSION_LEVEL: u8 = 10;
+/// Drivers are a few hundred kilobytes at most, anything larger is not a driver
+const MAX_DRIVER_SIZE: usize = 64 * 1024 * 1024;
+
+/// A driver compressed at build time, together with the SHA-256 of the original file
+///
+/// # Example
+/// ```
+/// use win_kernel_driver::{compress_driver, EmbeddedDriver};
+///
+/// let original = b"MZ not really a driver".repeat(100);
+/// let (compressed, sha256) = compress_driver(&original);
+/// let compressed: &'static [u8] = Box::leak(compressed.into_boxed_slice());
+/// let driver = EmbeddedDriver::new("example.sys", compressed, sha256);
+///
+/// assert!(driver.compressed().len() < original.len());
+/// assert_eq!(driver.decompress().unwrap(), original);
+///
+/// // A payload that does not match its hash is rejected
+/// let tampered = EmbeddedDriver::new("example.sys", compressed, [0; 32]);
+/// assert!(tampered.decompress().is_err());
+/// ```
+#[derive(Clone, Copy)]
+pub struct EmbeddedDriver {
+    name: &'static str,
+    compressed: &'static [u8],
+    sha256: [u8; 32]
+}
+
+impl EmbeddedDriver {
+    /// A driver named `name`, compressed with [compress_driver], that hashes to `sha256`
+    /// once decompressed. Usually created through [include_driver!](crate::include_driver).
+    pub const fn new(name: &'static str, compressed: &'static [u8], sha256: [u8; 32]) -> Self {
+        EmbeddedDriver { name, compressed, sha256 }
+    }
+
+    /// File name of the driver
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The compressed payload
+    pub fn compressed(&self) -> &'static [u8] {
+        self.compressed
+    }
+
+    /// SHA-256 of the decompressed driver
+    pub fn sha256(&self) -> &[u8; 32] {
+        &self.sha256
+    }
+
+    /// Decompress the driver. Fails with [Error::CorruptPayload] if the payload is
+    /// damaged or does not match the recorded SHA-256.
+    pub fn decompress(&self) -> Result<Vec<u8>> {
+        let bytes = decompress_to_vec_with_limit(self.compressed, MAX_DRIVER_SIZE)
+            .map_err(|err| Error::CorruptPayload { name: self.name, reason: err.to_string() })?;
+
+        if staging::sha256(&bytes) != self.sha256 {
+            return Err(Error::CorruptPayload { name: self.name, reason: "SHA-256 does not match".to_string() });
+        }
+
+        Ok(bytes)
+    }
+}
+
+impl fmt::Debug for EmbeddedDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmbeddedDriver")
+            .field("name", &self.name)
+            .field("compressed_len", &self.compressed.len())
+            .field("sha256", &staging::to_hex(&self.sha256))
+            .finish()
+    }
+}
+
+/// Compress driver bytes the way [EmbeddedDriver] expects them. Returns the
+/// compressed bytes and the SHA-256 of the original ones.
+pub fn compress_driver(bytes: &[u8]) -> (Vec<u8>, [u8; 32]) {
+    (compress_to_vec(bytes, COMPRESSION_LEVEL), staging::sha256(bytes))
+}
+
+/// Compress the driver at `source` into `out_dir`, for use from a build script.
+///
+/// Writes `{file name}.deflate` with the compressed driver and `{file name}.sha256`
+/// with its hash, which [include_driver!](crate::include_driver) picks up, and tells
+/// cargo to run the build script again when the driver changes. Returns the path of
+/// the compressed file.
+///
+/// # Example
+/// In `build.rs`:
+/// ```no_run
+/// use std::env;
+/// use std::path::{Path, PathBuf};
+///
+/// let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+/// win_kernel_driver::embed_driver(Path::new("my_driver.sys"), &out_dir).unwrap();
+/// ```
+pub fn embed_driver(source: &Path, out_dir: &Path) -> io::Result<PathBuf> {
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    let bytes = fs::read(source)?;
+    let file_name = source.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "driver path has no file name"))?
+        .to_string_lossy();
+
+    let (compressed, sha256) = compress_driver(&bytes);
+    let compressed_path = out_dir.join(format!("{}.deflate", file_name));
+    fs::write(&compressed_path, compressed)?;
+    fs::write(out_dir.join(format!("{}.sha256", file_name)), sha256)?;
+
+    Ok(compressed_path)
+}
+
+/// Create the [EmbeddedDriver] for a driver a build script compressed with
+/// [embed_driver], by its file name.
+///
+/// # Example
+/// ```ignore
+/// use win_kernel_driver::{include_driver, DriverBuilder, EmbeddedDriver};
+///
+/// static MY_DRIVER: EmbeddedDriver = include_driver!("my_driver.sys");
+///
+/// let driver = DriverBuilder::new()
+///     .set_device_id("MyDriver")
+///     .set_embedded_driver(&MY_DRIVER)
+///     .build().unwrap();
+/// ```
+#[macro_export]
+macro_rules! include_driver {
+    ($file:literal) => {
+        $crate::EmbeddedDriver::new(
+            $file,
+            include_bytes!(concat!(env!("OUT_DIR"), "/", $file, ".deflate")),
+            *include_bytes!(concat!(env!("OUT_DIR"), "/", $file, ".sha256"))
+        )
+    };
+}
@@ -0,0 +1,322 @@
+//! Errors returned by the driver and service functions
+// err-derive generates its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use err_derive::Error;
+
+use crate::ioctl::IoControlCode;
+use crate::pe::Machine;
+use crate::scm::ServiceState;
+
+/// Win32 `ERROR_INVALID_FUNCTION`
+pub const ERROR_INVALID_FUNCTION: u32 = 1;
+/// Win32 `ERROR_FILE_NOT_FOUND`
+pub const ERROR_FILE_NOT_FOUND: u32 = 2;
+/// Win32 `ERROR_PATH_NOT_FOUND`
+pub const ERROR_PATH_NOT_FOUND: u32 = 3;
+/// Win32 `ERROR_ACCESS_DENIED`
+pub const ERROR_ACCESS_DENIED: u32 = 5;
+/// Win32 `ERROR_INVALID_HANDLE`
+pub const ERROR_INVALID_HANDLE: u32 = 6;
+/// Win32 `ERROR_INSUFFICIENT_BUFFER`
+pub const ERROR_INSUFFICIENT_BUFFER: u32 = 122;
+/// Win32 `ERROR_INVALID_NAME`
+#[cfg(windows)]
+pub const ERROR_INVALID_NAME: u32 = 123;
+/// Win32 `ERROR_OPERATION_ABORTED`
+pub const ERROR_OPERATION_ABORTED: u32 = 995;
+/// Win32 `ERROR_SERVICE_DOES_NOT_EXIST`
+pub const ERROR_SERVICE_DOES_NOT_EXIST: u32 = 1060;
+/// Win32 `ERROR_SERVICE_MARKED_FOR_DELETE`
+pub const ERROR_SERVICE_MARKED_FOR_DELETE: u32 = 1072;
+/// Win32 `ERROR_SERVICE_EXISTS`
+pub const ERROR_SERVICE_EXISTS: u32 = 1073;
+
+/// The error behind a failed service control manager call: the `windows_service` error
+/// on Windows, an [io::Error] holding the Win32 code elsewhere
+#[cfg(windows)]
+pub type ServiceSource = windows_service::Error;
+/// The error behind a failed service control manager call: the `windows_service` error
+/// on Windows, an [io::Error] holding the Win32 code elsewhere
+#[cfg(not(windows))]
+pub type ServiceSource = io::Error;
+
+/// Errors that can occur while installing or communicating with a kernel driver
+#[derive(Debug, Error)]
+pub enum Error {
+    /// [DriverBuilder::build](crate::DriverBuilder::build) was called without a device id
+    #[error(display = "Device ID needs to be set")]
+    MissingDeviceId,
+
+    /// [DriverBuilder::build](crate::DriverBuilder::build) was called without a driver path or binary
+    #[error(display = "Either a path to the driver file, or a binary array of the driver file, must be set")]
+    MissingDriver,
+
+    /// The driver binary could not be written to disk
+    #[error(display = "Unable to write driver binary to {:?}", path)]
+    DriverStaging {
+        path: PathBuf,
+        #[error(cause)]
+        source: io::Error
+    },
+
+    /// The driver file could not be read
+    #[error(display = "Unable to read driver image {:?}", path)]
+    ReadImage {
+        path: PathBuf,
+        #[error(cause)]
+        source: io::Error
+    },
+
+    /// The driver file is not a valid PE image
+    #[error(display = "Invalid driver image: {}", _0)]
+    InvalidImage(&'static str),
+
+    /// The driver was built for a different architecture than the running Windows
+    #[error(display = "Driver image is built for {} but Windows is running on {}", image, host)]
+    ArchitectureMismatch { image: Machine, host: Machine },
+
+    /// An [EmbeddedDriver](crate::EmbeddedDriver) could not be decompressed, or did not
+    /// decompress to the bytes it was built from
+    #[error(display = "Embedded driver {} is corrupt: {}", name, reason)]
+    CorruptPayload { name: &'static str, reason: String },
+
+    /// The driver file changed on disk after it was staged, and was not installed
+    #[error(display = "Driver file {:?} does not match the SHA-256 it was staged with", path)]
+    ChecksumMismatch { path: PathBuf },
+
+    /// The driver service does not exist
+    #[error(display = "Driver service {} is not installed", name)]
+    NotInstalled {
+        name: String,
+        #[error(cause)]
+        source: ServiceSource
+    },
+
+    /// A service with the same name already exists
+    #[error(display = "Driver service {} already exists", name)]
+    ServiceExists {
+        name: String,
+        #[error(cause)]
+        source: ServiceSource
+    },
+
+    /// The service was deleted but other handles to it are still open.
+    /// It will go away once every handle is closed or after a reboot.
+    #[error(display = "Driver service {} is marked for deletion", name)]
+    ServiceMarkedForDeletion {
+        name: String,
+        #[error(cause)]
+        source: ServiceSource
+    },
+
+    /// The service was reinstalled with another driver file since it was looked at
+    #[error(display = "Driver service {} changed since it was found", _0)]
+    ServiceChanged(String),
+
+    /// [WinKernelDriver::install](crate::WinKernelDriver::install) found the service changed by someone else on every attempt
+    #[error(display = "Driver service {} kept changing while being installed, gave up after {} attempts", name, attempts)]
+    ServiceUnstable { name: String, attempts: usize },
+
+    /// The caller lacks the rights for the operation. Installing drivers and
+    /// opening most devices requires administrator privileges.
+    #[error(display = "Access denied while trying to {}", operation)]
+    AccessDenied {
+        operation: &'static str,
+        #[error(cause)]
+        source: io::Error
+    },
+
+    /// The service did not reach the state waited for in time
+    #[error(display = "Driver service {} is {:?} instead of {:?}", name, actual, expected)]
+    ServiceStateTimeout { name: String, expected: ServiceState, actual: ServiceState },
+
+    /// Any other service control manager failure
+    #[error(display = "Service control manager failed to {}", operation)]
+    Service {
+        operation: &'static str,
+        #[error(cause)]
+        source: ServiceSource
+    },
+
+    /// [WinKernelDriver::open](crate::WinKernelDriver::open) was called on an open driver
+    #[error(display = "Driver already opened")]
+    AlreadyOpen,
+
+    /// An operation needing an open handle was called before [WinKernelDriver::open](crate::WinKernelDriver::open)
+    #[error(display = "Driver not opened")]
+    NotOpen,
+
+    /// `CreateFile` failed on the device path
+    #[error(display = "Unable to open device {}. Last error code: {:#x}", path, code)]
+    OpenFailed { path: String, code: u32 },
+
+    /// `DeviceIoControl` failed. `code` is the Win32 error code.
+    #[error(display = "DeviceIoControl - Unable to write command {}. Last error code: {:#x}", ioctl, code)]
+    IoctlFailed { ioctl: IoControlCode, code: u32 },
+
+    /// The buffers passed to an IO command can not be used with it
+    #[error(display = "Invalid buffer for command {}: {}", ioctl, reason)]
+    InvalidBuffer { ioctl: IoControlCode, reason: &'static str },
+
+    /// The driver wrote fewer bytes than the output structure needs
+    #[error(display = "Command {} returned {} bytes, expected {}", ioctl, actual, expected)]
+    ShortOutput { ioctl: IoControlCode, expected: usize, actual: usize },
+
+    /// An async IO command was cancelled before the driver completed it
+    #[error(display = "Command {} was cancelled", ioctl)]
+    Cancelled { ioctl: IoControlCode },
+
+    /// An async IO command did not complete within its timeout and was cancelled
+    #[error(display = "Command {} timed out after {:?}", ioctl, timeout)]
+    Timeout { ioctl: IoControlCode, timeout: Duration },
+
+    /// The device type or function code does not fit in an IO control code
+    #[error(display = "Invalid IO control code: device type {:#x} must fit in 16 bits and function {:#x} in 12 bits", device_type, function)]
+    InvalidIoControlCode { device_type: u32, function: u32 },
+
+    /// A string could not be parsed as an IO control code, method or access
+    #[error(display = "Unable to parse IO control code {:?}: {}", input, reason)]
+    ParseIoControlCode { input: String, reason: &'static str },
+
+    /// A hardware lock was still held by someone else when the timeout expired
+    #[error(display = "Timed out waiting for lock {}", name)]
+    LockTimeout { name: String },
+
+    /// A hardware lock could not be created or waited on
+    #[error(display = "Unable to take lock {}", name)]
+    Lock {
+        name: String,
+        #[error(cause)]
+        source: io::Error
+    },
+
+    /// There is no logical processor with this index
+    #[error(display = "Logical processor {} does not exist, there are {}", cpu, count)]
+    InvalidProcessor { cpu: usize, count: usize },
+
+    /// The calling thread could not be moved to a logical processor, or back
+    #[error(display = "Unable to set the thread affinity to logical processor {}", cpu)]
+    Affinity {
+        cpu: usize,
+        #[error(cause)]
+        source: io::Error
+    },
+
+    /// Kernel drivers can not be loaded on this platform
+    #[error(display = "Unsupported platform")]
+    UnsupportedPlatform
+}
+
+impl Error {
+    /// Map a `windows_service` error for `operation` onto the matching variant
+    #[cfg(windows)]
+    pub(crate) fn from_service(operation: &'static str, name: &str, err: windows_service::Error) -> Self {
+        let code = match &err {
+            windows_service::Error::Winapi(io_err) => io_err.raw_os_error().map(|c| c as u32),
+            _ => None
+        };
+        let name = name.to_owned();
+
+        match (code, err) {
+            (Some(ERROR_ACCESS_DENIED), windows_service::Error::Winapi(source)) => Error::AccessDenied { operation, source },
+            (Some(ERROR_SERVICE_DOES_NOT_EXIST), source) => Error::NotInstalled { name, source },
+            (Some(ERROR_SERVICE_EXISTS), source) => Error::ServiceExists { name, source },
+            (Some(ERROR_SERVICE_MARKED_FOR_DELETE), source) => Error::ServiceMarkedForDeletion { name, source },
+            (_, source) => Error::Service { operation, source }
+        }
+    }
+
+    /// [Error::NotInstalled] for a service found missing without asking the SCM
+    pub fn not_installed(name: &str) -> Self {
+        Error::NotInstalled { name: name.to_owned(), source: service_source(ERROR_SERVICE_DOES_NOT_EXIST) }
+    }
+
+    /// [Error::ServiceExists] for a name found taken without asking the SCM
+    pub fn service_exists(name: &str) -> Self {
+        Error::ServiceExists { name: name.to_owned(), source: service_source(ERROR_SERVICE_EXISTS) }
+    }
+
+    /// [Error::ServiceMarkedForDeletion] for a service found deleted without asking the SCM
+    pub fn marked_for_deletion(name: &str) -> Self {
+        Error::ServiceMarkedForDeletion { name: name.to_owned(), source: service_source(ERROR_SERVICE_MARKED_FOR_DELETE) }
+    }
+
+    /// The Win32 error code behind this error, if there is one
+    pub fn win32_code(&self) -> Option<u32> {
+        match self {
+            Error::AccessDenied { .. } => Some(ERROR_ACCESS_DENIED),
+            Error::NotInstalled { .. } => Some(ERROR_SERVICE_DOES_NOT_EXIST),
+            Error::ServiceExists { .. } => Some(ERROR_SERVICE_EXISTS),
+            Error::ServiceMarkedForDeletion { .. } => Some(ERROR_SERVICE_MARKED_FOR_DELETE),
+            Error::OpenFailed { code, .. } | Error::IoctlFailed { code, .. } => Some(*code),
+            Error::Cancelled { .. } | Error::Timeout { .. } => Some(ERROR_OPERATION_ABORTED),
+            Error::Service { source, .. } => source_code(source),
+            _ => None
+        }
+    }
+}
+
+/// The error a service control manager returns with Win32 error `code`
+#[cfg(windows)]
+fn service_source(code: u32) -> ServiceSource {
+    windows_service::Error::Winapi(io::Error::from_raw_os_error(code as i32))
+}
+
+#[cfg(not(windows))]
+fn service_source(code: u32) -> ServiceSource {
+    io::Error::from_raw_os_error(code as i32)
+}
+
+#[cfg(windows)]
+fn source_code(source: &ServiceSource) -> Option<u32> {
+    match source {
+        windows_service::Error::Winapi(err) => err.raw_os_error().map(|c| c as u32),
+        _ => None
+    }
+}
+
+#[cfg(not(windows))]
+fn source_code(source: &ServiceSource) -> Option<u32> {
+    source.raw_os_error().map(|c| c as u32)
+}
+
+/// Result type used throughout this crate
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+
+    #[test]
+    fn service_errors_keep_their_source() {
+        let err = Error::marked_for_deletion("WinRing0_1_2_0");
+
+        let source = err.source().and_then(|source| source.downcast_ref::<ServiceSource>()).unwrap();
+        assert_eq!(source_code(source), Some(ERROR_SERVICE_MARKED_FOR_DELETE));
+        assert_eq!(err.win32_code(), Some(ERROR_SERVICE_MARKED_FOR_DELETE));
+        assert_eq!(err.to_string(), "Driver service WinRing0_1_2_0 is marked for deletion");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn from_service_keeps_windows_service_error() {
+        let err = Error::from_service("create the service", "WinRing0_1_2_0",
+            windows_service::Error::Winapi(io::Error::from_raw_os_error(ERROR_SERVICE_EXISTS as i32)));
+        assert!(matches!(err, Error::ServiceExists { .. }));
+        assert!(err.source().unwrap().downcast_ref::<windows_service::Error>().is_some());
+
+        let err = Error::from_service("start the service", "WinRing0_1_2_0",
+            windows_service::Error::InvalidServiceState(windows_service::service::ServiceState::from_raw(99).unwrap_err()));
+        match &err {
+            Error::Service { source: windows_service::Error::InvalidServiceState(_), .. } => {},
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+}
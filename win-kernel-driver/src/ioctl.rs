@@ -0,0 +1,305 @@
+//! IO control codes
+//! 
+//! An IO control code packs a device type, a function code, a transfer [Method] and the
+//! required [Access] into 32 bits, as done by the `CTL_CODE` macro of the Windows DDK:
+//! 
+//! ```text
+//!  31            16 15  14 13            2 1    0
+//! +----------------+------+---------------+------+
+//! |  device type   |access|   function    |method|
+//! +----------------+------+---------------+------+
+//! ```
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+/// IO Method
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum Method {
+    BUFFERED = 0,
+    INDIRECT = 1,
+    OUTDIRECT = 2,
+    NEITHER = 3
+}
+
+/// IO Access
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum Access {
+    ANY = 0,
+    READ = 1,
+    WRITE = 2,
+    READ_WRITE = 3
+}
+
+impl Method {
+    /// Name of the method as used in the Windows headers
+    pub const fn name(self) -> &'static str {
+        match self {
+            Method::BUFFERED => "METHOD_BUFFERED",
+            Method::INDIRECT => "METHOD_IN_DIRECT",
+            Method::OUTDIRECT => "METHOD_OUT_DIRECT",
+            Method::NEITHER => "METHOD_NEITHER"
+        }
+    }
+}
+
+impl Access {
+    /// Name of the access as used in the Windows headers
+    pub const fn name(self) -> &'static str {
+        match self {
+            Access::ANY => "FILE_ANY_ACCESS",
+            Access::READ => "FILE_READ_ACCESS",
+            Access::WRITE => "FILE_WRITE_ACCESS",
+            Access::READ_WRITE => "FILE_READ_ACCESS | FILE_WRITE_ACCESS"
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = Error;
+
+    /// Parse a method from its header name or short name, ignoring case:
+    /// `METHOD_BUFFERED` or `buffered`, `METHOD_IN_DIRECT` or `in_direct`, ...
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.trim().to_ascii_uppercase();
+        let name = name.strip_prefix("METHOD_").unwrap_or(&name);
+
+        match name {
+            "BUFFERED" => Ok(Method::BUFFERED),
+            "IN_DIRECT" | "INDIRECT" => Ok(Method::INDIRECT),
+            "OUT_DIRECT" | "OUTDIRECT" => Ok(Method::OUTDIRECT),
+            "NEITHER" => Ok(Method::NEITHER),
+            _ => Err(Error::ParseIoControlCode { input: s.to_owned(), reason: "unknown method" })
+        }
+    }
+}
+
+impl FromStr for Access {
+    type Err = Error;
+
+    /// Parse an access from its header name or short name, ignoring case:
+    /// `FILE_ANY_ACCESS` or `any`, `FILE_READ_ACCESS` or `read`, ... Read and write
+    /// access is `read_write` or both header names joined with `|`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut access = 0;
+
+        for part in s.split('|') {
+            let name = part.trim().to_ascii_uppercase();
+            let name = name.strip_prefix("FILE_").unwrap_or(&name);
+            let name = name.strip_suffix("_ACCESS").unwrap_or(name);
+
+            access |= match name {
+                "ANY" => 0,
+                "READ" => 1,
+                "WRITE" => 2,
+                "READ_WRITE" => 3,
+                _ => { return Err(Error::ParseIoControlCode { input: s.to_owned(), reason: "unknown access" }); }
+            };
+        }
+
+        Ok(match access {
+            0 => Access::ANY,
+            1 => Access::READ,
+            2 => Access::WRITE,
+            _ => Access::READ_WRITE
+        })
+    }
+}
+
+/// Largest device type that fits in an IO control code
+pub const MAX_DEVICE_TYPE: u32 = 0xFFFF;
+/// Largest function code that fits in an IO control code
+pub const MAX_FUNCTION: u32 = 0xFFF;
+
+/// An IO control code.
+/// 
+/// Every `u32` is a valid code, so conversions from and to `u32` are free. Building
+/// one with [IoControlCode::new] checks the device type and function fit in their bits,
+/// which fails the build when used in a constant.
+/// 
+/// # Example
+/// ```
+/// use win_kernel_driver::{IoControlCode, Method, Access};
+/// 
+/// const OLS_READ_MSR: IoControlCode = IoControlCode::new(40000, 0x821, Method::BUFFERED, Access::ANY);
+/// 
+/// assert_eq!(OLS_READ_MSR.raw(), 0x9C402084);
+/// assert_eq!(OLS_READ_MSR.function(), 0x821);
+/// assert_eq!(OLS_READ_MSR.access(), Access::ANY);
+/// assert_eq!(OLS_READ_MSR.to_string(), "CTL_CODE(0x9C40, 0x821, METHOD_BUFFERED, FILE_ANY_ACCESS)");
+/// ```
+/// 
+/// Out of range values don't compile:
+/// ```compile_fail
+/// use win_kernel_driver::{IoControlCode, Method, Access};
+/// 
+/// const BAD: IoControlCode = IoControlCode::new(0x10000, 0x800, Method::BUFFERED, Access::ANY);
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IoControlCode(u32);
+
+impl IoControlCode {
+    /// Build an IO control code.
+    /// 
+    /// # Panics
+    /// 
+    /// If `device_type` is larger than [MAX_DEVICE_TYPE] or `function` larger than
+    /// [MAX_FUNCTION]. In a constant this is a compile error.
+    pub const fn new(device_type: u32, function: u32, method: Method, access: Access) -> Self {
+        assert!(device_type <= MAX_DEVICE_TYPE, "device type must fit in 16 bits");
+        assert!(function <= MAX_FUNCTION, "function code must fit in 12 bits");
+
+        IoControlCode((device_type << 16) | ((access as u32) << 14) | (function << 2) | (method as u32))
+    }
+
+    /// Build an IO control code, failing with [Error::InvalidIoControlCode] instead of
+    /// panicking on out of range values
+    pub fn try_new(device_type: u32, function: u32, method: Method, access: Access) -> Result<Self> {
+        if device_type > MAX_DEVICE_TYPE || function > MAX_FUNCTION {
+            return Err(Error::InvalidIoControlCode { device_type, function });
+        }
+
+        Ok(Self::new(device_type, function, method, access))
+    }
+
+    /// Wrap a raw code
+    pub const fn from_raw(code: u32) -> Self {
+        IoControlCode(code)
+    }
+
+    /// The raw code, as passed to `DeviceIoControl`
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+
+    /// The device type, bits 16 to 31
+    pub const fn device_type(self) -> u32 {
+        self.0 >> 16
+    }
+
+    /// The function code, bits 2 to 13
+    pub const fn function(self) -> u32 {
+        (self.0 >> 2) & MAX_FUNCTION
+    }
+
+    /// The transfer method, bits 0 and 1
+    pub const fn method(self) -> Method {
+        match self.0 & 0x3 {
+            0 => Method::BUFFERED,
+            1 => Method::INDIRECT,
+            2 => Method::OUTDIRECT,
+            _ => Method::NEITHER
+        }
+    }
+
+    /// The required access, bits 14 and 15
+    pub const fn access(self) -> Access {
+        match (self.0 >> 14) & 0x3 {
+            0 => Access::ANY,
+            1 => Access::READ,
+            2 => Access::WRITE,
+            _ => Access::READ_WRITE
+        }
+    }
+}
+
+impl fmt::Display for IoControlCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CTL_CODE({:#X}, {:#X}, {}, {})", self.device_type(), self.function(), self.method().name(), self.access().name())
+    }
+}
+
+impl FromStr for IoControlCode {
+    type Err = Error;
+
+    /// Parse either a raw code, or a device type, function, method and access
+    /// separated by commas. Numbers are decimal, or hexadecimal with a `0x` prefix.
+    /// The [Display](fmt::Display) form is accepted too.
+    ///
+    /// # Example
+    /// ```
+    /// use win_kernel_driver::{IoControlCode, Method, Access};
+    ///
+    /// let read_msr = IoControlCode::new(40000, 0x821, Method::BUFFERED, Access::ANY);
+    ///
+    /// assert_eq!("0x9C402084".parse::<IoControlCode>().unwrap(), read_msr);
+    /// assert_eq!("40000,0x821,buffered,any".parse::<IoControlCode>().unwrap(), read_msr);
+    /// assert_eq!(read_msr.to_string().parse::<IoControlCode>().unwrap(), read_msr);
+    /// assert!("40000,0x1000,buffered,any".parse::<IoControlCode>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self> {
+        let trimmed = s.trim();
+        let fields = trimmed.strip_prefix("CTL_CODE(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .unwrap_or(trimmed);
+
+        let parts: Vec<&str> = fields.split(',').map(str::trim).collect();
+        let number = |part: &str| parse_number(part)
+            .ok_or_else(|| Error::ParseIoControlCode { input: s.to_owned(), reason: "not a number" });
+
+        match parts.as_slice() {
+            [raw] => Ok(IoControlCode::from_raw(number(raw)?)),
+            [device_type, function, method, access] => {
+                IoControlCode::try_new(number(device_type)?, number(function)?, method.parse()?, access.parse()?)
+            },
+            _ => Err(Error::ParseIoControlCode { input: s.to_owned(), reason: "expected a code or device_type,function,method,access" })
+        }
+    }
+}
+
+/// Parse a decimal number, or a hexadecimal one with a `0x` prefix
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok()
+    }
+}
+
+impl fmt::Debug for IoControlCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IoControlCode({:#010x} = {})", self.0, self)
+    }
+}
+
+impl From<u32> for IoControlCode {
+    fn from(code: u32) -> Self {
+        IoControlCode(code)
+    }
+}
+
+impl From<IoControlCode> for u32 {
+    fn from(code: IoControlCode) -> Self {
+        code.0
+    }
+}
+
+/// Creates an IOCTL code
+/// 
+/// # Arguments
+/// 
+/// * `device_type` - The device type is a 16bit integer. It must match the kernel driver
+///   you're loading.
+/// * `function`    - The function code you want to send to the driver. Consult your driver's
+///   documentation for the available codes.
+/// * `method`      - The IO method to use
+/// * `access`      - The access level (read/write/any) to use
+/// 
+/// See [IoControlCode::new] for the checks done on the arguments.
+/// 
+/// # Example
+/// ```
+/// use win_kernel_driver::{io_control_code, Method, Access};
+/// 
+/// let device = 0x00000022; // FILE_DEVICE_UNKNOWN
+/// let function = 0x800; // Some function code defined by the driver
+/// 
+/// // Generate an IO control code for a buffered read to the driver.
+/// let ioctl = io_control_code(device, function, Method::BUFFERED, Access::READ);
+/// ```
+pub const fn io_control_code(device_type: u32, function: u32, method: Method, access: Access) -> u32 {
+    IoControlCode::new(device_type, function, method, access).raw()
+}
@@ -6,13 +6,103 @@
 //! This crate is based off of the [KernelDriver class](https://github.com/openhardwaremonitor/openhardwaremonitor/blob/master/Hardware/KernelDriver.cs)
 //! from OpenHardwareMonitor.
 //! 
-//! For example usage see [WinKernelDriver], [DriverBuilder], and [io_control_code]
+//! For example usage see [WinKernelDriver], [DriverBuilder], and [IoControlCode].
+//! [AsyncDriver] does the same IO through futures.
+//! The `wkd` binary does the same from the command line: installing a `.sys` as a
+//! service, checking its status and sending raw IO control codes to its device.
 //!
+//! All fallible functions return an [Error] which can be matched on to recover
+//! from specific failures, like a service that is already installed.
+//!
+//! Diagnostics are emitted through [tracing](https://docs.rs/tracing): service install
+//! steps at `INFO`, opening and closing devices and failed commands at `DEBUG`, and
+//! every IO command with its decoded control code, buffer sizes and duration at `TRACE`.
+//!
+//! Drivers can only be loaded on Windows. Elsewhere the crate still builds: the Win32
+//! backends fail with [Error::UnsupportedPlatform], while [MockTransport] and
+//! [FakeServiceManager] work everywhere.
+//!
+
+#[cfg(windows)]
 mod utils;
 mod driver;
+mod error;
+mod ioctl;
+mod pod;
+mod transport;
+mod scm;
+mod staging;
+mod pe;
+mod lock;
+mod timer;
+mod async_driver;
+mod batch;
+mod session;
+mod protocol;
+mod embed;
+mod affinity;
 
 pub use driver::WinKernelDriver;
 pub use driver::DriverBuilder;
-pub use driver::Access;
-pub use driver::Method;
-pub use driver::io_control_code;
+pub use driver::DriverState;
+pub use driver::ServiceGuard;
+pub use ioctl::Access;
+pub use ioctl::Method;
+pub use ioctl::IoControlCode;
+pub use ioctl::io_control_code;
+pub use ioctl::MAX_DEVICE_TYPE;
+pub use ioctl::MAX_FUNCTION;
+pub use error::Error;
+pub use error::Result;
+pub use pod::Pod;
+pub use transport::Transport;
+pub use transport::RawDevice;
+pub use transport::OwnedDevice;
+pub use transport::OpenOptions;
+pub use transport::Win32Transport;
+pub use transport::MockTransport;
+pub use transport::Expectation;
+pub use transport::MockCall;
+pub use transport::PendingIo;
+pub use scm::ServiceControlManager;
+pub use scm::Win32ServiceManager;
+pub use scm::FakeServiceManager;
+pub use scm::ServiceConfig;
+pub use scm::ServiceDetails;
+pub use scm::ServiceState;
+pub use scm::InstallOutcome;
+pub use scm::StartType;
+pub use scm::ErrorControl;
+pub use scm::DriverService;
+pub use scm::find_driver_services;
+pub use scm::remove_driver_service;
+pub use scm::resolve_driver_path;
+pub use pe::PeImage;
+pub use pe::Machine;
+pub use pe::Subsystem;
+pub use pe::VersionInfo;
+pub use lock::Bus;
+pub use lock::HardwareLock;
+pub use lock::HardwareLockGuard;
+pub use lock::LockBackend;
+pub use lock::LockToken;
+pub use lock::NamedMutexBackend;
+pub use lock::ProcessLockBackend;
+pub use lock::FileLockBackend;
+pub use async_driver::AsyncDriver;
+pub use async_driver::IoFuture;
+pub use async_driver::Canceller;
+pub use batch::IoBatch;
+pub use batch::IoResult;
+pub use session::DriverSession;
+pub use session::Concurrency;
+pub use protocol::DeviceIo;
+pub use embed::EmbeddedDriver;
+pub use embed::compress_driver;
+pub use embed::embed_driver;
+pub use affinity::ThreadAffinity;
+pub use affinity::AffinityGuard;
+pub use affinity::AffinityBackend;
+pub use affinity::AffinityToken;
+pub use affinity::ProcessorGroupBackend;
+pub use affinity::SchedAffinityBackend;
@@ -0,0 +1,173 @@
+//! Hardware bus locks
+//! 
+//! Monitoring tools talk to the same chips: a Super I/O chip is driven through an
+//! index/data port pair on the ISA bus, SMBus controllers through a handful of
+//! registers. Two programs interleaving those accesses corrupt each other's
+//! transactions. OpenHardwareMonitor, LibreHardwareMonitor, HWiNFO and others agree on a
+//! set of named global mutexes to serialize them, see [Bus].
+//! 
+//! A [HardwareLock] takes one of those locks through a [LockBackend]:
+//! [NamedMutexBackend] uses the real Win32 mutexes, [ProcessLockBackend] and
+//! [FileLockBackend] give the same discipline without them.
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, trace};
+
+use crate::error::Result;
+
+#[cfg(windows)]
+mod win32;
+#[cfg(not(windows))]
+mod unsupported;
+mod portable;
+
+#[cfg(windows)]
+pub use win32::NamedMutexBackend;
+#[cfg(not(windows))]
+pub use unsupported::NamedMutexBackend;
+pub use portable::ProcessLockBackend;
+pub use portable::FileLockBackend;
+
+/// Shared hardware with a well-known lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bus {
+    /// ISA bus / LPC IO ports, e.g. Super I/O index and data ports
+    Isa,
+    /// PCI configuration space
+    Pci,
+    /// SMBus controller registers
+    SmBus,
+    /// Embedded controller ports
+    Ec
+}
+
+impl Bus {
+    /// Name of the global mutex other tools use for this bus
+    pub const fn lock_name(self) -> &'static str {
+        match self {
+            Bus::Isa => r"Global\Access_ISABUS.HTP.Method",
+            Bus::Pci => r"Global\Access_PCI",
+            Bus::SmBus => r"Global\Access_SMBUS.HTP.KernelDriver",
+            Bus::Ec => r"Global\Access_EC"
+        }
+    }
+}
+
+impl fmt::Display for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.lock_name())
+    }
+}
+
+/// A way to take named locks
+pub trait LockBackend: Send + Sync {
+    /// Take the lock called `name`, waiting at most `timeout`. Fails with
+    /// [Error::LockTimeout](crate::Error::LockTimeout) if it is still held by then.
+    fn acquire(&self, name: &str, timeout: Duration) -> Result<Box<dyn LockToken>>;
+}
+
+/// Proof that a lock is held. The lock is released when the token is dropped.
+pub trait LockToken {}
+
+/// A named lock shared with other processes.
+/// 
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use win_kernel_driver::{Bus, Error, HardwareLock, ProcessLockBackend};
+/// 
+/// let smbus = HardwareLock::new(Bus::SmBus).set_backend(ProcessLockBackend);
+/// 
+/// let guard = smbus.lock(Duration::from_millis(100)).unwrap();
+/// 
+/// // Meanwhile, on another thread
+/// std::thread::spawn(|| {
+///     let smbus = HardwareLock::new(Bus::SmBus).set_backend(ProcessLockBackend);
+///     match smbus.lock(Duration::from_millis(10)) {
+///         Err(Error::LockTimeout { .. }) => { },
+///         _ => panic!("the bus should still be locked")
+///     }
+/// }).join().unwrap();
+/// 
+/// drop(guard);
+/// ```
+#[derive(Clone)]
+pub struct HardwareLock {
+    name: String,
+    backend: Arc<dyn LockBackend>
+}
+
+impl HardwareLock {
+    /// The lock for `bus`, using [NamedMutexBackend]
+    pub fn new(bus: Bus) -> Self {
+        Self::named(bus.lock_name())
+    }
+
+    /// A lock with a custom name, using [NamedMutexBackend]
+    pub fn named(name: &str) -> Self {
+        HardwareLock {
+            name: name.to_owned(),
+            backend: Arc::new(NamedMutexBackend)
+        }
+    }
+
+    /// Take the lock through `backend` instead
+    pub fn set_backend<B: LockBackend + 'static>(mut self, backend: B) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Name of the lock
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Take the lock, waiting at most `timeout`. The lock is held until the
+    /// guard is dropped.
+    pub fn lock(&self, timeout: Duration) -> Result<HardwareLockGuard<'_>> {
+        let token = match self.backend.acquire(&self.name, timeout) {
+            Ok(token) => token,
+            Err(err) => {
+                debug!(lock = %self.name, ?timeout, error = %err, "lock not acquired");
+                return Err(err);
+            }
+        };
+        trace!(lock = %self.name, "lock acquired");
+
+        Ok(HardwareLockGuard {
+            lock: self,
+            _token: token,
+            _not_send: PhantomData
+        })
+    }
+}
+
+impl fmt::Debug for HardwareLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HardwareLock").field("name", &self.name).finish()
+    }
+}
+
+/// A held [HardwareLock]. Win32 mutexes belong to the thread that took them, so
+/// the guard can't be sent to another thread.
+pub struct HardwareLockGuard<'a> {
+    lock: &'a HardwareLock,
+    _token: Box<dyn LockToken>,
+    _not_send: PhantomData<*const ()>
+}
+
+impl<'a> HardwareLockGuard<'a> {
+    /// Name of the held lock
+    pub fn name(&self) -> &str {
+        self.lock.name()
+    }
+}
+
+impl<'a> fmt::Debug for HardwareLockGuard<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HardwareLockGuard").field("name", &self.lock.name).finish()
+    }
+}
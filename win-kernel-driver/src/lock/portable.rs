@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use super::{LockBackend, LockToken};
+
+/// How often [FileLockBackend] retries a held lock
+const FILE_LOCK_POLL: Duration = Duration::from_millis(5);
+
+/// Locks shared by the threads of this process only.
+/// 
+/// Like Win32 mutexes they are owned by a thread, which can take a lock it
+/// already holds again.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcessLockBackend;
+
+#[derive(Default)]
+struct ProcessLocks {
+    held: Mutex<HashMap<String, (ThreadId, usize)>>,
+    released: Condvar
+}
+
+fn process_locks() -> &'static ProcessLocks {
+    static LOCKS: OnceLock<ProcessLocks> = OnceLock::new();
+    LOCKS.get_or_init(ProcessLocks::default)
+}
+
+impl ProcessLocks {
+    fn held(&self) -> MutexGuard<'_, HashMap<String, (ThreadId, usize)>> {
+        self.held.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+struct ProcessLock {
+    name: String
+}
+
+impl LockToken for ProcessLock {}
+
+impl Drop for ProcessLock {
+    fn drop(&mut self) {
+        let locks = process_locks();
+        let mut held = locks.held();
+
+        if let Some((_, depth)) = held.get_mut(&self.name) {
+            *depth -= 1;
+            if *depth == 0 {
+                held.remove(&self.name);
+                locks.released.notify_all();
+            }
+        }
+    }
+}
+
+impl LockBackend for ProcessLockBackend {
+    fn acquire(&self, name: &str, timeout: Duration) -> Result<Box<dyn LockToken>> {
+        let locks = process_locks();
+        let me = thread::current().id();
+        let deadline = Instant::now() + timeout;
+        let mut held = locks.held();
+
+        loop {
+            match held.get_mut(name) {
+                None => {
+                    held.insert(name.to_owned(), (me, 1));
+                    break;
+                },
+                Some((owner, depth)) if *owner == me => {
+                    *depth += 1;
+                    break;
+                },
+                Some(_) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::LockTimeout { name: name.to_owned() });
+                    }
+                    held = locks.released.wait_timeout(held, deadline - now)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+                }
+            }
+        }
+
+        Ok(Box::new(ProcessLock { name: name.to_owned() }))
+    }
+}
+
+/// Locks backed by advisory locks on files in a directory, shared by every
+/// process using the same directory.
+/// 
+/// Unlike the other backends a lock can't be taken twice, not even by the
+/// thread holding it.
+#[derive(Debug, Clone)]
+pub struct FileLockBackend {
+    dir: PathBuf
+}
+
+impl FileLockBackend {
+    /// Keep the lock files in `dir`
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileLockBackend { dir: dir.into() }
+    }
+
+    /// The lock file used for `name`
+    pub fn lock_path(&self, name: &str) -> PathBuf {
+        let file_name: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' { c } else { '_' })
+            .collect();
+
+        self.dir.join(format!("{}.lock", file_name))
+    }
+}
+
+struct FileLock {
+    file: File
+}
+
+impl LockToken for FileLock {}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+impl LockBackend for FileLockBackend {
+    fn acquire(&self, name: &str, timeout: Duration) -> Result<Box<dyn LockToken>> {
+        let lock_error = |source| Error::Lock { name: name.to_owned(), source };
+
+        fs::create_dir_all(&self.dir).map_err(lock_error)?;
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(self.lock_path(name))
+            .map_err(lock_error)?;
+
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            match file.try_lock() {
+                Ok(()) => { return Ok(Box::new(FileLock { file })); }
+                Err(TryLockError::WouldBlock) => { },
+                Err(TryLockError::Error(err)) => { return Err(lock_error(err)); }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::LockTimeout { name: name.to_owned() });
+            }
+            thread::sleep(FILE_LOCK_POLL.min(deadline - now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::lock::HardwareLock;
+
+    const SHORT: Duration = Duration::from_millis(20);
+
+    fn lock_dir(test: &str) -> PathBuf {
+        env::temp_dir().join(format!("wkd-lock-{}-{}", test, process::id()))
+    }
+
+    fn try_on_other_thread<B: LockBackend + Clone + 'static>(backend: &B, name: &str) -> Result<()> {
+        let backend = backend.clone();
+        let name = name.to_owned();
+        thread::spawn(move || backend.acquire(&name, SHORT).map(drop)).join().unwrap()
+    }
+
+    #[test]
+    fn process_lock_times_out_on_other_thread() {
+        let name = "process_lock_times_out_on_other_thread";
+        let _token = ProcessLockBackend.acquire(name, SHORT).unwrap();
+
+        let start = Instant::now();
+        assert!(matches!(try_on_other_thread(&ProcessLockBackend, name), Err(Error::LockTimeout { .. })));
+        assert!(start.elapsed() >= SHORT);
+    }
+
+    #[test]
+    fn process_lock_is_reentrant() {
+        let name = "process_lock_is_reentrant";
+        let outer = ProcessLockBackend.acquire(name, SHORT).unwrap();
+        let inner = ProcessLockBackend.acquire(name, SHORT).unwrap();
+
+        drop(inner);
+        assert!(try_on_other_thread(&ProcessLockBackend, name).is_err());
+
+        drop(outer);
+        assert!(try_on_other_thread(&ProcessLockBackend, name).is_ok());
+    }
+
+    #[test]
+    fn process_lock_wakes_up_waiter_on_release() {
+        let name = "process_lock_wakes_up_waiter_on_release";
+        let token = ProcessLockBackend.acquire(name, SHORT).unwrap();
+
+        let (acquiring, waiting) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            acquiring.send(()).unwrap();
+            ProcessLockBackend.acquire(name, Duration::from_secs(10)).map(drop)
+        });
+
+        waiting.recv().unwrap();
+        thread::sleep(SHORT);
+        drop(token);
+        assert!(waiter.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn file_lock_excludes_second_handle() {
+        let backend = FileLockBackend::new(lock_dir("excludes"));
+        let name = r"Global\Access_ISABUS.HTP.Method";
+
+        let token = backend.acquire(name, SHORT).unwrap();
+        assert!(backend.lock_path(name).exists());
+
+        // Even the same thread opens a second handle, which the lock excludes
+        assert!(matches!(backend.acquire(name, SHORT), Err(Error::LockTimeout { .. })));
+        assert!(matches!(try_on_other_thread(&backend, name), Err(Error::LockTimeout { .. })));
+
+        drop(token);
+        assert!(backend.acquire(name, SHORT).is_ok());
+
+        let _ = fs::remove_dir_all(lock_dir("excludes"));
+    }
+
+    #[test]
+    fn lock_is_released_when_guard_drops() {
+        let process = HardwareLock::named("lock_is_released_when_guard_drops").set_backend(ProcessLockBackend);
+        let backend = FileLockBackend::new(lock_dir("guard"));
+        let file = HardwareLock::named("lock_is_released_when_guard_drops").set_backend(backend.clone());
+
+        {
+            let _process_guard = process.lock(SHORT).unwrap();
+            let _file_guard = file.lock(SHORT).unwrap();
+            assert!(try_on_other_thread(&ProcessLockBackend, process.name()).is_err());
+            assert!(try_on_other_thread(&backend, file.name()).is_err());
+        }
+
+        assert!(try_on_other_thread(&ProcessLockBackend, process.name()).is_ok());
+        assert!(try_on_other_thread(&backend, file.name()).is_ok());
+
+        let _ = fs::remove_dir_all(lock_dir("guard"));
+    }
+}
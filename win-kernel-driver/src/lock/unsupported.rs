@@ -0,0 +1,16 @@
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use super::{LockBackend, LockToken};
+
+/// Stands in for the Win32 named mutexes on other platforms. Acquiring fails with
+/// [Error::UnsupportedPlatform]; use a [FileLockBackend](crate::FileLockBackend) or
+/// [ProcessLockBackend](crate::ProcessLockBackend) instead.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NamedMutexBackend;
+
+impl LockBackend for NamedMutexBackend {
+    fn acquire(&self, _name: &str, _timeout: Duration) -> Result<Box<dyn LockToken>> {
+        Err(Error::UnsupportedPlatform)
+    }
+}
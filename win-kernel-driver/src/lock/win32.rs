@@ -0,0 +1,72 @@
+use std::io;
+use std::ptr::null_mut;
+use std::time::Duration;
+
+use winapi::shared::winerror;
+use winapi::um::errhandlingapi;
+use winapi::um::handleapi;
+use winapi::um::synchapi;
+use winapi::um::winbase;
+use winapi::um::winnt;
+
+use crate::error::{Error, Result, ERROR_ACCESS_DENIED};
+use crate::utils::to_wide;
+use super::{LockBackend, LockToken};
+
+/// Locks backed by Win32 named mutexes, shared with every other process using
+/// the same names.
+/// 
+/// A mutex whose owner died without releasing it is taken over, as the other tools do.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NamedMutexBackend;
+
+struct NamedMutex {
+    handle: winnt::HANDLE
+}
+
+impl LockToken for NamedMutex {}
+
+impl Drop for NamedMutex {
+    fn drop(&mut self) {
+        unsafe {
+            synchapi::ReleaseMutex(self.handle);
+            handleapi::CloseHandle(self.handle);
+        }
+    }
+}
+
+impl LockBackend for NamedMutexBackend {
+    fn acquire(&self, name: &str, timeout: Duration) -> Result<Box<dyn LockToken>> {
+        let wide_name = to_wide(name);
+
+        let handle = unsafe {
+            let mut handle = synchapi::CreateMutexW(null_mut(), 0, wide_name.as_ptr());
+
+            // Created by a service with a stricter DACL, it may still be opened for waiting
+            if handle.is_null() && errhandlingapi::GetLastError() == ERROR_ACCESS_DENIED {
+                handle = synchapi::OpenMutexW(winnt::SYNCHRONIZE | winnt::MUTEX_MODIFY_STATE, 0, wide_name.as_ptr());
+            }
+            handle
+        };
+
+        if handle.is_null() {
+            return Err(Error::Lock { name: name.to_owned(), source: io::Error::last_os_error() });
+        }
+
+        let millis = timeout.as_millis().min(winbase::INFINITE as u128 - 1) as u32;
+
+        match unsafe { synchapi::WaitForSingleObject(handle, millis) } {
+            winbase::WAIT_OBJECT_0 | winbase::WAIT_ABANDONED => Ok(Box::new(NamedMutex { handle })),
+            result => {
+                let err = io::Error::last_os_error();
+                unsafe { handleapi::CloseHandle(handle) };
+
+                if result == winerror::WAIT_TIMEOUT {
+                    Err(Error::LockTimeout { name: name.to_owned() })
+                } else {
+                    Err(Error::Lock { name: name.to_owned(), source: err })
+                }
+            }
+        }
+    }
+}
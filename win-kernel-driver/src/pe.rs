@@ -0,0 +1,564 @@
+//! Kernel driver image inspection
+//!
+//! Drivers are PE images. Only the parts needed to decide whether an image can be
+//! loaded, and to tell images apart, are parsed: the COFF and optional headers, the
+//! version resource and the certificate table entry.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{Error, Result};
+
+/// `IMAGE_DIRECTORY_ENTRY_RESOURCE`
+const DIRECTORY_RESOURCE: usize = 2;
+/// `IMAGE_DIRECTORY_ENTRY_SECURITY`
+const DIRECTORY_SECURITY: usize = 4;
+/// `RT_VERSION`
+const RESOURCE_VERSION: u32 = 16;
+/// `VS_FIXEDFILEINFO::dwSignature`
+const FIXED_FILE_INFO_SIGNATURE: u32 = 0xFEEF_04BD;
+
+/// CPU architecture an image was built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Machine {
+    X86,
+    X64,
+    Arm,
+    Arm64,
+    Unknown(u16)
+}
+
+impl Machine {
+    /// Decode the `Machine` field of the COFF header
+    pub fn from_raw(raw: u16) -> Self {
+        match raw {
+            0x014c => Machine::X86,
+            0x8664 => Machine::X64,
+            0x01c4 => Machine::Arm,
+            0xaa64 => Machine::Arm64,
+            other => Machine::Unknown(other)
+        }
+    }
+
+    /// The architecture of the running Windows kernel, which is what a driver has
+    /// to match. This is not the architecture of the current process: a 32bit
+    /// process on 64bit Windows still needs a 64bit driver.
+    #[cfg(windows)]
+    pub fn host() -> Self {
+        use winapi::um::sysinfoapi;
+        use winapi::um::winnt;
+
+        let mut info: sysinfoapi::SYSTEM_INFO = unsafe { std::mem::zeroed() };
+        unsafe { sysinfoapi::GetNativeSystemInfo(&mut info) };
+
+        match unsafe { info.u.s().wProcessorArchitecture } {
+            winnt::PROCESSOR_ARCHITECTURE_INTEL => Machine::X86,
+            winnt::PROCESSOR_ARCHITECTURE_AMD64 => Machine::X64,
+            winnt::PROCESSOR_ARCHITECTURE_ARM => Machine::Arm,
+            winnt::PROCESSOR_ARCHITECTURE_ARM64 => Machine::Arm64,
+            other => Machine::Unknown(other)
+        }
+    }
+
+    /// The architecture this crate was compiled for, as there is no Windows kernel
+    /// to ask on other platforms
+    #[cfg(not(windows))]
+    pub fn host() -> Self {
+        if cfg!(target_arch = "x86") {
+            Machine::X86
+        } else if cfg!(target_arch = "x86_64") {
+            Machine::X64
+        } else if cfg!(target_arch = "arm") {
+            Machine::Arm
+        } else if cfg!(target_arch = "aarch64") {
+            Machine::Arm64
+        } else {
+            Machine::Unknown(0)
+        }
+    }
+}
+
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Machine::X86 => write!(f, "x86"),
+            Machine::X64 => write!(f, "x64"),
+            Machine::Arm => write!(f, "ARM"),
+            Machine::Arm64 => write!(f, "ARM64"),
+            Machine::Unknown(raw) => write!(f, "unknown machine {:#06x}", raw)
+        }
+    }
+}
+
+/// Subsystem an image runs in. Kernel drivers are [Subsystem::Native].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Subsystem {
+    Native,
+    WindowsGui,
+    WindowsCui,
+    EfiApplication,
+    EfiBootServiceDriver,
+    EfiRuntimeDriver,
+    Unknown(u16)
+}
+
+impl Subsystem {
+    /// Decode the `Subsystem` field of the optional header
+    pub fn from_raw(raw: u16) -> Self {
+        match raw {
+            1 => Subsystem::Native,
+            2 => Subsystem::WindowsGui,
+            3 => Subsystem::WindowsCui,
+            10 => Subsystem::EfiApplication,
+            11 => Subsystem::EfiBootServiceDriver,
+            12 => Subsystem::EfiRuntimeDriver,
+            other => Subsystem::Unknown(other)
+        }
+    }
+}
+
+/// Contents of the version resource
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// File version from the fixed part of the resource, e.g. `[1, 2, 0, 5]`
+    pub file_version_parts: Option<[u16; 4]>,
+    /// Every string of the first string table, e.g. `"ProductName"` => `"WinRing0"`
+    pub strings: BTreeMap<String, String>
+}
+
+impl VersionInfo {
+    /// The `FileVersion` string
+    pub fn file_version(&self) -> Option<&str> {
+        self.strings.get("FileVersion").map(String::as_str)
+    }
+
+    /// The `ProductName` string
+    pub fn product_name(&self) -> Option<&str> {
+        self.strings.get("ProductName").map(String::as_str)
+    }
+}
+
+/// Summary of a driver image
+///
+/// # Example
+/// ```
+/// use win_kernel_driver::{PeImage, Machine, Subsystem};
+///
+/// let image = PeImage::parse(include_bytes!("../../win_ring0/WinRing0x64.sys")).unwrap();
+///
+/// assert_eq!(image.machine, Machine::X64);
+/// assert_eq!(image.subsystem, Subsystem::Native);
+/// assert!(image.has_signature);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeImage {
+    pub machine: Machine,
+    pub subsystem: Subsystem,
+    /// Link time as seconds since the unix epoch. Reproducible builds put a hash here instead.
+    pub timestamp: u32,
+    /// The version resource, if the image has one
+    pub version: Option<VersionInfo>,
+    /// The image has an Authenticode certificate table. The signature itself is not checked.
+    pub has_signature: bool
+}
+
+impl PeImage {
+    /// Parse an image held in memory
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let image = Reader(bytes);
+
+        if image.u16(0)? != 0x5A4D {
+            return Err(Error::InvalidImage("missing MZ header"));
+        }
+
+        let pe = image.u32(0x3C)? as usize;
+        if image.u32(pe)? != 0x0000_4550 {
+            return Err(Error::InvalidImage("missing PE signature"));
+        }
+
+        let coff = add(pe, 4)?;
+        let machine = Machine::from_raw(image.u16(coff)?);
+        let section_count = image.u16(coff + 2)? as usize;
+        let timestamp = image.u32(coff + 4)?;
+        let optional_size = image.u16(coff + 16)? as usize;
+
+        let optional = coff + 20;
+        let directories = match image.u16(optional)? {
+            0x10b => optional + 96,
+            0x20b => optional + 112,
+            _ => { return Err(Error::InvalidImage("unknown optional header magic")); }
+        };
+        let subsystem = Subsystem::from_raw(image.u16(optional + 68)?);
+        let directory_count = image.u32(directories - 4)? as usize;
+
+        let directory = |index: usize| -> Result<(u32, u32)> {
+            if index >= directory_count {
+                return Ok((0, 0));
+            }
+            let entry = directories + index * 8;
+            Ok((image.u32(entry)?, image.u32(entry + 4)?))
+        };
+
+        let mut sections = Vec::with_capacity(section_count);
+        let section_table = add(optional, optional_size)?;
+        for index in 0..section_count {
+            let header = section_table + index * 40;
+            sections.push(Section {
+                virtual_size: image.u32(header + 8)?,
+                virtual_address: image.u32(header + 12)?,
+                raw_size: image.u32(header + 16)?,
+                raw_offset: image.u32(header + 20)?
+            });
+        }
+
+        // The certificate table entry holds a file offset rather than an RVA
+        let (security_offset, security_size) = directory(DIRECTORY_SECURITY)?;
+        let has_signature = security_offset != 0 && security_size != 0;
+
+        let (resource_rva, resource_size) = directory(DIRECTORY_RESOURCE)?;
+        let version = if resource_rva != 0 && resource_size != 0 {
+            let resources = Resources { image, sections: &sections, base: rva_to_offset(&sections, resource_rva)? };
+            match resources.find_version()? {
+                Some(data) => Some(parse_version(data)?),
+                None => None
+            }
+        } else {
+            None
+        };
+
+        Ok(PeImage { machine, subsystem, timestamp, version, has_signature })
+    }
+
+    /// Read and parse an image file
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|source| Error::ReadImage { path: path.to_path_buf(), source })?;
+
+        Self::parse(&bytes)
+    }
+
+    /// The link time
+    pub fn link_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.timestamp as u64)
+    }
+}
+
+#[derive(Debug)]
+struct Section {
+    virtual_size: u32,
+    virtual_address: u32,
+    raw_size: u32,
+    raw_offset: u32
+}
+
+fn rva_to_offset(sections: &[Section], rva: u32) -> Result<usize> {
+    for section in sections {
+        let size = section.virtual_size.max(section.raw_size);
+        match rva.checked_sub(section.virtual_address) {
+            Some(delta) if delta < size => {
+                return section.raw_offset.checked_add(delta)
+                    .map(|offset| offset as usize)
+                    .ok_or(Error::InvalidImage("section offset out of range"));
+            },
+            _ => {}
+        }
+    }
+
+    Err(Error::InvalidImage("address outside of every section"))
+}
+
+/// `offset + delta`, failing on images whose offsets don't fit
+fn add(offset: usize, delta: usize) -> Result<usize> {
+    offset.checked_add(delta).ok_or(Error::InvalidImage("offset out of range"))
+}
+
+/// Bounds checked little endian reads
+#[derive(Clone, Copy)]
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8]> {
+        offset.checked_add(len)
+            .and_then(|end| self.0.get(offset..end))
+            .ok_or(Error::InvalidImage("truncated image"))
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16> {
+        let b = self.bytes(offset, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32> {
+        let b = self.bytes(offset, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+struct Resources<'a> {
+    image: Reader<'a>,
+    sections: &'a [Section],
+    base: usize
+}
+
+impl<'a> Resources<'a> {
+    /// Walk type -> name -> language, taking the first name and language of `RT_VERSION`
+    fn find_version(&self) -> Result<Option<&'a [u8]>> {
+        let names = match self.entry(0, Some(RESOURCE_VERSION))? {
+            Some(entry) => entry,
+            None => { return Ok(None); }
+        };
+        let languages = match self.entry(subdirectory(names)?, None)? {
+            Some(entry) => entry,
+            None => { return Ok(None); }
+        };
+        let data = match self.entry(subdirectory(languages)?, None)? {
+            Some(entry) => entry,
+            None => { return Ok(None); }
+        };
+
+        if data & 0x8000_0000 != 0 {
+            return Err(Error::InvalidImage("resource tree is too deep"));
+        }
+
+        let data_entry = add(self.base, data as usize)?;
+        let rva = self.image.u32(data_entry)?;
+        let size = self.image.u32(add(data_entry, 4)?)? as usize;
+
+        Ok(Some(self.image.bytes(rva_to_offset(self.sections, rva)?, size)?))
+    }
+
+    /// The `OffsetToData` of the entry with `id` (or the first entry) in the directory at `offset`
+    fn entry(&self, offset: u32, id: Option<u32>) -> Result<Option<u32>> {
+        let directory = add(self.base, offset as usize)?;
+        let count = self.image.u16(add(directory, 12)?)? as usize + self.image.u16(add(directory, 14)?)? as usize;
+
+        for index in 0..count {
+            let entry = add(directory, 16 + index * 8)?;
+            let name = self.image.u32(entry)?;
+            if id.is_none_or(|id| id == name) {
+                return Ok(Some(self.image.u32(add(entry, 4)?)?));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn subdirectory(offset: u32) -> Result<u32> {
+    if offset & 0x8000_0000 == 0 {
+        return Err(Error::InvalidImage("resource tree is too shallow"));
+    }
+
+    Ok(offset & 0x7FFF_FFFF)
+}
+
+/// A `VS_VERSIONINFO`, `StringFileInfo`, `StringTable` or `String` block
+struct Block<'a> {
+    key: String,
+    value: &'a [u8],
+    /// `wType`: 1 for text values, whose length is counted in characters
+    text: bool,
+    children: &'a [u8]
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Parse the block at the start of `data`, returning it and the length it takes up
+fn parse_block(data: &[u8]) -> Result<(Block<'_>, usize)> {
+    let reader = Reader(data);
+    let length = reader.u16(0)? as usize;
+    let value_length = reader.u16(2)? as usize;
+    let text = reader.u16(4)? == 1;
+
+    if length < 6 || length > data.len() {
+        return Err(Error::InvalidImage("malformed version resource"));
+    }
+    let block = &data[..length];
+
+    let (key, key_end) = read_utf16(block, 6)?;
+    let value_start = align4(key_end).min(length);
+    let value_bytes = if text { value_length * 2 } else { value_length };
+    let value_end = (value_start + value_bytes).min(length);
+
+    let block = Block {
+        key,
+        value: &block[value_start..value_end],
+        text,
+        children: &block[align4(value_end).min(length)..]
+    };
+
+    Ok((block, align4(length)))
+}
+
+/// Read a nul terminated UTF-16 string at `offset`, returning it and the offset after the nul
+fn read_utf16(data: &[u8], offset: usize) -> Result<(String, usize)> {
+    let mut units = vec![];
+    let mut position = offset;
+
+    loop {
+        let unit = Reader(data).u16(position)?;
+        position += 2;
+        if unit == 0 {
+            break;
+        }
+        units.push(unit);
+    }
+
+    Ok((String::from_utf16_lossy(&units), position))
+}
+
+fn children(mut data: &[u8]) -> Result<Vec<Block<'_>>> {
+    let mut blocks = vec![];
+
+    while data.len() >= 6 {
+        let (block, length) = parse_block(data)?;
+        blocks.push(block);
+        data = &data[length.min(data.len())..];
+    }
+
+    Ok(blocks)
+}
+
+fn parse_version(data: &[u8]) -> Result<VersionInfo> {
+    let (root, _) = parse_block(data)?;
+    if root.key != "VS_VERSION_INFO" {
+        return Err(Error::InvalidImage("malformed version resource"));
+    }
+
+    let mut info = VersionInfo::default();
+
+    let fixed = Reader(root.value);
+    if root.value.len() >= 16 && fixed.u32(0)? == FIXED_FILE_INFO_SIGNATURE {
+        let ms = fixed.u32(8)?;
+        let ls = fixed.u32(12)?;
+        info.file_version_parts = Some([(ms >> 16) as u16, ms as u16, (ls >> 16) as u16, ls as u16]);
+    }
+
+    for file_info in children(root.children)? {
+        if file_info.key != "StringFileInfo" {
+            continue;
+        }
+        if let Some(table) = children(file_info.children)?.into_iter().next() {
+            for string in children(table.children)? {
+                let value = if string.text {
+                    read_utf16(string.value, 0).map(|(value, _)| value).unwrap_or_else(|_| utf16_lossy(string.value))
+                } else {
+                    utf16_lossy(string.value)
+                };
+                info.strings.insert(string.key, value);
+            }
+        }
+    }
+
+    Ok(info)
+}
+
+/// Decode a UTF-16 value that may lack its nul terminator
+fn utf16_lossy(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect();
+
+    String::from_utf16_lossy(&units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINRING0: &[u8] = include_bytes!("../../win_ring0/WinRing0.sys");
+    const WINRING0_X64: &[u8] = include_bytes!("../../win_ring0/WinRing0x64.sys");
+
+    fn expect_invalid(bytes: &[u8], reason: &str) {
+        match PeImage::parse(bytes) {
+            Err(Error::InvalidImage(actual)) => assert_eq!(actual, reason),
+            other => panic!("expected InvalidImage({:?}), got {:?}", reason, other)
+        }
+    }
+
+    fn check_winring0(image: &PeImage) {
+        assert_eq!(image.subsystem, Subsystem::Native);
+        assert!(image.has_signature);
+
+        let version = image.version.as_ref().unwrap();
+        assert_eq!(version.file_version_parts, Some([1, 2, 0, 5]));
+        assert_eq!(version.file_version(), Some("1.2.0.5"));
+        assert_eq!(version.product_name(), Some("WinRing0"));
+        assert_eq!(version.strings["CompanyName"], "OpenLibSys.org");
+    }
+
+    #[test]
+    fn parses_x86_driver() {
+        let image = PeImage::parse(WINRING0).unwrap();
+
+        assert_eq!(image.machine, Machine::X86);
+        assert_eq!(image.timestamp, 1217078710);
+        check_winring0(&image);
+    }
+
+    #[test]
+    fn parses_x64_driver() {
+        let image = PeImage::parse(WINRING0_X64).unwrap();
+
+        assert_eq!(image.machine, Machine::X64);
+        assert_eq!(image.timestamp, 1217078977);
+        check_winring0(&image);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        expect_invalid(b"", "truncated image");
+        expect_invalid(b"ZM", "missing MZ header");
+
+        let mut bytes = WINRING0_X64.to_vec();
+        bytes[0x3C..0x40].copy_from_slice(&u32::MAX.to_le_bytes());
+        expect_invalid(&bytes, "truncated image");
+
+        let mut bytes = WINRING0_X64.to_vec();
+        let pe = u32::from_le_bytes([bytes[0x3C], bytes[0x3D], bytes[0x3E], bytes[0x3F]]) as usize;
+        bytes[pe] = b'X';
+        expect_invalid(&bytes, "missing PE signature");
+
+        let mut bytes = WINRING0_X64.to_vec();
+        bytes[pe + 24..pe + 26].copy_from_slice(&0x1234u16.to_le_bytes());
+        expect_invalid(&bytes, "unknown optional header magic");
+    }
+
+    #[test]
+    fn truncated_images_fail_without_panicking() {
+        for image in &[WINRING0, WINRING0_X64] {
+            for len in 0..image.len() {
+                let _ = PeImage::parse(&image[..len]);
+            }
+            assert!(PeImage::parse(&image[..0x200]).is_err());
+        }
+    }
+
+    #[test]
+    fn corrupt_headers_fail_without_panicking() {
+        for image in &[WINRING0, WINRING0_X64] {
+            for offset in 0..0x400 {
+                for value in &[0x00, 0x7F, 0x80, 0xFF] {
+                    let mut bytes = image.to_vec();
+                    bytes[offset] = *value;
+                    let _ = PeImage::parse(&bytes);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rva_past_the_address_space_is_invalid() {
+        let sections = [Section { virtual_size: 0x1000, virtual_address: 0x1000, raw_size: 0x1000, raw_offset: u32::MAX - 0x10 }];
+
+        assert_eq!(rva_to_offset(&sections, 0x1008).unwrap(), (u32::MAX - 0x8) as usize);
+        assert!(matches!(rva_to_offset(&sections, 0x1020), Err(Error::InvalidImage("section offset out of range"))));
+        assert!(matches!(rva_to_offset(&sections, 0x800), Err(Error::InvalidImage("address outside of every section"))));
+        assert!(matches!(rva_to_offset(&sections, 0x2000), Err(Error::InvalidImage("address outside of every section"))));
+    }
+}
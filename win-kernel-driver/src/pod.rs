@@ -0,0 +1,55 @@
+//! Plain old data buffers for typed IO
+use std::mem::size_of;
+use std::slice;
+
+/// Marker for types that can be sent to and received from a driver as raw bytes.
+///
+/// Driver input and output structures are usually declared in C, so implementors
+/// should be `#[repr(C)]` (or `#[repr(C, packed)]` if the driver's header packs them).
+///
+/// # Safety
+///
+/// Implementors must not contain padding, pointers, references or any field for which
+/// some bit pattern is invalid (`bool`, `char`, enums). Every possible byte sequence of
+/// `size_of::<Self>()` bytes must be a valid value.
+///
+/// # Example
+/// ```
+/// use win_kernel_driver::Pod;
+///
+/// #[repr(C)]
+/// #[derive(Clone, Copy)]
+/// struct WriteMsrInput {
+///     register: u32,
+///     value: [u32; 2]
+/// }
+///
+/// unsafe impl Pod for WriteMsrInput {}
+/// ```
+pub unsafe trait Pod: Copy + 'static {}
+
+unsafe impl Pod for () {}
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// View a value as its raw bytes
+pub fn bytes_of<T: Pod>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// View a value as its raw, writable bytes
+pub fn bytes_of_mut<T: Pod>(value: &mut T) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+/// A zeroed value, used for output buffers before the driver fills them
+pub fn zeroed<T: Pod>() -> T {
+    unsafe { std::mem::zeroed() }
+}
@@ -0,0 +1,158 @@
+//! Typed clients for driver protocols
+//!
+//! A driver's interface is a list of IO control codes, each taking an input structure
+//! and filling an output structure. [driver_protocol!](crate::driver_protocol) declares
+//! such a list once and generates a client with one method per command, sending them
+//! through anything implementing [DeviceIo].
+use std::mem::size_of;
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use crate::ioctl::IoControlCode;
+use crate::pod::{self, Pod};
+
+/// Something IO commands can be sent through, like an opened
+/// [WinKernelDriver](crate::WinKernelDriver) or a [DriverSession](crate::DriverSession)
+pub trait DeviceIo {
+    /// Perform an IO command with arbitrary buffers, see
+    /// [WinKernelDriver::io_bytes](crate::WinKernelDriver::io_bytes)
+    fn io_bytes(&self, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize>;
+
+    /// Perform an IO command with typed structures. Fails with [Error::ShortOutput] if
+    /// the driver wrote less than `size_of::<O>()` bytes.
+    fn io_typed<I: Pod, O: Pod>(&self, ioctl_code: IoControlCode, input: &I) -> Result<O> where Self: Sized {
+        let mut output: O = pod::zeroed();
+        let written = self.io_bytes(ioctl_code, pod::bytes_of(input), pod::bytes_of_mut(&mut output))?;
+
+        if written < size_of::<O>() {
+            return Err(Error::ShortOutput { ioctl: ioctl_code, expected: size_of::<O>(), actual: written });
+        }
+
+        Ok(output)
+    }
+}
+
+impl<T: DeviceIo + ?Sized> DeviceIo for &T {
+    fn io_bytes(&self, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        (**self).io_bytes(ioctl_code, in_buffer, out_buffer)
+    }
+}
+
+impl<T: DeviceIo + ?Sized> DeviceIo for Arc<T> {
+    fn io_bytes(&self, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        (**self).io_bytes(ioctl_code, in_buffer, out_buffer)
+    }
+}
+
+/// Declare a driver protocol and generate a typed client for it.
+///
+/// Each command is written as a function signature followed by the name of the IO
+/// control code constant to generate and its function code, [Method](crate::Method) and
+/// [Access](crate::Access). Inputs and outputs are [Pod] types; commands without input
+/// take no argument and commands without output return `()`.
+///
+/// The client wraps any [DeviceIo], such as `&WinKernelDriver` or a `DriverSession`,
+/// and checks the driver wrote a whole output structure.
+///
+/// # Example
+/// ```
+/// use win_kernel_driver::{driver_protocol, DriverBuilder, MockTransport, Pod};
+///
+/// #[repr(C)]
+/// #[derive(Debug, Clone, Copy, PartialEq)]
+/// pub struct Version { major: u16, minor: u16 }
+/// unsafe impl Pod for Version {}
+///
+/// driver_protocol! {
+///     /// Commands of the example driver
+///     pub struct ExampleProtocol;
+///     device_type = 0x8000;
+///
+///     /// Version of the loaded driver
+///     fn version() -> Version = IOCTL_EXAMPLE_VERSION(0x800, BUFFERED, ANY);
+///     /// Read a register
+///     fn read_register(index: u32) -> u64 = IOCTL_EXAMPLE_READ(0x801, BUFFERED, READ);
+///     /// Write a register
+///     fn write_register(input: [u32; 2]) -> () = IOCTL_EXAMPLE_WRITE(0x802, BUFFERED, WRITE);
+/// }
+///
+/// let mock = MockTransport::new();
+/// mock.expect(IOCTL_EXAMPLE_VERSION).returns_typed(&Version { major: 1, minor: 2 });
+/// mock.expect(IOCTL_EXAMPLE_READ).with_typed_input(&7u32).returns_typed(&42u64);
+/// mock.expect(IOCTL_EXAMPLE_READ).returns(&[0; 4]);
+///
+/// let mut driver = DriverBuilder::new()
+///     .set_device_id("Example")
+///     .set_driver_path("example.sys".into())
+///     .set_transport(mock)
+///     .build().unwrap();
+/// driver.open().unwrap();
+///
+/// let example = ExampleProtocol::new(&driver);
+/// assert_eq!(example.version().unwrap(), Version { major: 1, minor: 2 });
+/// assert_eq!(example.read_register(7).unwrap(), 42);
+///
+/// // Only 4 of the 8 bytes were written
+/// assert!(example.read_register(8).is_err());
+/// ```
+#[macro_export]
+macro_rules! driver_protocol {
+    (@method $(#[$meta:meta])* $op:ident () -> $output:ty = $code:ident) => {
+        $(#[$meta])*
+        pub fn $op(&self) -> $crate::Result<$output> {
+            $crate::DeviceIo::io_typed(&self.device, $code, &())
+        }
+    };
+
+    (@method $(#[$meta:meta])* $op:ident ($arg:ident : $input:ty) -> $output:ty = $code:ident) => {
+        $(#[$meta])*
+        pub fn $op(&self, $arg: $input) -> $crate::Result<$output> {
+            $crate::DeviceIo::io_typed(&self.device, $code, &$arg)
+        }
+    };
+
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident;
+        device_type = $device_type:expr;
+
+        $(
+            $(#[$op_meta:meta])*
+            fn $op:ident ( $($arg:ident : $input:ty)? ) -> $output:ty
+                = $code:ident ( $function:expr, $method:ident, $access:ident );
+        )*
+    ) => {
+        $(
+            #[doc = concat!("IO control code of [", stringify!($name), "::", stringify!($op), "]")]
+            $vis const $code: $crate::IoControlCode = $crate::IoControlCode::new(
+                $device_type, $function, $crate::Method::$method, $crate::Access::$access
+            );
+        )*
+
+        $(#[$meta])*
+        $vis struct $name<D> {
+            device: D
+        }
+
+        impl<D: $crate::DeviceIo> $name<D> {
+            /// Send the commands through `device`
+            pub fn new(device: D) -> Self {
+                $name { device }
+            }
+
+            /// The device the commands are sent through
+            pub fn device(&self) -> &D {
+                &self.device
+            }
+
+            /// Give back the device
+            pub fn into_inner(self) -> D {
+                self.device
+            }
+
+            $(
+                $crate::driver_protocol!(@method $(#[$op_meta])* $op ( $($arg : $input)? ) -> $output = $code);
+            )*
+        }
+    };
+}
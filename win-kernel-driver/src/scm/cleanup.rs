@@ -0,0 +1,313 @@
+//! Finding and removing driver services left behind by other installs
+//!
+//! Programs that crash or get killed never uninstall their driver, so machines collect
+//! services from different tools and versions, often pointing at staged files that are
+//! long gone. [find_driver_services] lists them, [remove_driver_service] deletes them.
+use std::env;
+use std::path::{Path, PathBuf};
+
+use tracing::{info, info_span};
+
+use crate::error::{Error, Result};
+use crate::staging;
+use super::{same_binary, uninstall, ServiceControlManager, ServiceState};
+
+/// A kernel driver service found by [find_driver_services]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverService {
+    /// Name of the service
+    pub name: String,
+    /// Path of the driver file, as stored by the service control manager
+    pub binary_path: PathBuf,
+    /// Current state of the service
+    pub state: ServiceState,
+    /// The service was deleted but is kept alive by open handles
+    pub marked_for_deletion: bool,
+    /// Whether the driver file still exists. A service without it can't be started.
+    pub file_exists: bool
+}
+
+/// List the kernel driver services whose name matches `pattern`, sorted by name.
+///
+/// The pattern is matched ignoring case, like the service control manager does, and
+/// may contain `*` for any number of characters and `?` for exactly one.
+///
+/// # Example
+/// ```
+/// use win_kernel_driver::{find_driver_services, remove_driver_service, FakeServiceManager, ServiceControlManager, ServiceState};
+///
+/// let staging_dir = std::env::temp_dir().join("wkd-cleanup-example");
+/// let staged = staging_dir.join("WinRing0_1_2_0-0123456789abcdef.sys");
+/// std::fs::create_dir_all(&staging_dir).unwrap();
+/// std::fs::write(&staged, b"MZ").unwrap();
+///
+/// let fake = FakeServiceManager::new();
+/// fake.add_service("WinRing0_1_2_0", &staged, ServiceState::Running);
+/// fake.add_service("WinRing0_1_2_0_x64", staging_dir.join("gone.sys"), ServiceState::Stopped);
+/// fake.add_service("Beep", r"System32\Drivers\Beep.sys", ServiceState::Running);
+///
+/// let found = find_driver_services(&fake, "winring0*").unwrap();
+/// assert_eq!(found.len(), 2);
+/// assert!(found[0].file_exists);
+/// assert!(!found[1].file_exists);
+///
+/// for service in &found {
+///     remove_driver_service(&fake, service, Some(&staging_dir)).unwrap();
+/// }
+///
+/// assert!(!staged.exists());
+/// assert_eq!(fake.driver_services().unwrap(), vec!["Beep"]);
+/// ```
+pub fn find_driver_services(scm: &dyn ServiceControlManager, pattern: &str) -> Result<Vec<DriverService>> {
+    let mut names: Vec<String> = scm.driver_services()?
+        .into_iter()
+        .filter(|name| name_matches(pattern, name))
+        .collect();
+    names.sort_by_key(|name| name.to_lowercase());
+
+    let mut found = vec![];
+    for name in names {
+        // Gone between listing and looking it up
+        let details = match scm.query(&name)? {
+            Some(details) => details,
+            None => { continue; }
+        };
+
+        found.push(DriverService {
+            file_exists: resolve_driver_path(&details.binary_path).is_file(),
+            name,
+            binary_path: details.binary_path,
+            state: details.state,
+            marked_for_deletion: details.marked_for_deletion
+        });
+    }
+
+    Ok(found)
+}
+
+/// Stop and delete a service found by [find_driver_services]. Returns the path of the
+/// driver file if it was deleted too.
+///
+/// Other programs may still be using the service, so:
+///
+/// * The service is looked up again first. If it now points at another driver file it
+///   was reinstalled in the meantime, and [Error::ServiceChanged] is returned.
+/// * A service that is already marked for deletion fails with
+///   [Error::ServiceMarkedForDeletion].
+/// * A running driver is stopped first. A driver whose device is still open refuses
+///   to stop, and that error is returned.
+/// * The driver file is only deleted if it is directly in `staging_dir`, where
+///   [DriverBuilder::set_driver_bin](crate::DriverBuilder::set_driver_bin) writes
+///   drivers. Files anywhere else, like `System32\drivers`, are left alone.
+pub fn remove_driver_service(scm: &dyn ServiceControlManager, service: &DriverService, staging_dir: Option<&Path>) -> Result<Option<PathBuf>> {
+    let _span = info_span!("remove_driver_service", service = %service.name).entered();
+
+    let current = match scm.query(&service.name)? {
+        Some(current) => current,
+        None => { return Err(Error::not_installed(&service.name)); }
+    };
+
+    if !same_binary(&current.binary_path, &service.binary_path) {
+        return Err(Error::ServiceChanged(service.name.clone()));
+    }
+    if current.marked_for_deletion {
+        return Err(Error::marked_for_deletion(&service.name));
+    }
+
+    uninstall(scm, &service.name)?;
+
+    let path = resolve_driver_path(&service.binary_path);
+    let staged = staging_dir.is_some_and(|dir| {
+        path.parent().is_some_and(|parent| same_binary(parent, dir))
+    });
+
+    if staged && path.is_file() {
+        info!(?path, "deleting staged driver file");
+        staging::remove(&path)?;
+        return Ok(Some(path));
+    }
+
+    Ok(None)
+}
+
+/// Where a driver path stored by the service control manager points to on disk.
+///
+/// Driver services store paths as `\??\C:\...`, `\SystemRoot\System32\...` or relative
+/// to the Windows directory, like `System32\drivers\beep.sys`.
+pub fn resolve_driver_path(path: &Path) -> PathBuf {
+    let text = path.to_string_lossy();
+    let text = text.strip_prefix(r"\??\").unwrap_or(&text);
+
+    let lower = text.to_ascii_lowercase();
+    if lower.starts_with(r"\systemroot\") {
+        return windows_dir().join(&text[r"\systemroot\".len()..]);
+    }
+
+    let bytes = text.as_bytes();
+    let has_drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    if has_drive || text.starts_with('\\') || Path::new(text).is_absolute() {
+        return PathBuf::from(text);
+    }
+
+    windows_dir().join(text)
+}
+
+fn windows_dir() -> PathBuf {
+    env::var_os("SystemRoot")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(r"C:\Windows"))
+}
+
+/// Match `name` against a pattern with `*` and `?` wildcards, ignoring case
+fn name_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+
+    // Position after the last `*` in the pattern, and where it started matching in the name
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                Some((after, matched)) => {
+                    p = after;
+                    n = matched + 1;
+                    star = Some((after, matched + 1));
+                },
+                None => { return false; }
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use crate::scm::FakeServiceManager;
+
+    fn test_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wkd-cleanup-{}-{}", test, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn empty_pattern_only_matches_empty_name() {
+        assert!(name_matches("", ""));
+        assert!(!name_matches("", "WinRing0"));
+    }
+
+    #[test]
+    fn star_matches_any_run_of_characters() {
+        assert!(name_matches("*", ""));
+        assert!(name_matches("*", "WinRing0_1_2_0"));
+        assert!(name_matches("WinRing0*", "WinRing0"));
+        assert!(name_matches("WinRing0*", "WinRing0_1_2_0"));
+        assert!(name_matches("WinRing0**", "WinRing0_1_2_0"));
+        assert!(name_matches("*_x64", "WinRing0_1_2_0_x64"));
+        assert!(name_matches("W*_*_x64", "WinRing0_1_2_0_x64"));
+        assert!(!name_matches("WinRing0*", "OldWinRing0"));
+        assert!(!name_matches("*_x64", "WinRing0_x64_old"));
+    }
+
+    #[test]
+    fn question_mark_matches_exactly_one_character() {
+        assert!(name_matches("WinRing0_?_2_0", "WinRing0_1_2_0"));
+        assert!(!name_matches("WinRing0_?_2_0", "WinRing0__2_0"));
+        assert!(!name_matches("WinRing0_?_2_0", "WinRing0_10_2_0"));
+        assert!(!name_matches("?", ""));
+        assert!(name_matches("?*", "a"));
+    }
+
+    #[test]
+    fn matching_ignores_case() {
+        assert!(name_matches("winring0*", "WINRING0_1_2_0"));
+        assert!(name_matches("WINRING0_?_2_0", "winring0_1_2_0"));
+        assert!(!name_matches("winring1*", "WinRing0"));
+    }
+
+    #[test]
+    fn orphaned_services_have_no_driver_file() {
+        let dir = test_dir("orphaned");
+        let present = dir.join("present.sys");
+        fs::write(&present, b"MZ").unwrap();
+
+        let fake = FakeServiceManager::new();
+        fake.add_service("WinRing0_a", &present, ServiceState::Running);
+        fake.add_service("WinRing0_b", dir.join("gone.sys"), ServiceState::Stopped);
+        fake.add_service("Other", dir.join("gone.sys"), ServiceState::Stopped);
+
+        let orphaned: Vec<String> = find_driver_services(&fake, "winring0_*").unwrap()
+            .into_iter()
+            .filter(|service| !service.file_exists)
+            .map(|service| service.name)
+            .collect();
+        assert_eq!(orphaned, vec!["WinRing0_b"]);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn removes_driver_file_only_inside_staging_dir() {
+        let staging_dir = test_dir("staging");
+        let other_dir = test_dir("other");
+        let nested_dir = staging_dir.join("nested");
+        fs::create_dir_all(&nested_dir).unwrap();
+
+        let staged = staging_dir.join("staged.sys");
+        let elsewhere = other_dir.join("elsewhere.sys");
+        let nested = nested_dir.join("nested.sys");
+        let kept_without_dir = staging_dir.join("kept.sys");
+        for path in &[&staged, &elsewhere, &nested, &kept_without_dir] {
+            fs::write(path, b"MZ").unwrap();
+        }
+
+        let fake = FakeServiceManager::new();
+        fake.add_service("Staged", &staged, ServiceState::Running);
+        fake.add_service("Elsewhere", &elsewhere, ServiceState::Running);
+        fake.add_service("Nested", &nested, ServiceState::Stopped);
+        fake.add_service("NoStagingDir", &kept_without_dir, ServiceState::Stopped);
+
+        let services = find_driver_services(&fake, "*").unwrap();
+        let service = |name: &str| services.iter().find(|service| service.name == name).unwrap();
+
+        assert_eq!(remove_driver_service(&fake, service("Staged"), Some(&staging_dir)).unwrap(), Some(staged.clone()));
+        assert_eq!(remove_driver_service(&fake, service("Elsewhere"), Some(&staging_dir)).unwrap(), None);
+        assert_eq!(remove_driver_service(&fake, service("Nested"), Some(&staging_dir)).unwrap(), None);
+        assert_eq!(remove_driver_service(&fake, service("NoStagingDir"), None).unwrap(), None);
+
+        assert!(!staged.exists());
+        assert!(elsewhere.exists());
+        assert!(nested.exists());
+        assert!(kept_without_dir.exists());
+        assert!(fake.driver_services().unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(staging_dir);
+        let _ = fs::remove_dir_all(other_dir);
+    }
+
+    #[test]
+    fn refuses_service_reinstalled_with_another_file() {
+        let fake = FakeServiceManager::new();
+        fake.add_service("WinRing0", r"C:\old\WinRing0x64.sys", ServiceState::Stopped);
+        let found = find_driver_services(&fake, "WinRing0").unwrap();
+
+        fake.add_service("WinRing0", r"C:\new\WinRing0x64.sys", ServiceState::Stopped);
+        assert!(matches!(remove_driver_service(&fake, &found[0], None), Err(Error::ServiceChanged(_))));
+        assert!(fake.operations().is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::error::{Error, Result};
+use super::{ServiceConfig, ServiceControlManager, ServiceDetails, ServiceState};
+
+/// An in-memory [ServiceControlManager].
+///
+/// Services can be seeded with [FakeServiceManager::add_service] to reproduce what is
+/// found on real machines, such as a stale service pointing at an old driver file. Every
+/// operation is logged and can be read back with [FakeServiceManager::operations].
+///
+/// Clones share the same services and log.
+///
+/// # Example
+/// ```
+/// use win_kernel_driver::{DriverBuilder, FakeServiceManager, InstallOutcome, ServiceState};
+///
+/// let fake = FakeServiceManager::new();
+/// fake.add_service("WinRing0_1_2_0", r"C:\old\WinRing0x64.sys", ServiceState::Running);
+///
+/// let mut driver = DriverBuilder::new()
+///     .set_device_id("WinRing0_1_2_0")
+///     .set_driver_path(r"C:\new\WinRing0x64.sys".into())
+///     .set_service_manager(fake.clone())
+///     .build().unwrap();
+///
+/// assert_eq!(driver.install().unwrap(), InstallOutcome::Recreated);
+/// assert_eq!(fake.operations(), vec![
+///     "stop WinRing0_1_2_0",
+///     "delete WinRing0_1_2_0",
+///     "create WinRing0_1_2_0",
+///     "start WinRing0_1_2_0"
+/// ]);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct FakeServiceManager {
+    state: Arc<Mutex<FakeState>>
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    services: BTreeMap<String, FakeService>,
+    operations: Vec<String>,
+    keep_deleted: bool
+}
+
+#[derive(Debug, Clone)]
+struct FakeService {
+    config: ServiceConfig,
+    state: ServiceState,
+    marked_for_deletion: bool
+}
+
+impl FakeServiceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an existing kernel driver service
+    pub fn add_service<P: Into<PathBuf>>(&self, name: &str, binary_path: P, state: ServiceState) {
+        let service = FakeService {
+            config: ServiceConfig::new(name, binary_path),
+            state,
+            marked_for_deletion: false
+        };
+
+        self.lock().services.insert(name.to_owned(), service);
+    }
+
+    /// Mark an existing service as deleted but still held open by some other process
+    pub fn mark_for_deletion(&self, name: &str) {
+        if let Some(service) = self.lock().services.get_mut(name) {
+            service.marked_for_deletion = true;
+        }
+    }
+
+    /// Simulate other processes holding handles to services: deleted services stay
+    /// around, marked for deletion, until [FakeServiceManager::release_handles] is called.
+    pub fn hold_handles(&self, hold: bool) {
+        self.lock().keep_deleted = hold;
+    }
+
+    /// Remove every service that is marked for deletion, as happens once the last
+    /// handle to it is closed
+    pub fn release_handles(&self) {
+        self.lock().services.retain(|_, service| !service.marked_for_deletion);
+    }
+
+    /// The current state of a service, if it exists
+    pub fn service(&self, name: &str) -> Option<ServiceDetails> {
+        self.lock().services.get(name).map(FakeService::details)
+    }
+
+    /// The config a service was created with, if it exists
+    pub fn config(&self, name: &str) -> Option<ServiceConfig> {
+        self.lock().services.get(name).map(|service| service.config.clone())
+    }
+
+    /// Every create / start / stop / delete call so far, as `"<operation> <name>"`
+    pub fn operations(&self) -> Vec<String> {
+        self.lock().operations.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl FakeService {
+    fn details(&self) -> ServiceDetails {
+        ServiceDetails {
+            binary_path: self.config.binary_path.clone(),
+            state: self.state,
+            marked_for_deletion: self.marked_for_deletion
+        }
+    }
+}
+
+impl FakeState {
+    fn service(&mut self, name: &str) -> Result<&mut FakeService> {
+        match self.services.get_mut(name) {
+            Some(service) if service.marked_for_deletion => Err(Error::marked_for_deletion(name)),
+            Some(service) => Ok(service),
+            None => Err(Error::not_installed(name))
+        }
+    }
+}
+
+impl ServiceControlManager for FakeServiceManager {
+    fn query(&self, name: &str) -> Result<Option<ServiceDetails>> {
+        Ok(self.service(name))
+    }
+
+    fn create(&self, config: &ServiceConfig) -> Result<()> {
+        let mut state = self.lock();
+        state.operations.push(format!("create {}", config.name));
+
+        match state.services.get(&config.name) {
+            Some(service) if service.marked_for_deletion => Err(Error::marked_for_deletion(&config.name)),
+            Some(_) => Err(Error::service_exists(&config.name)),
+            None => {
+                state.services.insert(config.name.clone(), FakeService {
+                    config: config.clone(),
+                    state: ServiceState::Stopped,
+                    marked_for_deletion: false
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn start(&self, name: &str) -> Result<()> {
+        let mut state = self.lock();
+        state.operations.push(format!("start {}", name));
+
+        state.service(name)?.state = ServiceState::Running;
+        Ok(())
+    }
+
+    fn stop(&self, name: &str) -> Result<()> {
+        let mut state = self.lock();
+        state.operations.push(format!("stop {}", name));
+
+        state.service(name)?.state = ServiceState::Stopped;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        let mut state = self.lock();
+        state.operations.push(format!("delete {}", name));
+
+        state.service(name)?;
+        if state.keep_deleted {
+            state.service(name)?.marked_for_deletion = true;
+        } else {
+            state.services.remove(name);
+        }
+        Ok(())
+    }
+
+    fn driver_services(&self) -> Result<Vec<String>> {
+        Ok(self.lock().services.keys().cloned().collect())
+    }
+}
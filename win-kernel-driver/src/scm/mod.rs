@@ -0,0 +1,466 @@
+//! Service control manager access
+//! 
+//! Kernel drivers are loaded by registering them as a service with the service control
+//! manager (SCM) and starting that service. [WinKernelDriver](crate::WinKernelDriver)
+//! does this through the [ServiceControlManager] trait. [Win32ServiceManager] is the real
+//! SCM, [FakeServiceManager] keeps services in memory so install logic can run anywhere.
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info, info_span};
+
+use crate::error::{Error, Result};
+
+#[cfg(windows)]
+mod win32;
+#[cfg(not(windows))]
+mod unsupported;
+mod fake;
+mod cleanup;
+
+#[cfg(windows)]
+pub use win32::Win32ServiceManager;
+#[cfg(not(windows))]
+pub use unsupported::Win32ServiceManager;
+pub use fake::FakeServiceManager;
+pub use cleanup::{find_driver_services, remove_driver_service, resolve_driver_path, DriverService};
+
+/// How many times [install] looks at the service again when it changes underneath it
+const INSTALL_ATTEMPTS: usize = 3;
+/// How long [install] waits for a starting or stopping service to finish
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+const SETTLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Current state of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ServiceState {
+    Stopped,
+    StartPending,
+    StopPending,
+    Running,
+    ContinuePending,
+    PausePending,
+    Paused
+}
+
+/// When the service control manager starts a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum StartType {
+    /// Started at system startup
+    Auto,
+    /// Only started when asked to, this is what [install] does
+    #[default]
+    OnDemand,
+    /// Can't be started
+    Disabled
+}
+
+/// What happens at boot when a service fails to start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ErrorControl {
+    /// Log nothing and carry on
+    Ignore,
+    /// Log the error and carry on
+    #[default]
+    Normal,
+    /// Log the error and switch to the last known good configuration
+    Severe,
+    /// Log the error and fail the boot if already on the last known good configuration
+    Critical
+}
+
+/// What is needed to create a driver service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceConfig {
+    /// Name of the service
+    pub name: String,
+    /// User friendly name of the service
+    pub display_name: String,
+    /// Path of the driver file
+    pub binary_path: PathBuf,
+    pub start_type: StartType,
+    pub error_control: ErrorControl,
+    /// Names of services that must be started before this one
+    pub dependencies: Vec<String>
+}
+
+impl ServiceConfig {
+    /// A config for an on demand service with normal error control and no dependencies
+    pub fn new<P: Into<PathBuf>>(name: &str, binary_path: P) -> Self {
+        ServiceConfig {
+            name: name.to_owned(),
+            display_name: name.to_owned(),
+            binary_path: binary_path.into(),
+            start_type: StartType::default(),
+            error_control: ErrorControl::default(),
+            dependencies: vec![]
+        }
+    }
+}
+
+/// An installed service as reported by [ServiceControlManager::query]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceDetails {
+    /// Path of the driver file the service loads
+    pub binary_path: PathBuf,
+    /// Current state of the service
+    pub state: ServiceState,
+    /// The service has been deleted but is kept alive by open handles. It can't
+    /// be started or recreated until they are closed.
+    pub marked_for_deletion: bool
+}
+
+/// The service control manager operations needed to manage a driver service
+pub trait ServiceControlManager: Send + Sync {
+    /// Look up a service. Returns `None` if it is not installed.
+    fn query(&self, name: &str) -> Result<Option<ServiceDetails>>;
+
+    /// Create a kernel driver service. Fails with [Error::ServiceExists] if
+    /// the name is taken.
+    fn create(&self, config: &ServiceConfig) -> Result<()>;
+
+    /// Start a service
+    fn start(&self, name: &str) -> Result<()>;
+
+    /// Stop a running service
+    fn stop(&self, name: &str) -> Result<()>;
+
+    /// Delete a service. It only goes away once every handle to it is closed.
+    fn delete(&self, name: &str) -> Result<()>;
+
+    /// Names of every kernel driver service, including stopped ones and ones
+    /// marked for deletion
+    fn driver_services(&self) -> Result<Vec<String>>;
+}
+
+/// What [install] had to do to get the service running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstallOutcome {
+    /// The service did not exist and was created
+    Created,
+    /// A service for the same driver file already existed and was (re)used
+    Reused,
+    /// A service with the same name pointed at a different driver file, it
+    /// was deleted and created again
+    Recreated
+}
+
+/// Install and start a driver service, coping with one that already exists.
+/// 
+/// * No service: it is created and started.
+/// * A service for the same driver file: it is started if it isn't running yet.
+/// * A service for a different driver file (a stale install from another version or
+///   tool): it is stopped, deleted and created again.
+/// * A service marked for deletion: [Error::ServiceMarkedForDeletion], as nothing can be
+///   done until the handles keeping it alive are closed.
+///
+/// A service that is still starting or stopping is waited for first. If someone else
+/// keeps creating or deleting the service meanwhile, install gives up with
+/// [Error::ServiceUnstable] after a few attempts.
+pub fn install(scm: &dyn ServiceControlManager, config: &ServiceConfig) -> Result<InstallOutcome> {
+    let _span = info_span!("install", service = %config.name).entered();
+
+    for _ in 0..INSTALL_ATTEMPTS {
+        let details = match scm.query(&config.name)? {
+            Some(details) => details,
+            None => {
+                info!(binary_path = ?config.binary_path, "creating service");
+                match scm.create(config) {
+                    Ok(()) => {},
+                    // Someone else created it in the meantime, look at what they created
+                    Err(Error::ServiceExists { .. }) => { continue; }
+                    Err(err) => { return Err(err); }
+                }
+
+                start(scm, &config.name)?;
+                return Ok(InstallOutcome::Created);
+            }
+        };
+
+        if let Some(outcome) = reuse_or_recreate(scm, config, details)? {
+            return Ok(outcome);
+        }
+    }
+
+    Err(Error::ServiceUnstable { name: config.name.clone(), attempts: INSTALL_ATTEMPTS })
+}
+
+/// Reuse or recreate an existing service. Returns `None` if it was deleted or created
+/// by someone else along the way and has to be looked at again.
+fn reuse_or_recreate(scm: &dyn ServiceControlManager, config: &ServiceConfig, details: ServiceDetails) -> Result<Option<InstallOutcome>> {
+    debug!(?details, "service exists");
+    let details = match settle(scm, &config.name, details)? {
+        Some(details) => details,
+        None => { return Ok(None); }
+    };
+
+    if details.marked_for_deletion {
+        return Err(Error::marked_for_deletion(&config.name));
+    }
+
+    if same_binary(&details.binary_path, &config.binary_path) {
+        info!("reusing service");
+        if details.state != ServiceState::Running {
+            start(scm, &config.name)?;
+        }
+        return Ok(Some(InstallOutcome::Reused));
+    }
+
+    info!(old_binary_path = ?details.binary_path, binary_path = ?config.binary_path, "recreating stale service");
+    if details.state != ServiceState::Stopped {
+        scm.stop(&config.name)?;
+    }
+    scm.delete(&config.name)?;
+
+    // The old service lingers if another process still has a handle to it
+    if let Some(old) = scm.query(&config.name)? {
+        if old.marked_for_deletion {
+            return Err(Error::marked_for_deletion(&config.name));
+        }
+    }
+
+    match scm.create(config) {
+        Ok(()) => {},
+        Err(Error::ServiceExists { .. }) => { return Ok(None); }
+        Err(err) => { return Err(err); }
+    }
+    start(scm, &config.name)?;
+
+    Ok(Some(InstallOutcome::Recreated))
+}
+
+/// Wait for a service that is starting, stopping, pausing or continuing to get there,
+/// as it can't be started or stopped before. Returns `None` if the service went away.
+fn settle(scm: &dyn ServiceControlManager, name: &str, mut details: ServiceDetails) -> Result<Option<ServiceDetails>> {
+    let start = Instant::now();
+
+    loop {
+        let expected = match details.state {
+            ServiceState::StartPending | ServiceState::ContinuePending => ServiceState::Running,
+            ServiceState::StopPending => ServiceState::Stopped,
+            ServiceState::PausePending => ServiceState::Paused,
+            _ => { return Ok(Some(details)); }
+        };
+
+        let elapsed = start.elapsed();
+        if elapsed >= SETTLE_TIMEOUT {
+            return Err(Error::ServiceStateTimeout { name: name.to_owned(), expected, actual: details.state });
+        }
+
+        debug!(state = ?details.state, "waiting for service to settle");
+        thread::sleep(SETTLE_POLL_INTERVAL.min(SETTLE_TIMEOUT - elapsed));
+
+        details = match scm.query(name)? {
+            Some(details) => details,
+            None => { return Ok(None); }
+        };
+    }
+}
+
+/// Start a service, treating one that was started by someone else in the meantime as success
+pub(crate) fn start(scm: &dyn ServiceControlManager, name: &str) -> Result<()> {
+    info!(service = name, "starting service");
+    match scm.start(name) {
+        Ok(()) => Ok(()),
+        // Started by someone else between the query and here
+        Err(Error::Service { .. }) if is_running(scm, name) => Ok(()),
+        Err(err) => Err(err)
+    }
+}
+
+fn is_running(scm: &dyn ServiceControlManager, name: &str) -> bool {
+    match scm.query(name) {
+        Ok(Some(details)) => details.state == ServiceState::Running,
+        _ => false
+    }
+}
+
+/// Stop and delete a driver service
+pub fn uninstall(scm: &dyn ServiceControlManager, name: &str) -> Result<()> {
+    let _span = info_span!("uninstall", service = name).entered();
+
+    let details = match scm.query(name)? {
+        Some(details) => details,
+        None => { return Err(Error::not_installed(name)); }
+    };
+
+    if details.state != ServiceState::Stopped {
+        info!("stopping service");
+        scm.stop(name)?;
+    }
+
+    info!("deleting service");
+    scm.delete(name)
+}
+
+/// Compare two driver paths the way the SCM stores them: case insensitive and
+/// with or without the `\??\` NT prefix.
+pub fn same_binary(a: &Path, b: &Path) -> bool {
+    fn normalize(path: &Path) -> String {
+        let path = path.to_string_lossy();
+        let path = path.strip_prefix(r"\??\").unwrap_or(&path);
+        path.to_lowercase()
+    }
+
+    normalize(a) == normalize(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    const NAME: &str = "WinRing0_1_2_0";
+    const DRIVER: &str = r"C:\drivers\WinRing0x64.sys";
+
+    fn config() -> ServiceConfig {
+        ServiceConfig::new(NAME, DRIVER)
+    }
+
+    /// Another process creates the service between the query and the create
+    struct RacingCreate {
+        fake: FakeServiceManager,
+        raced: Mutex<bool>
+    }
+
+    impl ServiceControlManager for RacingCreate {
+        fn query(&self, name: &str) -> Result<Option<ServiceDetails>> { self.fake.query(name) }
+        fn create(&self, config: &ServiceConfig) -> Result<()> {
+            let mut raced = self.raced.lock().unwrap();
+            if !*raced {
+                *raced = true;
+                self.fake.add_service(&config.name, &config.binary_path, ServiceState::Running);
+            }
+            self.fake.create(config)
+        }
+        fn start(&self, name: &str) -> Result<()> { self.fake.start(name) }
+        fn stop(&self, name: &str) -> Result<()> { self.fake.stop(name) }
+        fn delete(&self, name: &str) -> Result<()> { self.fake.delete(name) }
+        fn driver_services(&self) -> Result<Vec<String>> { self.fake.driver_services() }
+    }
+
+    /// A service that is still starting for the first few queries
+    struct SlowStart {
+        fake: FakeServiceManager,
+        pending_queries: Mutex<usize>
+    }
+
+    impl ServiceControlManager for SlowStart {
+        fn query(&self, name: &str) -> Result<Option<ServiceDetails>> {
+            let mut pending = self.pending_queries.lock().unwrap();
+            let mut details = self.fake.query(name)?;
+            if *pending > 0 {
+                *pending -= 1;
+                if let Some(details) = details.as_mut() {
+                    details.state = ServiceState::StartPending;
+                }
+            }
+            Ok(details)
+        }
+        fn create(&self, config: &ServiceConfig) -> Result<()> { self.fake.create(config) }
+        fn start(&self, name: &str) -> Result<()> { self.fake.start(name) }
+        fn stop(&self, name: &str) -> Result<()> { self.fake.stop(name) }
+        fn delete(&self, name: &str) -> Result<()> { self.fake.delete(name) }
+        fn driver_services(&self) -> Result<Vec<String>> { self.fake.driver_services() }
+    }
+
+    /// Claims the service exists on create, but never shows it
+    struct Flapping {
+        fake: FakeServiceManager
+    }
+
+    impl ServiceControlManager for Flapping {
+        fn query(&self, name: &str) -> Result<Option<ServiceDetails>> { self.fake.query(name) }
+        fn create(&self, config: &ServiceConfig) -> Result<()> {
+            self.fake.create(config)?;
+            self.fake.delete(&config.name)?;
+            Err(Error::service_exists(&config.name))
+        }
+        fn start(&self, name: &str) -> Result<()> { self.fake.start(name) }
+        fn stop(&self, name: &str) -> Result<()> { self.fake.stop(name) }
+        fn delete(&self, name: &str) -> Result<()> { self.fake.delete(name) }
+        fn driver_services(&self) -> Result<Vec<String>> { self.fake.driver_services() }
+    }
+
+    #[test]
+    fn creates_missing_service() {
+        let fake = FakeServiceManager::new();
+
+        assert_eq!(install(&fake, &config()).unwrap(), InstallOutcome::Created);
+        assert_eq!(fake.operations(), vec![format!("create {}", NAME), format!("start {}", NAME)]);
+        assert_eq!(fake.service(NAME).unwrap().state, ServiceState::Running);
+    }
+
+    #[test]
+    fn reuses_stopped_service_for_same_binary() {
+        let fake = FakeServiceManager::new();
+        fake.add_service(NAME, r"\??\c:\DRIVERS\winring0x64.sys", ServiceState::Stopped);
+
+        assert_eq!(install(&fake, &config()).unwrap(), InstallOutcome::Reused);
+        assert_eq!(fake.operations(), vec![format!("start {}", NAME)]);
+    }
+
+    #[test]
+    fn reuses_running_service_without_starting_it() {
+        let fake = FakeServiceManager::new();
+        fake.add_service(NAME, DRIVER, ServiceState::Running);
+
+        assert_eq!(install(&fake, &config()).unwrap(), InstallOutcome::Reused);
+        assert!(fake.operations().is_empty());
+    }
+
+    #[test]
+    fn fails_on_service_marked_for_deletion() {
+        let fake = FakeServiceManager::new();
+        fake.add_service(NAME, DRIVER, ServiceState::Stopped);
+        fake.mark_for_deletion(NAME);
+
+        assert!(matches!(install(&fake, &config()), Err(Error::ServiceMarkedForDeletion { .. })));
+        assert!(fake.operations().is_empty());
+    }
+
+    #[test]
+    fn fails_when_deleted_service_is_held_open() {
+        let fake = FakeServiceManager::new();
+        fake.add_service(NAME, r"C:\old\WinRing0x64.sys", ServiceState::Running);
+        fake.hold_handles(true);
+
+        assert!(matches!(install(&fake, &config()), Err(Error::ServiceMarkedForDeletion { .. })));
+        assert_eq!(fake.operations(), vec![format!("stop {}", NAME), format!("delete {}", NAME)]);
+
+        fake.release_handles();
+        assert_eq!(install(&fake, &config()).unwrap(), InstallOutcome::Created);
+    }
+
+    #[test]
+    fn reuses_service_created_by_someone_else() {
+        let scm = RacingCreate { fake: FakeServiceManager::new(), raced: Mutex::new(false) };
+
+        assert_eq!(install(&scm, &config()).unwrap(), InstallOutcome::Reused);
+        assert_eq!(scm.fake.operations(), vec![format!("create {}", NAME)]);
+    }
+
+    #[test]
+    fn waits_for_pending_service() {
+        let fake = FakeServiceManager::new();
+        fake.add_service(NAME, DRIVER, ServiceState::Running);
+        let scm = SlowStart { fake, pending_queries: Mutex::new(3) };
+
+        assert_eq!(install(&scm, &config()).unwrap(), InstallOutcome::Reused);
+        assert!(scm.fake.operations().is_empty());
+        assert_eq!(*scm.pending_queries.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn gives_up_on_service_that_keeps_changing() {
+        let scm = Flapping { fake: FakeServiceManager::new() };
+
+        match install(&scm, &config()) {
+            Err(Error::ServiceUnstable { attempts, .. }) => assert_eq!(attempts, INSTALL_ATTEMPTS),
+            other => panic!("unexpected {:?}", other)
+        }
+        assert_eq!(scm.fake.operations().len(), 2 * INSTALL_ATTEMPTS);
+    }
+}
@@ -0,0 +1,34 @@
+use crate::error::{Error, Result};
+use super::{ServiceConfig, ServiceControlManager, ServiceDetails};
+
+/// Stands in for the service control manager on other platforms. Every operation
+/// fails with [Error::UnsupportedPlatform]; use a
+/// [FakeServiceManager](crate::FakeServiceManager) instead.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Win32ServiceManager;
+
+impl ServiceControlManager for Win32ServiceManager {
+    fn query(&self, _name: &str) -> Result<Option<ServiceDetails>> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn create(&self, _config: &ServiceConfig) -> Result<()> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn start(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn stop(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn delete(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn driver_services(&self) -> Result<Vec<String>> {
+        Err(Error::UnsupportedPlatform)
+    }
+}
@@ -0,0 +1,230 @@
+use std::ffi::OsString;
+use std::io;
+use std::ptr::{null, null_mut};
+
+use windows_service::{
+    service::{Service, ServiceAccess, ServiceDependency, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceType},
+    service::ServiceState as WinServiceState,
+    service_manager::{ServiceManager, ServiceManagerAccess}
+};
+
+use winapi::shared::minwindef::DWORD;
+use winapi::shared::winerror;
+use winapi::um::errhandlingapi;
+use winapi::um::winnt;
+use winapi::um::winsvc;
+
+use crate::error::{Error, Result, ERROR_SERVICE_MARKED_FOR_DELETE};
+use crate::utils::{from_wide_ptr, to_wide};
+use super::{ErrorControl, ServiceConfig, ServiceControlManager, ServiceDetails, ServiceState, StartType};
+
+/// The service control manager of the local computer
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Win32ServiceManager;
+
+impl Win32ServiceManager {
+    fn connect(&self, name: &str) -> Result<ServiceManager> {
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::all())
+            .map_err(|err| Error::from_service("connect to the service manager", name, err))
+    }
+
+    fn open(&self, name: &str, operation: &'static str) -> Result<Service> {
+        self.connect(name)?
+            .open_service(name, ServiceAccess::all())
+            .map_err(|err| Error::from_service(operation, name, err))
+    }
+}
+
+impl ServiceControlManager for Win32ServiceManager {
+    fn query(&self, name: &str) -> Result<Option<ServiceDetails>> {
+        let service = match self.open(name, "open the service") {
+            Ok(service) => service,
+            Err(Error::NotInstalled { .. }) => { return Ok(None); }
+            Err(err) => { return Err(err); }
+        };
+
+        let config = service.query_config()
+            .map_err(|err| Error::from_service("query the service config", name, err))?;
+        let status = service.query_status()
+            .map_err(|err| Error::from_service("query the service status", name, err))?;
+
+        Ok(Some(ServiceDetails {
+            binary_path: config.executable_path,
+            state: status.current_state.into(),
+            marked_for_deletion: marked_for_deletion(name)
+        }))
+    }
+
+    fn create(&self, config: &ServiceConfig) -> Result<()> {
+        let service_info = ServiceInfo {
+            name: OsString::from(&config.name),
+            display_name: OsString::from(&config.display_name),
+            service_type: ServiceType::KERNEL_DRIVER,
+            start_type: config.start_type.into(),
+            error_control: config.error_control.into(),
+            executable_path: config.binary_path.clone(),
+            launch_arguments: vec![],
+            dependencies: config.dependencies.iter()
+                .map(|name| ServiceDependency::Service(OsString::from(name)))
+                .collect(),
+            account_name: None,
+            account_password: None
+        };
+
+        self.connect(&config.name)?
+            .create_service(service_info, ServiceAccess::all())
+            .map(|_| ())
+            .map_err(|err| Error::from_service("create the service", &config.name, err))
+    }
+
+    fn start(&self, name: &str) -> Result<()> {
+        self.open(name, "open the service")?
+            .start(&[OsString::from("")])
+            .map_err(|err| Error::from_service("start the service", name, err))
+    }
+
+    fn stop(&self, name: &str) -> Result<()> {
+        self.open(name, "open the service")?
+            .stop()
+            .map(|_| ())
+            .map_err(|err| Error::from_service("stop the service", name, err))
+    }
+
+    fn delete(&self, name: &str) -> Result<()> {
+        self.open(name, "open the service")?
+            .delete()
+            .map_err(|err| Error::from_service("delete the service", name, err))
+    }
+
+    fn driver_services(&self) -> Result<Vec<String>> {
+        let last_error = || Error::from_service("list the services", "", windows_service::Error::Winapi(io::Error::last_os_error()));
+        let mut names = vec![];
+
+        unsafe {
+            let manager = winsvc::OpenSCManagerW(null(), null(), winsvc::SC_MANAGER_ENUMERATE_SERVICE);
+            if manager.is_null() {
+                return Err(last_error());
+            }
+
+            // u64 keeps the entries, which start with pointers, aligned
+            let mut buffer: Vec<u64> = vec![0; 4096];
+            let mut resume_handle: DWORD = 0;
+
+            loop {
+                let mut needed: DWORD = 0;
+                let mut returned: DWORD = 0;
+
+                let res = winsvc::EnumServicesStatusExW(
+                    manager,
+                    winsvc::SC_ENUM_PROCESS_INFO,
+                    winnt::SERVICE_KERNEL_DRIVER,
+                    winsvc::SERVICE_STATE_ALL,
+                    buffer.as_mut_ptr() as *mut u8,
+                    (buffer.len() * 8) as DWORD,
+                    &mut needed,
+                    &mut returned,
+                    &mut resume_handle,
+                    null()
+                );
+
+                if res == 0 && errhandlingapi::GetLastError() != winerror::ERROR_MORE_DATA {
+                    let err = last_error();
+                    winsvc::CloseServiceHandle(manager);
+                    return Err(err);
+                }
+
+                let entries = std::slice::from_raw_parts(
+                    buffer.as_ptr() as *const winsvc::ENUM_SERVICE_STATUS_PROCESSW,
+                    returned as usize
+                );
+                names.extend(entries.iter().map(|entry| from_wide_ptr(entry.lpServiceName)));
+
+                if res != 0 {
+                    break;
+                }
+                if needed as usize > buffer.len() * 8 {
+                    buffer.resize(needed as usize / 8 + 1, 0);
+                }
+            }
+
+            winsvc::CloseServiceHandle(manager);
+        }
+
+        Ok(names)
+    }
+}
+
+impl From<WinServiceState> for ServiceState {
+    fn from(state: WinServiceState) -> Self {
+        match state {
+            WinServiceState::Stopped => ServiceState::Stopped,
+            WinServiceState::StartPending => ServiceState::StartPending,
+            WinServiceState::StopPending => ServiceState::StopPending,
+            WinServiceState::Running => ServiceState::Running,
+            WinServiceState::ContinuePending => ServiceState::ContinuePending,
+            WinServiceState::PausePending => ServiceState::PausePending,
+            WinServiceState::Paused => ServiceState::Paused
+        }
+    }
+}
+
+impl From<StartType> for ServiceStartType {
+    fn from(start_type: StartType) -> Self {
+        match start_type {
+            StartType::Auto => ServiceStartType::AutoStart,
+            StartType::OnDemand => ServiceStartType::OnDemand,
+            StartType::Disabled => ServiceStartType::Disabled
+        }
+    }
+}
+
+impl From<ErrorControl> for ServiceErrorControl {
+    fn from(error_control: ErrorControl) -> Self {
+        match error_control {
+            ErrorControl::Ignore => ServiceErrorControl::Ignore,
+            ErrorControl::Normal => ServiceErrorControl::Normal,
+            ErrorControl::Severe => ServiceErrorControl::Severe,
+            ErrorControl::Critical => ServiceErrorControl::Critical
+        }
+    }
+}
+
+/// The SCM has no query for deleted services, but any change to one
+/// fails with `ERROR_SERVICE_MARKED_FOR_DELETE`. Changing nothing is enough
+/// to find out.
+fn marked_for_deletion(name: &str) -> bool {
+    let name = to_wide(name);
+
+    unsafe {
+        let manager = winsvc::OpenSCManagerW(null_mut(), null_mut(), winsvc::SC_MANAGER_CONNECT);
+        if manager.is_null() {
+            return false;
+        }
+
+        let service = winsvc::OpenServiceW(manager, name.as_ptr(), winsvc::SERVICE_CHANGE_CONFIG);
+        if service.is_null() {
+            winsvc::CloseServiceHandle(manager);
+            return false;
+        }
+
+        let res = winsvc::ChangeServiceConfigW(
+            service,
+            winsvc::SERVICE_NO_CHANGE,
+            winsvc::SERVICE_NO_CHANGE,
+            winsvc::SERVICE_NO_CHANGE,
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            null_mut()
+        );
+        let code = errhandlingapi::GetLastError();
+
+        winsvc::CloseServiceHandle(service);
+        winsvc::CloseServiceHandle(manager);
+
+        res == 0 && code == ERROR_SERVICE_MARKED_FOR_DELETE
+    }
+}
@@ -0,0 +1,129 @@
+//! Sharing one device handle between threads
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+use crate::driver::check_buffers;
+use crate::error::Result;
+use crate::ioctl::IoControlCode;
+use crate::pod::Pod;
+use crate::protocol::DeviceIo;
+use crate::transport::{OwnedDevice, Transport};
+
+/// Whether the IO commands of a [DriverSession] may run at the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Concurrency {
+    /// One command at a time, for drivers that keep state between commands or touch
+    /// hardware that needs several accesses per operation
+    Serialized,
+    /// Commands from different threads go to the driver at the same time
+    Concurrent
+}
+
+/// A device handle shared between clones, made by [WinKernelDriver::session](crate::WinKernelDriver::session).
+///
+/// Clones are cheap and can be sent to other threads. They all use the same handle,
+/// which is closed once the last clone is dropped; [DriverSession::handles] tells how
+/// many are left.
+///
+/// # Example
+/// ```
+/// use std::thread;
+/// use win_kernel_driver::{Concurrency, DriverBuilder, MockTransport, io_control_code, Method, Access};
+///
+/// let read_msr = io_control_code(40000, 0x821, Method::BUFFERED, Access::ANY);
+///
+/// let mock = MockTransport::new();
+/// mock.expect(read_msr).returns(&0x0064_0000u64.to_le_bytes());
+///
+/// let driver = DriverBuilder::new()
+///     .set_device_id("WinRing0_1_2_0")
+///     .set_driver_path("WinRing0x64.sys".into())
+///     .set_transport(mock.clone())
+///     .build().unwrap();
+///
+/// let session = driver.session(Concurrency::Serialized).unwrap();
+///
+/// let workers: Vec<_> = (0..4).map(|_| {
+///     let session = session.clone();
+///     thread::spawn(move || session.io(read_msr, 0x1a2).unwrap())
+/// }).collect();
+///
+/// for worker in workers {
+///     assert_eq!(worker.join().unwrap(), 0x0064_0000);
+/// }
+///
+/// assert_eq!(session.handles(), 1);
+/// drop(session);
+/// assert_eq!(mock.open_devices(), 0);
+/// ```
+#[derive(Clone)]
+pub struct DriverSession {
+    shared: Arc<Shared>
+}
+
+struct Shared {
+    device: OwnedDevice,
+    serialize: Option<Mutex<()>>
+}
+
+impl DriverSession {
+    pub(crate) fn open(transport: Arc<dyn Transport>, path: &str, concurrency: Concurrency) -> Result<Self> {
+        let serialize = match concurrency {
+            Concurrency::Serialized => Some(Mutex::new(())),
+            Concurrency::Concurrent => None
+        };
+
+        Ok(DriverSession {
+            shared: Arc::new(Shared {
+                device: OwnedDevice::open(transport, path)?,
+                serialize
+            })
+        })
+    }
+
+    /// Number of clones sharing the handle
+    pub fn handles(&self) -> usize {
+        Arc::strong_count(&self.shared)
+    }
+
+    /// Whether commands run one at a time
+    pub fn concurrency(&self) -> Concurrency {
+        match self.shared.serialize {
+            Some(_) => Concurrency::Serialized,
+            None => Concurrency::Concurrent
+        }
+    }
+
+    /// Perform an IO command, see [WinKernelDriver::io](crate::WinKernelDriver::io)
+    pub fn io<C: Into<IoControlCode>>(&self, ioctl_code: C, in_buffer: u32) -> Result<u64> {
+        let mut out_buffer = [0u8; size_of::<u64>()];
+        self.io_bytes(ioctl_code, &in_buffer.to_le_bytes(), &mut out_buffer)?;
+
+        Ok(u64::from_le_bytes(out_buffer))
+    }
+
+    /// Perform an IO command with arbitrary buffers, see
+    /// [WinKernelDriver::io_bytes](crate::WinKernelDriver::io_bytes)
+    pub fn io_bytes<C: Into<IoControlCode>>(&self, ioctl_code: C, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        let ioctl_code = ioctl_code.into();
+        check_buffers(ioctl_code, out_buffer)?;
+
+        // A panic in another thread doesn't leave the driver in a bad state
+        let _serialized = self.shared.serialize.as_ref()
+            .map(|lock| lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+
+        self.shared.device.ioctl(ioctl_code, in_buffer, out_buffer)
+    }
+
+    /// Perform an IO command with typed structures, see
+    /// [WinKernelDriver::io_typed](crate::WinKernelDriver::io_typed)
+    pub fn io_typed<C: Into<IoControlCode>, I: Pod, O: Pod>(&self, ioctl_code: C, input: &I) -> Result<O> {
+        DeviceIo::io_typed(self, ioctl_code.into(), input)
+    }
+}
+
+impl DeviceIo for DriverSession {
+    fn io_bytes(&self, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        DriverSession::io_bytes(self, ioctl_code, in_buffer, out_buffer)
+    }
+}
@@ -0,0 +1,132 @@
+//! Writing driver binaries to disk
+//!
+//! The service control manager loads drivers from a file, so a driver embedded in the
+//! program has to be written out first. Several processes may do this at the same time,
+//! so staged files are named after a hash of their content and written atomically:
+//! the bytes go to a private temporary file which is then renamed into place. A file
+//! that is already there is only reused if its hash matches.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::error::{Error, Result};
+
+/// Number of hash bytes used in staged file names
+const NAME_HASH_BYTES: usize = 8;
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// SHA-256 of `bytes`
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+/// SHA-256 of the file at `path`
+pub fn sha256_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Lower case hex representation of `bytes`
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The path a driver with the given hash is staged at
+pub fn staged_path(dir: &Path, device_id: &str, sha256: &[u8; 32]) -> PathBuf {
+    dir.join(format!("{}-{}.sys", device_id, to_hex(&sha256[..NAME_HASH_BYTES])))
+}
+
+/// Write `driver_bin` to `dir`, or reuse an identical file staged earlier.
+/// Returns the path of the staged file.
+pub fn stage(dir: &Path, device_id: &str, driver_bin: &[u8]) -> Result<PathBuf> {
+    let hash = sha256(driver_bin);
+    let path = staged_path(dir, device_id, &hash);
+
+    stage_with(&path, &hash, || Ok(driver_bin.to_vec()))
+}
+
+/// Make sure `path` holds a file hashing to `sha256`, calling `contents` for the bytes
+/// only if it has to be written
+pub(crate) fn stage_with<F>(path: &Path, sha256: &[u8; 32], contents: F) -> Result<PathBuf>
+    where F: FnOnce() -> Result<Vec<u8>>
+{
+    if matches(path, sha256) {
+        debug!(?path, "reusing staged driver");
+        return Ok(path.to_path_buf());
+    }
+
+    let staging_error = |source| Error::DriverStaging { path: path.to_path_buf(), source };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(staging_error)?;
+    }
+
+    let bytes = contents()?;
+    if self::sha256(&bytes) != *sha256 {
+        return Err(staging_error(io::Error::new(ErrorKind::InvalidData, "driver binary does not match its hash")));
+    }
+
+    let temp_path = temp_path(path);
+    let written = write_new(&temp_path, &bytes).and_then(|_| fs::rename(&temp_path, path));
+
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temp_path);
+
+        // Another process may have staged the same file in the meantime and be
+        // using it, which makes replacing it fail
+        if matches(path, sha256) {
+            return Ok(path.to_path_buf());
+        }
+        return Err(staging_error(err));
+    }
+
+    info!(?path, len = bytes.len(), "staged driver");
+    Ok(path.to_path_buf())
+}
+
+/// Delete a staged file. A file that is already gone is not an error.
+pub fn remove(path: &Path) -> Result<()> {
+    debug!(?path, "removing staged driver");
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(source) => Err(Error::DriverStaging { path: path.to_path_buf(), source })
+    }
+}
+
+fn matches(path: &Path, sha256: &[u8; 32]) -> bool {
+    match sha256_file(path) {
+        Ok(hash) => hash == *sha256,
+        Err(_) => false
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let counter = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    path.with_file_name(format!(".{}.{}.{}.tmp", name, process::id(), counter))
+}
+
+fn write_new(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
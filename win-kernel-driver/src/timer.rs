@@ -0,0 +1,80 @@
+//! A single background thread waking futures at a deadline
+//!
+//! Async IO has to work with any executor, so timeouts and scripted delays can't rely
+//! on a runtime's timer. Wakers are queued here and woken by one lazily started thread.
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock};
+use std::task::Waker;
+use std::thread;
+use std::time::Instant;
+
+#[derive(Default)]
+struct Timer {
+    queue: Mutex<Vec<(Instant, Waker)>>,
+    changed: Condvar
+}
+
+fn timer() -> &'static Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+
+    TIMER.get_or_init(|| {
+        thread::Builder::new()
+            .name("win-kernel-driver-timer".to_owned())
+            .spawn(run)
+            .expect("unable to start timer thread");
+        Timer::default()
+    })
+}
+
+impl Timer {
+    fn queue(&self) -> MutexGuard<'_, Vec<(Instant, Waker)>> {
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Wake `waker` once `deadline` has passed
+pub(crate) fn wake_at(deadline: Instant, waker: &Waker) {
+    let timer = timer();
+    let mut queue = timer.queue();
+
+    // Futures re-register on every poll, don't pile up copies
+    if queue.iter().any(|(at, queued)| *at == deadline && queued.will_wake(waker)) {
+        return;
+    }
+
+    queue.push((deadline, waker.clone()));
+    timer.changed.notify_one();
+}
+
+fn run() {
+    let timer = timer();
+    let mut queue = timer.queue();
+
+    loop {
+        let now = Instant::now();
+        let mut expired = vec![];
+        let mut index = 0;
+
+        while index < queue.len() {
+            if queue[index].0 <= now {
+                expired.push(queue.swap_remove(index).1);
+            } else {
+                index += 1;
+            }
+        }
+
+        // Wakers may poll inline and queue themselves again
+        if !expired.is_empty() {
+            drop(queue);
+            expired.into_iter().for_each(Waker::wake);
+            queue = timer.queue();
+            continue;
+        }
+
+        queue = match queue.iter().map(|(at, _)| *at).min() {
+            Some(next) => timer.changed.wait_timeout(queue, next - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner()).0,
+            None => timer.changed.wait(queue)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        };
+    }
+}
@@ -0,0 +1,348 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{
+    Error, Result, ERROR_FILE_NOT_FOUND, ERROR_INSUFFICIENT_BUFFER, ERROR_INVALID_FUNCTION, ERROR_INVALID_HANDLE,
+    ERROR_OPERATION_ABORTED
+};
+use crate::ioctl::IoControlCode;
+use crate::pod::{self, Pod};
+use crate::timer;
+use super::{OpenOptions, PendingIo, RawDevice, Transport};
+
+/// An in-memory, scriptable [Transport].
+///
+/// Each [MockTransport::expect] call adds an expectation: an IO control code, optionally
+/// the exact input, and the output or Win32 error to answer with. Requests are matched
+/// against the expectations in the order they were added. Requests nobody expected fail
+/// with `ERROR_INVALID_FUNCTION`, like a driver that doesn't know the code.
+///
+/// Clones share the same script and call log, so keep a clone around to inspect the
+/// calls after handing the transport to a [DriverBuilder](crate::DriverBuilder).
+///
+/// Answers can be held back with [Expectation::delay] to act like a slow device.
+/// Requests started with [Transport::submit] then stay pending until the delay has
+/// passed, and can be cancelled in the meantime.
+///
+/// # Example
+/// ```
+/// use win_kernel_driver::{DriverBuilder, MockTransport, io_control_code, Method, Access};
+///
+/// let read_msr = io_control_code(40000, 0x821, Method::BUFFERED, Access::ANY);
+///
+/// let mock = MockTransport::new();
+/// mock.expect(read_msr)
+///     .with_input(&0x1a2u32.to_le_bytes())
+///     .returns(&0x0064_0000_0000_0000u64.to_le_bytes());
+///
+/// let mut driver = DriverBuilder::new()
+///     .set_device_id("WinRing0_1_2_0")
+///     .set_driver_path("WinRing0x64.sys".into())
+///     .set_transport(mock.clone())
+///     .build().unwrap();
+///
+/// driver.open().unwrap();
+/// assert_eq!(driver.io(read_msr, 0x1a2).unwrap(), 0x0064_0000_0000_0000);
+/// assert_eq!(mock.calls().len(), 1);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>
+}
+
+/// A request received by a [MockTransport]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockCall {
+    pub ioctl_code: IoControlCode,
+    pub input: Vec<u8>
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    expectations: Vec<Rule>,
+    calls: Vec<MockCall>,
+    /// Win32 error for the next opens, and how many of them fail (`None` for all)
+    open_error: Option<(u32, Option<usize>)>,
+    next_device: usize,
+    open_devices: Vec<RawDevice>,
+    opened_paths: Vec<String>,
+    open_options: Vec<OpenOptions>
+}
+
+#[derive(Debug)]
+struct Rule {
+    ioctl_code: IoControlCode,
+    input: Option<Vec<u8>>,
+    remaining: Option<usize>,
+    delay: Duration,
+    response: std::result::Result<Vec<u8>, u32>
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start an expectation for `ioctl_code`. It is registered once
+    /// [Expectation::returns] or [Expectation::fails] is called.
+    pub fn expect<C: Into<IoControlCode>>(&self, ioctl_code: C) -> Expectation<'_> {
+        Expectation {
+            mock: self,
+            ioctl_code: ioctl_code.into(),
+            input: None,
+            times: None,
+            delay: Duration::ZERO
+        }
+    }
+
+    /// Make every following [Transport::open] fail with the Win32 error `code`,
+    /// e.g. `ERROR_FILE_NOT_FOUND` (2) for a device that hasn't appeared yet.
+    /// `None` lets opens succeed again.
+    pub fn fail_open(&self, code: Option<u32>) {
+        self.lock().open_error = code.map(|code| (code, None));
+    }
+
+    /// Make only the next `count` opens fail with the Win32 error `code`, like a device
+    /// whose symbolic link shows up a little after its service started
+    pub fn fail_opens(&self, code: u32, count: usize) {
+        self.lock().open_error = if count > 0 { Some((code, Some(count))) } else { None };
+    }
+
+    /// Every request received so far, in order
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.lock().calls.clone()
+    }
+
+    /// The paths passed to [Transport::open], in order
+    pub fn opened_paths(&self) -> Vec<String> {
+        self.lock().opened_paths.clone()
+    }
+
+    /// The options passed to every open, in order
+    pub fn open_options(&self) -> Vec<OpenOptions> {
+        self.lock().open_options.clone()
+    }
+
+    /// Number of devices opened and not closed yet
+    pub fn open_devices(&self) -> usize {
+        self.lock().open_devices.len()
+    }
+
+    /// True once every expectation with a [Expectation::times] count has been used up
+    pub fn satisfied(&self) -> bool {
+        self.lock().expectations.iter().all(|rule| rule.remaining.is_none_or(|n| n == 0))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Log a request and find its scripted answer and delay
+    fn respond(&self, device: RawDevice, ioctl_code: IoControlCode, in_buffer: &[u8]) -> Result<(std::result::Result<Vec<u8>, u32>, Duration)> {
+        let mut state = self.lock();
+
+        if !state.open_devices.contains(&device) {
+            return Err(Error::IoctlFailed { ioctl: ioctl_code, code: ERROR_INVALID_HANDLE });
+        }
+
+        state.calls.push(MockCall { ioctl_code, input: in_buffer.to_vec() });
+
+        let rule = state.expectations.iter_mut().find(|rule| {
+            rule.ioctl_code == ioctl_code
+                && rule.remaining != Some(0)
+                && rule.input.as_ref().is_none_or(|input| input.as_slice() == in_buffer)
+        });
+
+        let rule = match rule {
+            Some(rule) => rule,
+            None => { return Err(Error::IoctlFailed { ioctl: ioctl_code, code: ERROR_INVALID_FUNCTION }); }
+        };
+
+        if let Some(remaining) = rule.remaining.as_mut() {
+            *remaining -= 1;
+        }
+
+        Ok((rule.response.clone(), rule.delay))
+    }
+}
+
+/// Turn a scripted answer into what the driver would have returned for an output
+/// buffer of `output_len` bytes
+fn complete(ioctl_code: IoControlCode, response: std::result::Result<Vec<u8>, u32>, output_len: usize) -> Result<Vec<u8>> {
+    match response {
+        Ok(output) if output.len() > output_len => {
+            Err(Error::IoctlFailed { ioctl: ioctl_code, code: ERROR_INSUFFICIENT_BUFFER })
+        },
+        Ok(output) => Ok(output),
+        Err(code) => Err(Error::IoctlFailed { ioctl: ioctl_code, code })
+    }
+}
+
+/// A pending expectation on a [MockTransport], see [MockTransport::expect]
+#[must_use = "expectations are only registered by returns() or fails()"]
+pub struct Expectation<'a> {
+    mock: &'a MockTransport,
+    ioctl_code: IoControlCode,
+    input: Option<Vec<u8>>,
+    times: Option<usize>,
+    delay: Duration
+}
+
+impl<'a> Expectation<'a> {
+    /// Only match requests with exactly this input
+    pub fn with_input(mut self, input: &[u8]) -> Self {
+        self.input = Some(input.to_vec());
+        self
+    }
+
+    /// Only match requests with exactly this typed input
+    pub fn with_typed_input<T: Pod>(self, input: &T) -> Self {
+        self.with_input(pod::bytes_of(input))
+    }
+
+    /// Only answer `count` requests, after which the next matching expectation is used
+    pub fn times(mut self, count: usize) -> Self {
+        self.times = Some(count);
+        self
+    }
+
+    /// Take `delay` to answer matching requests. Blocking calls sleep for it.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Answer matching requests with `output`
+    pub fn returns(self, output: &[u8]) {
+        self.register(Ok(output.to_vec()));
+    }
+
+    /// Answer matching requests with a typed output
+    pub fn returns_typed<T: Pod>(self, output: &T) {
+        self.register(Ok(pod::bytes_of(output).to_vec()));
+    }
+
+    /// Fail matching requests with the Win32 error `code`
+    pub fn fails(self, code: u32) {
+        self.register(Err(code));
+    }
+
+    fn register(self, response: std::result::Result<Vec<u8>, u32>) {
+        self.mock.lock().expectations.push(Rule {
+            ioctl_code: self.ioctl_code,
+            input: self.input,
+            remaining: self.times,
+            delay: self.delay,
+            response
+        });
+    }
+}
+
+impl Transport for MockTransport {
+    fn open(&self, path: &str) -> Result<RawDevice> {
+        self.open_with(path, &OpenOptions::new())
+    }
+
+    fn open_overlapped(&self, path: &str) -> Result<RawDevice> {
+        self.open_with(path, &OpenOptions::new().set_overlapped(true))
+    }
+
+    fn open_with(&self, path: &str, options: &OpenOptions) -> Result<RawDevice> {
+        let mut state = self.lock();
+        state.opened_paths.push(path.to_owned());
+        state.open_options.push(*options);
+
+        if let Some((code, remaining)) = state.open_error {
+            if let Some(remaining) = remaining {
+                state.open_error = if remaining > 1 { Some((code, Some(remaining - 1))) } else { None };
+            }
+            return Err(Error::OpenFailed { path: path.to_owned(), code });
+        }
+        if path.is_empty() {
+            return Err(Error::OpenFailed { path: path.to_owned(), code: ERROR_FILE_NOT_FOUND });
+        }
+
+        state.next_device += 1;
+        let device = RawDevice(state.next_device);
+        state.open_devices.push(device);
+
+        Ok(device)
+    }
+
+    fn ioctl(&self, device: RawDevice, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        let (response, delay) = self.respond(device, ioctl_code, in_buffer)?;
+
+        if delay > Duration::ZERO {
+            thread::sleep(delay);
+        }
+
+        let output = complete(ioctl_code, response, out_buffer.len())?;
+        out_buffer[..output.len()].copy_from_slice(&output);
+
+        Ok(output.len())
+    }
+
+    fn submit(&self, device: RawDevice, ioctl_code: IoControlCode, input: Vec<u8>, output_len: usize) -> Result<Arc<dyn PendingIo>> {
+        let (response, delay) = self.respond(device, ioctl_code, &input)?;
+
+        Ok(Arc::new(MockIo {
+            ioctl_code,
+            ready_at: Instant::now() + delay,
+            result: Mutex::new(Some(complete(ioctl_code, response, output_len))),
+            cancelled: AtomicBool::new(false),
+            waker: Mutex::new(None)
+        }))
+    }
+
+    fn close(&self, device: RawDevice) -> Result<()> {
+        let mut state = self.lock();
+        match state.open_devices.iter().position(|d| *d == device) {
+            Some(index) => {
+                state.open_devices.remove(index);
+                Ok(())
+            },
+            None => Err(Error::NotOpen)
+        }
+    }
+}
+
+/// A request submitted to a [MockTransport], completing once its delay has passed
+struct MockIo {
+    ioctl_code: IoControlCode,
+    ready_at: Instant,
+    result: Mutex<Option<Result<Vec<u8>>>>,
+    cancelled: AtomicBool,
+    waker: Mutex<Option<Waker>>
+}
+
+impl PendingIo for MockIo {
+    fn poll(&self, cx: &mut Context<'_>) -> Poll<Result<Vec<u8>>> {
+        *self.waker.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(cx.waker().clone());
+
+        let result = if self.cancelled.load(Ordering::SeqCst) {
+            Err(Error::IoctlFailed { ioctl: self.ioctl_code, code: ERROR_OPERATION_ABORTED })
+        } else if Instant::now() >= self.ready_at {
+            self.result.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take()
+                .expect("request polled after completion")
+        } else {
+            timer::wake_at(self.ready_at, cx.waker());
+            return Poll::Pending;
+        };
+
+        Poll::Ready(result)
+    }
+
+    fn cancel(&self) {
+        // Like a driver, only requests that are still in flight can be cancelled
+        if Instant::now() < self.ready_at {
+            self.cancelled.store(true, Ordering::SeqCst);
+
+            if let Some(waker) = self.waker.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take() {
+                waker.wake();
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Device transports
+//! 
+//! A [Transport] is what [WinKernelDriver](crate::WinKernelDriver) uses to open the
+//! device, send IO control codes to it and close it again. [Win32Transport] talks to a
+//! real driver. [MockTransport] answers from a script so code built on top of a driver
+//! can be exercised without one.
+//! 
+//! Requests can also be started without waiting for them with [Transport::submit],
+//! which is what [AsyncDriver](crate::AsyncDriver) is built on.
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use tracing::{debug, trace};
+
+use crate::error::Result;
+use crate::ioctl::IoControlCode;
+use crate::protocol::DeviceIo;
+
+#[cfg(windows)]
+mod win32;
+#[cfg(not(windows))]
+mod unsupported;
+mod mock;
+
+#[cfg(windows)]
+pub use win32::Win32Transport;
+#[cfg(not(windows))]
+pub use unsupported::Win32Transport;
+pub use mock::MockTransport;
+pub use mock::Expectation;
+pub use mock::MockCall;
+
+/// An open device, as handed out by [Transport::open].
+/// 
+/// The value is only meaningful to the transport that created it. For
+/// [Win32Transport] it is the `HANDLE` returned by `CreateFile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RawDevice(pub usize);
+
+/// How a device is opened, see [Transport::open_with]
+/// 
+/// The default asks for read and write access, shares nothing, and opens the device
+/// for blocking IO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    /// Let others open the device for reading while it is open
+    pub share_read: bool,
+    /// Let others open the device for writing while it is open
+    pub share_write: bool,
+    /// Open for overlapped IO with [Transport::submit]
+    pub overlapped: bool
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        OpenOptions {
+            read: true,
+            write: true,
+            share_read: false,
+            share_write: false,
+            overlapped: false
+        }
+    }
+
+    /// Read access only. Enough for commands declared with `FILE_READ_ACCESS` or
+    /// `FILE_ANY_ACCESS`.
+    pub fn read_only() -> Self {
+        Self::new().set_write(false)
+    }
+
+    pub fn set_read(mut self, read: bool) -> Self {
+        self.read = read;
+        self
+    }
+
+    pub fn set_write(mut self, write: bool) -> Self {
+        self.write = write;
+        self
+    }
+
+    /// Share read and write access with other handles
+    pub fn set_shared(mut self, shared: bool) -> Self {
+        self.share_read = shared;
+        self.share_write = shared;
+        self
+    }
+
+    pub fn set_share_read(mut self, share_read: bool) -> Self {
+        self.share_read = share_read;
+        self
+    }
+
+    pub fn set_share_write(mut self, share_write: bool) -> Self {
+        self.share_write = share_write;
+        self
+    }
+
+    pub fn set_overlapped(mut self, overlapped: bool) -> Self {
+        self.overlapped = overlapped;
+        self
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A device opened through a [Transport], closed again when dropped
+pub struct OwnedDevice {
+    transport: Arc<dyn Transport>,
+    device: RawDevice,
+    closed: bool
+}
+
+impl OwnedDevice {
+    /// Open the device at `path` with [Transport::open]
+    pub fn open(transport: Arc<dyn Transport>, path: &str) -> Result<Self> {
+        Self::open_with(transport, path, &OpenOptions::new())
+    }
+
+    /// Open the device at `path` with [Transport::open_overlapped]
+    pub fn open_overlapped(transport: Arc<dyn Transport>, path: &str) -> Result<Self> {
+        Self::open_with(transport, path, &OpenOptions::new().set_overlapped(true))
+    }
+
+    /// Open the device at `path` with [Transport::open_with]
+    pub fn open_with(transport: Arc<dyn Transport>, path: &str, options: &OpenOptions) -> Result<Self> {
+        match transport.open_with(path, options) {
+            Ok(device) => {
+                debug!(path, ?options, ?device, "device opened");
+                Ok(OwnedDevice { transport, device, closed: false })
+            },
+            Err(err) => {
+                debug!(path, ?options, error = %err, "device open failed");
+                Err(err)
+            }
+        }
+    }
+
+    /// Send `ioctl_code` to the device, see [Transport::ioctl]. Every command is
+    /// traced at `TRACE` level, failures at `DEBUG`.
+    pub fn ioctl(&self, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.transport.ioctl(self.device, ioctl_code, in_buffer, out_buffer);
+        let elapsed_us = start.elapsed().as_micros() as u64;
+
+        match &result {
+            Ok(written) => trace!(
+                ioctl = %ioctl_code, input_len = in_buffer.len(), output_len = out_buffer.len(),
+                written, elapsed_us, "ioctl"
+            ),
+            Err(err) => debug!(
+                ioctl = %ioctl_code, input_len = in_buffer.len(), output_len = out_buffer.len(),
+                elapsed_us, error = %err, "ioctl failed"
+            )
+        }
+
+        result
+    }
+
+    /// The transport's handle to the device. It stays owned by this value.
+    pub fn raw(&self) -> RawDevice {
+        self.device
+    }
+
+    /// The transport the device was opened with
+    pub fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    /// Close the device, reporting errors dropping it would ignore
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        debug!(device = ?self.device, "device closed");
+        self.transport.close(self.device)
+    }
+}
+
+impl DeviceIo for OwnedDevice {
+    fn io_bytes(&self, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        self.ioctl(ioctl_code, in_buffer, out_buffer)
+    }
+}
+
+impl Drop for OwnedDevice {
+    fn drop(&mut self) {
+        if !self.closed {
+            debug!(device = ?self.device, "device closed on drop");
+            let _ = self.transport.close(self.device);
+        }
+    }
+}
+
+impl fmt::Debug for OwnedDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OwnedDevice").field(&self.device).finish()
+    }
+}
+
+/// The open / io / close path to a device
+pub trait Transport: Send + Sync {
+    /// Open the device at `path` (e.g. `\\.\WinRing0_1_2_0`)
+    fn open(&self, path: &str) -> Result<RawDevice>;
+
+    /// Send `ioctl_code` to an open device. Returns the number of bytes written
+    /// to `out_buffer`.
+    fn ioctl(&self, device: RawDevice, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize>;
+
+    /// Close a device returned by [Transport::open] or [Transport::open_overlapped]
+    fn close(&self, device: RawDevice) -> Result<()>;
+
+    /// Open the device at `path` for use with [Transport::submit].
+    /// Defaults to [Transport::open].
+    fn open_overlapped(&self, path: &str) -> Result<RawDevice> {
+        self.open(path)
+    }
+
+    /// Open the device at `path` with explicit access and sharing.
+    /// 
+    /// Defaults to [Transport::open] or [Transport::open_overlapped], for transports
+    /// that have no notion of access modes.
+    fn open_with(&self, path: &str, options: &OpenOptions) -> Result<RawDevice> {
+        if options.overlapped {
+            self.open_overlapped(path)
+        } else {
+            self.open(path)
+        }
+    }
+
+    /// Start `ioctl_code` on a device returned by [Transport::open_overlapped] without
+    /// waiting for it to complete. The driver may write up to `output_len` bytes.
+    /// 
+    /// The default runs [Transport::ioctl] to completion and hands back the finished
+    /// request.
+    fn submit(&self, device: RawDevice, ioctl_code: IoControlCode, input: Vec<u8>, output_len: usize) -> Result<Arc<dyn PendingIo>> {
+        let mut output = vec![0u8; output_len];
+        let result = self.ioctl(device, ioctl_code, &input, &mut output).map(|written| {
+            output.truncate(written);
+            output
+        });
+
+        Ok(Arc::new(CompletedIo(Mutex::new(Some(result)))))
+    }
+}
+
+/// A request started by [Transport::submit]
+pub trait PendingIo: Send + Sync {
+    /// Check whether the request has completed, returning the bytes the driver wrote.
+    /// Until then `cx` is woken when it is worth polling again.
+    /// 
+    /// Only polled until it returns [Poll::Ready].
+    fn poll(&self, cx: &mut Context<'_>) -> Poll<Result<Vec<u8>>>;
+
+    /// Ask the driver to abandon the request. It still has to be polled to completion,
+    /// which usually fails with `ERROR_OPERATION_ABORTED` (995) unless the driver
+    /// finished first.
+    fn cancel(&self);
+}
+
+/// A request that already completed when it was submitted
+struct CompletedIo(Mutex<Option<Result<Vec<u8>>>>);
+
+impl PendingIo for CompletedIo {
+    fn poll(&self, _cx: &mut Context<'_>) -> Poll<Result<Vec<u8>>> {
+        let result = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        Poll::Ready(result.expect("request polled after completion"))
+    }
+
+    fn cancel(&self) { }
+}
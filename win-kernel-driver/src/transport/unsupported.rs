@@ -0,0 +1,23 @@
+use crate::error::{Error, Result};
+use crate::ioctl::IoControlCode;
+use super::{RawDevice, Transport};
+
+/// Stands in for the Win32 transport on other platforms, where there are no kernel
+/// drivers to talk to. Every operation fails with [Error::UnsupportedPlatform];
+/// use a [MockTransport](crate::MockTransport) instead.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Win32Transport;
+
+impl Transport for Win32Transport {
+    fn open(&self, _path: &str) -> Result<RawDevice> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn ioctl(&self, _device: RawDevice, _ioctl_code: IoControlCode, _in_buffer: &[u8], _out_buffer: &mut [u8]) -> Result<usize> {
+        Err(Error::UnsupportedPlatform)
+    }
+
+    fn close(&self, _device: RawDevice) -> Result<()> {
+        Err(Error::UnsupportedPlatform)
+    }
+}
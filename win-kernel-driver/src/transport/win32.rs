@@ -0,0 +1,293 @@
+use std::convert::TryFrom;
+use std::ffi::{c_void, CString};
+use std::mem;
+use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use winapi::shared::minwindef::{BOOLEAN, DWORD, FALSE, TRUE};
+use winapi::shared::winerror;
+use winapi::um::errhandlingapi;
+use winapi::um::fileapi;
+use winapi::um::handleapi;
+use winapi::um::ioapiset;
+use winapi::um::minwinbase::OVERLAPPED;
+use winapi::um::synchapi;
+use winapi::um::threadpoollegacyapiset;
+use winapi::um::winbase;
+use winapi::um::winnt;
+
+use crate::error::{Error, Result, ERROR_ACCESS_DENIED, ERROR_INVALID_NAME};
+use crate::ioctl::IoControlCode;
+use super::{OpenOptions, PendingIo, RawDevice, Transport};
+
+/// Talks to a device through `CreateFileA`, `DeviceIoControl` and `CloseHandle`.
+/// This is the transport used unless another one is set on the
+/// [DriverBuilder](crate::DriverBuilder).
+/// 
+/// Submitted requests use overlapped IO: completion signals an event, and a thread
+/// pool wait on that event wakes the future.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Win32Transport;
+
+impl Win32Transport {
+    fn create_file(&self, path: &str, options: &OpenOptions) -> Result<RawDevice> {
+        let c_path = CString::new(path)
+            .map_err(|_| Error::OpenFailed { path: path.to_owned(), code: ERROR_INVALID_NAME })?;
+
+        let mut access = 0;
+        if options.read { access |= winnt::GENERIC_READ; }
+        if options.write { access |= winnt::GENERIC_WRITE; }
+
+        let mut share_mode = 0;
+        if options.share_read { share_mode |= winnt::FILE_SHARE_READ; }
+        if options.share_write { share_mode |= winnt::FILE_SHARE_WRITE; }
+
+        let flags = if options.overlapped { winbase::FILE_FLAG_OVERLAPPED } else { 0 };
+
+        unsafe {
+            let device: winnt::HANDLE = fileapi::CreateFileA(
+                c_path.as_ptr(),
+                access,
+                share_mode,
+                null_mut(),
+                fileapi::OPEN_EXISTING,
+                winnt::FILE_ATTRIBUTE_NORMAL | flags,
+                null_mut()
+            );
+
+            if device == handleapi::INVALID_HANDLE_VALUE {
+                let code = errhandlingapi::GetLastError();
+                if code == ERROR_ACCESS_DENIED {
+                    return Err(Error::AccessDenied {
+                        operation: "open the device",
+                        source: std::io::Error::from_raw_os_error(code as i32)
+                    });
+                }
+                return Err(Error::OpenFailed { path: path.to_owned(), code });
+            }
+
+            Ok(RawDevice(device as usize))
+        }
+    }
+}
+
+impl Transport for Win32Transport {
+    fn open(&self, path: &str) -> Result<RawDevice> {
+        self.create_file(path, &OpenOptions::new())
+    }
+
+    fn ioctl(&self, device: RawDevice, ioctl_code: IoControlCode, in_buffer: &[u8], out_buffer: &mut [u8]) -> Result<usize> {
+        let in_buffer_size = DWORD::try_from(in_buffer.len())
+            .map_err(|_| Error::InvalidBuffer { ioctl: ioctl_code, reason: "input buffer larger than 4GB" })?;
+        let out_buffer_size = DWORD::try_from(out_buffer.len())
+            .map_err(|_| Error::InvalidBuffer { ioctl: ioctl_code, reason: "output buffer larger than 4GB" })?;
+
+        let in_buffer_ptr = if in_buffer.is_empty() { null_mut() } else { in_buffer.as_ptr() as *mut c_void };
+        let out_buffer_ptr = if out_buffer.is_empty() { null_mut() } else { out_buffer.as_mut_ptr() as *mut c_void };
+
+        let mut out_buffer_written: DWORD = 0;
+
+        unsafe {
+            let res = ioapiset::DeviceIoControl(
+                device.0 as winnt::HANDLE,
+                ioctl_code.raw(),
+                in_buffer_ptr,
+                in_buffer_size,
+                out_buffer_ptr,
+                out_buffer_size,
+                &mut out_buffer_written,
+                null_mut()
+            );
+
+            if res != 0 {
+                Ok(out_buffer_written as usize)
+            } else {
+                let last_error = errhandlingapi::GetLastError();
+                Err(Error::IoctlFailed { ioctl: ioctl_code, code: last_error })
+            }
+        }
+    }
+
+    fn close(&self, device: RawDevice) -> Result<()> {
+        unsafe {
+            handleapi::CloseHandle(device.0 as winnt::HANDLE);
+        }
+
+        Ok(())
+    }
+
+    fn open_overlapped(&self, path: &str) -> Result<RawDevice> {
+        self.create_file(path, &OpenOptions::new().set_overlapped(true))
+    }
+
+    fn open_with(&self, path: &str, options: &OpenOptions) -> Result<RawDevice> {
+        self.create_file(path, options)
+    }
+
+    fn submit(&self, device: RawDevice, ioctl_code: IoControlCode, input: Vec<u8>, output_len: usize) -> Result<Arc<dyn PendingIo>> {
+        let in_buffer_size = DWORD::try_from(input.len())
+            .map_err(|_| Error::InvalidBuffer { ioctl: ioctl_code, reason: "input buffer larger than 4GB" })?;
+        let out_buffer_size = DWORD::try_from(output_len)
+            .map_err(|_| Error::InvalidBuffer { ioctl: ioctl_code, reason: "output buffer larger than 4GB" })?;
+
+        let event = unsafe { synchapi::CreateEventW(null_mut(), TRUE, FALSE, null_mut()) };
+        if event.is_null() {
+            return Err(Error::IoctlFailed { ioctl: ioctl_code, code: unsafe { errhandlingapi::GetLastError() } });
+        }
+
+        // The OVERLAPPED and both buffers belong to the driver until the request
+        // completes, so they live on the heap and are only freed after that
+        let mut overlapped: Box<OVERLAPPED> = Box::new(unsafe { mem::zeroed() });
+        overlapped.hEvent = event;
+
+        let mut io = OverlappedIo {
+            device: device.0,
+            ioctl_code,
+            state: Mutex::new(IoState {
+                overlapped,
+                input,
+                output: vec![0u8; output_len],
+                finished: false
+            }),
+            waker: Box::new(Mutex::new(None)),
+            wait: 0
+        };
+
+        let started = {
+            let state = io.state.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let in_buffer_ptr = if state.input.is_empty() { null_mut() } else { state.input.as_mut_ptr() as *mut c_void };
+            let out_buffer_ptr = if state.output.is_empty() { null_mut() } else { state.output.as_mut_ptr() as *mut c_void };
+
+            unsafe {
+                let res = ioapiset::DeviceIoControl(
+                    device.0 as winnt::HANDLE,
+                    ioctl_code.raw(),
+                    in_buffer_ptr,
+                    in_buffer_size,
+                    out_buffer_ptr,
+                    out_buffer_size,
+                    null_mut(),
+                    &mut *state.overlapped
+                );
+
+                match errhandlingapi::GetLastError() {
+                    _ if res != 0 => Ok(()),
+                    winerror::ERROR_IO_PENDING => Ok(()),
+                    code => {
+                        state.finished = true;
+                        Err(Error::IoctlFailed { ioctl: ioctl_code, code })
+                    }
+                }
+            }
+        };
+        started?;
+
+        let mut wait: winnt::HANDLE = null_mut();
+        let registered = unsafe {
+            winbase::RegisterWaitForSingleObject(
+                &mut wait,
+                event,
+                Some(wake_on_completion),
+                &*io.waker as *const Mutex<Option<Waker>> as *mut c_void,
+                winbase::INFINITE,
+                winnt::WT_EXECUTEONLYONCE
+            )
+        };
+
+        if registered == 0 {
+            return Err(Error::IoctlFailed { ioctl: ioctl_code, code: unsafe { errhandlingapi::GetLastError() } });
+        }
+        io.wait = wait as usize;
+
+        Ok(Arc::new(io))
+    }
+}
+
+/// An overlapped `DeviceIoControl` in flight
+struct OverlappedIo {
+    device: usize,
+    ioctl_code: IoControlCode,
+    state: Mutex<IoState>,
+    /// Woken from the thread pool once the event is signaled. Boxed so its address
+    /// stays valid for the wait callback.
+    waker: Box<Mutex<Option<Waker>>>,
+    wait: usize
+}
+
+struct IoState {
+    overlapped: Box<OVERLAPPED>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    finished: bool
+}
+
+// The raw pointers inside OVERLAPPED are only touched behind the mutex
+unsafe impl Send for IoState {}
+
+unsafe extern "system" fn wake_on_completion(context: *mut c_void, _timed_out: BOOLEAN) {
+    let waker = &*(context as *const Mutex<Option<Waker>>);
+    let waker = waker.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl PendingIo for OverlappedIo {
+    fn poll(&self, cx: &mut Context<'_>) -> Poll<Result<Vec<u8>>> {
+        // Register before checking, so a completion in between still wakes us
+        *self.waker.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(cx.waker().clone());
+
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut written: DWORD = 0;
+
+        unsafe {
+            let res = ioapiset::GetOverlappedResult(self.device as winnt::HANDLE, &mut *state.overlapped, &mut written, FALSE);
+
+            if res == 0 {
+                let code = errhandlingapi::GetLastError();
+                if code == winerror::ERROR_IO_INCOMPLETE {
+                    return Poll::Pending;
+                }
+
+                state.finished = true;
+                return Poll::Ready(Err(Error::IoctlFailed { ioctl: self.ioctl_code, code }));
+            }
+        }
+
+        state.finished = true;
+        let mut output = mem::take(&mut state.output);
+        output.truncate(written as usize);
+
+        Poll::Ready(Ok(output))
+    }
+
+    fn cancel(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if !state.finished {
+            unsafe { ioapiset::CancelIoEx(self.device as winnt::HANDLE, &mut *state.overlapped) };
+        }
+    }
+}
+
+impl Drop for OverlappedIo {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        unsafe {
+            // Dropped while in flight: the driver may still write to the buffers
+            if !state.finished {
+                let mut written: DWORD = 0;
+                ioapiset::CancelIoEx(self.device as winnt::HANDLE, &mut *state.overlapped);
+                ioapiset::GetOverlappedResult(self.device as winnt::HANDLE, &mut *state.overlapped, &mut written, TRUE);
+            }
+
+            if self.wait != 0 {
+                threadpoollegacyapiset::UnregisterWaitEx(self.wait as winnt::HANDLE, handleapi::INVALID_HANDLE_VALUE);
+            }
+            handleapi::CloseHandle(state.overlapped.hEvent);
+        }
+    }
+}
@@ -1,8 +1,22 @@
-use std::{ffi::OsStr};
-use std::os::windows::ffi::OsStrExt;
+use std::{ffi::OsStr, ffi::OsString};
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
 use std::iter::once;
 
 pub fn to_wide(msg:&str) -> Vec<u16> {
     let wide: Vec<u16> = OsStr::new(msg).encode_wide().chain(once(0)).collect();
     wide
 }
+
+/// Read a NUL terminated wide string
+/// 
+/// # Safety
+/// 
+/// `ptr` must point to a NUL terminated UTF-16 string
+pub unsafe fn from_wide_ptr(ptr: *const u16) -> String {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+
+    OsString::from_wide(std::slice::from_raw_parts(ptr, len)).to_string_lossy().into_owned()
+}
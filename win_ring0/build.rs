@@ -0,0 +1,12 @@
+//! Compresses the winRing0 drivers, see [win_kernel_driver::embed_driver]
+use std::env;
+use std::path::{Path, PathBuf};
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+
+    for driver in &["WinRing0.sys", "WinRing0x64.sys"] {
+        win_kernel_driver::embed_driver(Path::new(driver), &out_dir)
+            .unwrap_or_else(|err| panic!("Unable to embed {}: {}", driver, err));
+    }
+}
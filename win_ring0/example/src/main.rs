@@ -1,11 +1,11 @@
 use win_ring0::WinRing0;
 
 pub fn main() {
-    let mut r0: Box<WinRing0> = Box::from(WinRing0::new());
+    let mut r0: Box<WinRing0> = Box::from(WinRing0::new().unwrap());
 
     println!("Installing ring0 driver");
     match r0.install() {
-        Ok(()) => { println!("Driver installed"); }
+        Ok(_) => { println!("Driver installed"); }
         Err(err) => { println!("Error: {}", err); }
     }
 
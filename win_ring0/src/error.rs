@@ -0,0 +1,51 @@
+//! Errors returned by [WinRing0](crate::WinRing0)
+// err-derive generates its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
+use err_derive::Error;
+
+use crate::pci::PciAddress;
+
+/// Errors that can occur while using the winRing0 driver
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Installing, opening or talking to the driver failed
+    #[error(display = "winRing0 driver error")]
+    Driver(#[error(cause)] win_kernel_driver::Error),
+
+    /// The driver caught a fault accessing the MSR, usually because the
+    /// register does not exist on this CPU. `code` is the Win32 error code.
+    #[error(display = "Fault accessing msr {:#x}. Last error code: {:#x}", msr, code)]
+    MsrFault { msr: u32, code: u32 },
+
+    /// The device or function number of a PCI address is out of range
+    #[error(display = "Invalid PCI address {:?}", _0)]
+    InvalidPciAddress(PciAddress),
+
+    /// A PCI configuration space access is not aligned to its size, or goes past the
+    /// end of the configuration space
+    #[error(display = "Invalid {} byte access at offset {:#x} of PCI device {}", size, offset, address)]
+    InvalidPciOffset { address: PciAddress, offset: u16, size: usize },
+
+    /// There is no device at this PCI address, its vendor id reads as all ones
+    #[error(display = "No PCI device at {}", _0)]
+    PciDeviceNotFound(PciAddress),
+
+    /// The driver could not access the PCI configuration space, for example because
+    /// the bus does not exist. `code` is the Win32 error code.
+    #[error(display = "Fault accessing offset {:#x} of PCI device {}. Last error code: {:#x}", offset, address, code)]
+    PciConfigFault { address: PciAddress, offset: u16, code: u32 },
+
+    /// A PCI id database is not in `pci.ids` format
+    #[error(display = "Invalid pci.ids database on line {}: {}", line, reason)]
+    ParsePciIds { line: usize, reason: &'static str }
+}
+
+impl From<win_kernel_driver::Error> for Error {
+    fn from(err: win_kernel_driver::Error) -> Self {
+        Error::Driver(err)
+    }
+}
+
+/// Result type used throughout this crate
+pub type Result<T> = std::result::Result<T, Error>;
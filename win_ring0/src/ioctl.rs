@@ -5,6 +5,9 @@
 use win_kernel_driver::io_control_code;
 use win_kernel_driver::Method;
 use win_kernel_driver::Access;
+use win_kernel_driver::IoControlCode;
+
+use super::protocol;
 
 
 /// The device type is defined by the winRing0 driver. For more information see
@@ -12,25 +15,39 @@ use win_kernel_driver::Access;
 pub const DEVICE_TYPE: u32 = 40000;
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u32)]
 pub enum IOCTL {
-    OLS_GET_DRIVER_VERSION = io_control_code(DEVICE_TYPE, 0x800, Method::BUFFERED, Access::ANY),
-    OLS_GET_REFCOUNT = io_control_code(DEVICE_TYPE, 0x801, Method::BUFFERED, Access::ANY),
-    OLS_READ_MSR = io_control_code(DEVICE_TYPE, 0x821, Method::BUFFERED, Access::ANY),
-    OLS_WRITE_MSR = io_control_code(DEVICE_TYPE, 0x822, Method::BUFFERED, Access::ANY),
-    OLS_READ_PMC = io_control_code(DEVICE_TYPE, 0x823, Method::BUFFERED, Access::ANY),
-    OLS_HALT = io_control_code(DEVICE_TYPE, 0x824, Method::BUFFERED, Access::ANY),
+    OLS_GET_DRIVER_VERSION = protocol::OLS_GET_DRIVER_VERSION.raw(),
+    OLS_GET_REFCOUNT = protocol::OLS_GET_REFCOUNT.raw(),
+    OLS_READ_MSR = protocol::OLS_READ_MSR.raw(),
+    OLS_WRITE_MSR = protocol::OLS_WRITE_MSR.raw(),
+    OLS_READ_PMC = protocol::OLS_READ_PMC.raw(),
+    OLS_HALT = protocol::OLS_HALT.raw(),
     OLS_READ_IO_PORT =  io_control_code(DEVICE_TYPE, 0x831, Method::BUFFERED, Access::READ),
     OLS_WRITE_IO_PORT = io_control_code(DEVICE_TYPE, 0x832, Method::BUFFERED, Access::WRITE),
-    OLS_READ_IO_PORT_BYTE = io_control_code(DEVICE_TYPE, 0x833, Method::BUFFERED, Access::READ),
-    OLS_READ_IO_PORT_WORD = io_control_code(DEVICE_TYPE, 0x834, Method::BUFFERED, Access::READ),
-    OLS_READ_IO_PORT_DWORD = io_control_code(DEVICE_TYPE, 0x835, Method::BUFFERED, Access::READ),
-    OLS_WRITE_IO_PORT_BYTE = io_control_code(DEVICE_TYPE, 0x836, Method::BUFFERED, Access::WRITE),
-    OLS_WRITE_IO_PORT_WORD = io_control_code(DEVICE_TYPE, 0x837, Method::BUFFERED, Access::WRITE),
-    OLS_WRITE_IO_PORT_DWORD = io_control_code(DEVICE_TYPE, 0x838, Method::BUFFERED, Access::WRITE),
+    OLS_READ_IO_PORT_BYTE = protocol::OLS_READ_IO_PORT_BYTE.raw(),
+    OLS_READ_IO_PORT_WORD = protocol::OLS_READ_IO_PORT_WORD.raw(),
+    OLS_READ_IO_PORT_DWORD = protocol::OLS_READ_IO_PORT_DWORD.raw(),
+    OLS_WRITE_IO_PORT_BYTE = protocol::OLS_WRITE_IO_PORT_BYTE.raw(),
+    OLS_WRITE_IO_PORT_WORD = protocol::OLS_WRITE_IO_PORT_WORD.raw(),
+    OLS_WRITE_IO_PORT_DWORD = protocol::OLS_WRITE_IO_PORT_DWORD.raw(),
     OLD_READ_MEMORY = io_control_code(DEVICE_TYPE, 0x841, Method::BUFFERED, Access::READ),
     OLS_WRITE_MEMORY = io_control_code(DEVICE_TYPE, 0x842, Method::BUFFERED, Access::WRITE),
-    OLS_READ_PCI_CONFIG = io_control_code(DEVICE_TYPE, 0x851, Method::BUFFERED, Access::READ),
-    OLS_WRITE_PCI_CONFIG = io_control_code(DEVICE_TYPE, 0x852, Method::BUFFERED, Access::READ)
+    OLS_READ_PCI_CONFIG = protocol::OLS_READ_PCI_CONFIG.raw(),
+    OLS_WRITE_PCI_CONFIG = protocol::OLS_WRITE_PCI_CONFIG.raw()
+}
+
+impl IOCTL {
+    /// The decoded IO control code
+    pub const fn code(self) -> IoControlCode {
+        IoControlCode::from_raw(self as u32)
+    }
+}
+
+impl From<IOCTL> for IoControlCode {
+    fn from(ioctl: IOCTL) -> Self {
+        ioctl.code()
+    }
 }
 
@@ -10,15 +10,15 @@
 //! For more information visit https://github.com/openhardwaremonitor/openhardwaremonitor.
 //! 
 //! # Example
-//! ```
+//! ```no_run
 //! use win_ring0::WinRing0;
 //! 
 //! pub fn main() {
-//!     let mut r0: Box<WinRing0> = Box::from(WinRing0::new());
+//!     let mut r0: Box<WinRing0> = Box::from(WinRing0::new().unwrap());
 //! 
 //!     println!("Installing ring0 driver");
 //!     match r0.install() {
-//!         Ok(()) => { println!("Driver installed"); }
+//!         Ok(_) => { println!("Driver installed"); }
 //!         Err(err) => { println!("Error: {}", err); }
 //!     }
 //! 
@@ -53,10 +53,27 @@
 //! }
 //! ```
 mod ioctl;
+mod error;
+mod protocol;
+mod pci;
+mod pci_ids;
 
 #[allow(non_snake_case)]
 mod winRing0;
 
 pub use ioctl::IOCTL;
 pub use winRing0::WinRing0;
+pub use winRing0::Ring0;
+pub use winRing0::Ring0Session;
 pub use ioctl::DEVICE_TYPE;
+pub use protocol::Ring0Protocol;
+pub use protocol::WriteMsrInput;
+pub use protocol::WriteIoPortInput;
+pub use protocol::ReadPciConfigInput;
+pub use protocol::WritePciConfigInput;
+pub use pci::PciAddress;
+pub use pci::PciBus;
+pub use pci::PciDevice;
+pub use pci_ids::PciIds;
+pub use error::Error;
+pub use error::Result;
@@ -0,0 +1,461 @@
+//! PCI configuration space
+//!
+//! winRing0 reads and writes 1, 2 or 4 bytes at a time, taking the width from the
+//! size of the buffers it is given. Devices that don't exist read as all ones.
+//!
+//! [PciBus] finds the devices, [PciIds] names them.
+use std::fmt;
+
+use win_kernel_driver::{DeviceIo, Error as DriverError};
+
+use super::error::{Error, Result};
+use super::protocol::{self, Ring0Protocol};
+use super::pci_ids::PciIds;
+
+/// Size of PCI Express extended configuration space
+const CONFIG_SPACE_SIZE: u32 = 0x1000;
+/// Offset of the vendor id, all ones if there is no device
+const VENDOR_ID: u16 = 0x00;
+/// Set in the header type of function 0 of multi-function devices
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+/// Header type of PCI-to-PCI bridges
+const HEADER_BRIDGE: u8 = 0x01;
+/// Header type of CardBus bridges
+const HEADER_CARDBUS: u8 = 0x02;
+
+/// Bus, device and function of a PCI device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    /// Bus number
+    pub bus: u8,
+    /// Device number, below 32
+    pub device: u8,
+    /// Function number, below 8
+    pub function: u8
+}
+
+impl PciAddress {
+    /// Function `function` of device `device` on bus `bus`. Fails with
+    /// [Error::InvalidPciAddress] if the device is above 31 or the function above 7.
+    pub fn new(bus: u8, device: u8, function: u8) -> Result<Self> {
+        let address = PciAddress { bus, device, function };
+        address.check()?;
+
+        Ok(address)
+    }
+
+    /// winRing0's encoding of the address: bus in bits 8-15, device in bits 3-7 and
+    /// function in bits 0-2
+    pub const fn raw(self) -> u32 {
+        ((self.bus as u32) << 8) | (((self.device & 0x1f) as u32) << 3) | ((self.function & 0x07) as u32)
+    }
+
+    /// The address encoded as returned by [PciAddress::raw]
+    pub const fn from_raw(raw: u32) -> Self {
+        PciAddress {
+            bus: ((raw >> 8) & 0xff) as u8,
+            device: ((raw >> 3) & 0x1f) as u8,
+            function: (raw & 0x07) as u8
+        }
+    }
+
+    fn check(self) -> Result<()> {
+        if self.device >= 32 || self.function >= 8 {
+            return Err(Error::InvalidPciAddress(self));
+        }
+        Ok(())
+    }
+}
+
+/// Formats like `lspci`, e.g. `00:1f.3`
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{:x}", self.bus, self.device, self.function)
+    }
+}
+
+/// Read `size` bytes at `offset` from the configuration space of `address`
+pub(crate) fn read_config<D: DeviceIo>(protocol: &Ring0Protocol<D>, address: PciAddress, offset: u16, size: usize) -> Result<u32> {
+    check_access(address, offset, size)?;
+
+    let value = read_unchecked(protocol, address, offset, size)?;
+
+    // All ones is a valid value for most registers, but not for the vendor id
+    if value == all_ones(size) {
+        let vendor = match (offset, size) {
+            (VENDOR_ID, 2) | (VENDOR_ID, 4) => value & 0xffff,
+            _ => read_unchecked(protocol, address, VENDOR_ID, 2)?
+        };
+
+        if vendor == 0xffff {
+            return Err(Error::PciDeviceNotFound(address));
+        }
+    }
+
+    Ok(value)
+}
+
+/// Write the low `size` bytes of `value` at `offset` to the configuration space of
+/// `address`. Writes to devices that don't exist are dropped silently by the
+/// hardware, so the device is looked for first.
+pub(crate) fn write_config<D: DeviceIo>(protocol: &Ring0Protocol<D>, address: PciAddress, offset: u16, size: usize, value: u32) -> Result<()> {
+    check_access(address, offset, size)?;
+
+    if read_unchecked(protocol, address, VENDOR_ID, 2)? == 0xffff {
+        return Err(Error::PciDeviceNotFound(address));
+    }
+
+    // OLS_WRITE_PCI_CONFIG_INPUT, with as many data bytes as are written
+    let mut input = [0u8; 12];
+    input[0..4].copy_from_slice(&address.raw().to_le_bytes());
+    input[4..8].copy_from_slice(&(offset as u32).to_le_bytes());
+    input[8..12].copy_from_slice(&value.to_le_bytes());
+
+    protocol.device()
+        .io_bytes(protocol::OLS_WRITE_PCI_CONFIG, &input[..8 + size], &mut [])
+        .map_err(|err| config_error(address, offset, err))?;
+
+    Ok(())
+}
+
+fn read_unchecked<D: DeviceIo>(protocol: &Ring0Protocol<D>, address: PciAddress, offset: u16, size: usize) -> Result<u32> {
+    // OLS_READ_PCI_CONFIG_INPUT
+    let mut input = [0u8; 8];
+    input[0..4].copy_from_slice(&address.raw().to_le_bytes());
+    input[4..8].copy_from_slice(&(offset as u32).to_le_bytes());
+
+    let mut output = [0u8; 4];
+    let written = protocol.device()
+        .io_bytes(protocol::OLS_READ_PCI_CONFIG, &input, &mut output[..size])
+        .map_err(|err| config_error(address, offset, err))?;
+
+    if written < size {
+        return Err(Error::Driver(DriverError::ShortOutput { ioctl: protocol::OLS_READ_PCI_CONFIG, expected: size, actual: written }));
+    }
+
+    Ok(u32::from_le_bytes(output))
+}
+
+fn check_access(address: PciAddress, offset: u16, size: usize) -> Result<()> {
+    address.check()?;
+
+    let offset_end = offset as u32 + size as u32;
+    if !(offset as usize).is_multiple_of(size) || offset_end > CONFIG_SPACE_SIZE {
+        return Err(Error::InvalidPciOffset { address, offset, size });
+    }
+    Ok(())
+}
+
+fn all_ones(size: usize) -> u32 {
+    match size {
+        1 => 0xff,
+        2 => 0xffff,
+        _ => 0xffff_ffff
+    }
+}
+
+/// The driver reports a bus that doesn't exist or a failed access as a failed command
+fn config_error(address: PciAddress, offset: u16, err: DriverError) -> Error {
+    match err {
+        DriverError::IoctlFailed { code, .. } => Error::PciConfigFault { address, offset, code },
+        err => Error::Driver(err)
+    }
+}
+
+/// A function of a PCI device, found by [PciBus::scan]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Vendor of the board the device is on, 0 for bridges
+    pub subsystem_vendor_id: u16,
+    /// Id of the board the device is on, 0 for bridges
+    pub subsystem_id: u16,
+    /// Base class, e.g. `0x0c` for serial bus controllers
+    pub class: u8,
+    /// Sub class, e.g. `0x05` for SMBus controllers
+    pub subclass: u8,
+    /// Programming interface
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Header type, including the multi-function bit
+    pub header_type: u8,
+    /// Bus behind a PCI-to-PCI or CardBus bridge
+    pub secondary_bus: Option<u8>
+}
+
+impl PciDevice {
+    /// The device has more than one function. Only meaningful on function 0.
+    pub fn is_multi_function(&self) -> bool {
+        self.header_type & HEADER_MULTI_FUNCTION != 0
+    }
+
+    /// Class, sub class and programming interface as one 24 bit value, e.g. `0x0c0500`
+    pub fn class_code(&self) -> u32 {
+        ((self.class as u32) << 16) | ((self.subclass as u32) << 8) | self.prog_if as u32
+    }
+
+    /// Describe the device like `lspci`, e.g.
+    /// `00:1f.4 SMBus: Intel Corporation Cannon Lake PCH SMBus Controller (rev 10)`.
+    /// Ids missing from `ids` are printed as hex.
+    pub fn describe(&self, ids: &PciIds) -> String {
+        let class = ids.class(self.class, self.subclass, None)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("Class {:02x}{:02x}", self.class, self.subclass));
+        let vendor = ids.vendor(self.vendor_id)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("Device {:04x}", self.vendor_id));
+        let device = ids.device(self.vendor_id, self.device_id)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("Device {:04x}", self.device_id));
+
+        format!("{} {}: {} {} (rev {:02x})", self.address, class, vendor, device, self.revision)
+    }
+}
+
+/// Walks the PCI buses through winRing0, made by [Ring0::pci_bus](crate::Ring0::pci_bus)
+///
+/// # Example
+/// ```
+/// use win_ring0::{PciAddress, PciIds, ReadPciConfigInput, WinRing0, IOCTL};
+/// use win_kernel_driver::MockTransport;
+///
+/// let mock = MockTransport::new();
+/// let reads = [
+///     // Host bridge, single function
+///     ((0, 0, 0), 0x00, 0x1904_8086u32), ((0, 0, 0), 0x08, 0x0600_0008), ((0, 0, 0), 0x0c, 0x0000_0000), ((0, 0, 0), 0x2c, 0x8694_1043),
+///     // SMBus controller
+///     ((0, 0x1f, 0), 0x00, 0xa323_8086), ((0, 0x1f, 0), 0x08, 0x0c05_0010), ((0, 0x1f, 0), 0x0c, 0x0000_0000), ((0, 0x1f, 0), 0x2c, 0x8694_1043),
+/// ];
+/// for ((bus, device, function), offset, value) in reads.iter() {
+///     let address = PciAddress::new(*bus, *device, *function).unwrap();
+///     mock.expect(IOCTL::OLS_READ_PCI_CONFIG)
+///         .with_typed_input(&ReadPciConfigInput { pci_address: address.raw(), offset: *offset })
+///         .returns(&value.to_le_bytes())
+/// }
+/// // Every other device is missing
+/// mock.expect(IOCTL::OLS_READ_PCI_CONFIG).returns(&0xffff_ffffu32.to_le_bytes());
+///
+/// let mut r0 = WinRing0::with_transport(mock).unwrap();
+/// r0.open().unwrap();
+///
+/// let devices = r0.pci_bus().scan().unwrap();
+/// assert_eq!(devices.len(), 2);
+///
+/// let smbus = devices.iter().find(|device| device.class == 0x0c && device.subclass == 0x05).unwrap();
+/// assert_eq!(smbus.address, PciAddress::new(0, 0x1f, 0).unwrap());
+/// assert_eq!(smbus.subsystem_vendor_id, 0x1043);
+/// assert_eq!(
+///     smbus.describe(PciIds::bundled()),
+///     "00:1f.0 SMBus: Intel Corporation Cannon Lake PCH SMBus Controller (rev 10)"
+/// );
+/// ```
+pub struct PciBus<D> {
+    protocol: Ring0Protocol<D>
+}
+
+impl<D: DeviceIo> PciBus<D> {
+    /// Access the PCI buses through `device`
+    pub fn new(device: D) -> Self {
+        PciBus { protocol: Ring0Protocol::new(device) }
+    }
+
+    /// The device at `address`, or `None` if there is none or its bus can't be accessed
+    pub fn device(&self, address: PciAddress) -> Result<Option<PciDevice>> {
+        let ids = match read_config(&self.protocol, address, 0x00, 4) {
+            Ok(ids) => ids,
+            Err(Error::PciDeviceNotFound(_)) | Err(Error::PciConfigFault { .. }) => { return Ok(None); }
+            Err(err) => { return Err(err); }
+        };
+
+        let class = read_config(&self.protocol, address, 0x08, 4)?;
+        let header_type = (read_config(&self.protocol, address, 0x0c, 4)? >> 16) as u8;
+
+        let (subsystem, secondary_bus) = match header_type & !HEADER_MULTI_FUNCTION {
+            HEADER_BRIDGE => (0, Some((read_config(&self.protocol, address, 0x18, 4)? >> 8) as u8)),
+            HEADER_CARDBUS => (read_config(&self.protocol, address, 0x40, 4)?, Some((read_config(&self.protocol, address, 0x18, 4)? >> 8) as u8)),
+            _ => (read_config(&self.protocol, address, 0x2c, 4)?, None)
+        };
+
+        Ok(Some(PciDevice {
+            address,
+            vendor_id: ids as u16,
+            device_id: (ids >> 16) as u16,
+            subsystem_vendor_id: subsystem as u16,
+            subsystem_id: (subsystem >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            secondary_bus
+        }))
+    }
+
+    /// Every device reachable from bus 0, sorted by address.
+    ///
+    /// Functions 1-7 are only looked at on multi-function devices. Buses behind
+    /// bridges are scanned too, as are the root buses of the other functions of a
+    /// multi-function host bridge at `00:00.0`. Buses the driver can't access are
+    /// skipped.
+    pub fn scan(&self) -> Result<Vec<PciDevice>> {
+        let mut found = vec![];
+        let mut visited = [false; 256];
+        let mut pending = vec![0u8];
+
+        while let Some(bus) = pending.pop() {
+            if visited[bus as usize] {
+                continue;
+            }
+            visited[bus as usize] = true;
+
+            for device in 0..32 {
+                let first = match self.device(PciAddress::new(bus, device, 0)?)? {
+                    Some(first) => first,
+                    None => { continue; }
+                };
+                let functions = if first.is_multi_function() { 8 } else { 1 };
+
+                for function in 0..functions {
+                    let found_device = match function {
+                        0 => first,
+                        _ => match self.device(PciAddress::new(bus, device, function)?)? {
+                            Some(found_device) => found_device,
+                            None => { continue; }
+                        }
+                    };
+
+                    if let Some(secondary) = found_device.secondary_bus {
+                        pending.push(secondary);
+                    }
+
+                    // Function n of a multi-function host bridge at 00:00 is the host bridge of bus n
+                    let is_host_bridge = found_device.class == 0x06 && found_device.subclass == 0x00;
+                    if bus == 0 && device == 0 && function > 0 && is_host_bridge {
+                        pending.push(function);
+                    }
+
+                    found.push(found_device);
+                }
+            }
+        }
+
+        found.sort_by_key(|device| device.address);
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use win_kernel_driver::{MockTransport, OwnedDevice};
+
+    use super::*;
+    use crate::ioctl::IOCTL;
+    use crate::protocol::ReadPciConfigInput;
+
+    /// A bus whose configuration space holds `registers`, reading all ones everywhere else
+    fn pci_bus(registers: &[(PciAddress, u32, u32)]) -> PciBus<OwnedDevice> {
+        let mock = MockTransport::new();
+        for (address, offset, value) in registers {
+            mock.expect(IOCTL::OLS_READ_PCI_CONFIG)
+                .with_typed_input(&ReadPciConfigInput { pci_address: address.raw(), offset: *offset })
+                .returns(&value.to_le_bytes());
+        }
+        mock.expect(IOCTL::OLS_READ_PCI_CONFIG).returns(&0xffff_ffffu32.to_le_bytes());
+
+        PciBus::new(OwnedDevice::open(Arc::new(mock), r"\\.\WinRing0_1_2_0").unwrap())
+    }
+
+    fn addresses(devices: &[PciDevice]) -> Vec<PciAddress> {
+        devices.iter().map(|device| device.address).collect()
+    }
+
+    const HOST_BRIDGE: PciAddress = PciAddress { bus: 0, device: 0, function: 0 };
+
+    fn address(bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress::new(bus, device, function).unwrap()
+    }
+
+    /// Registers of a single function host bridge at 00:00.0
+    fn host_bridge() -> Vec<(PciAddress, u32, u32)> {
+        vec![(HOST_BRIDGE, 0x00, 0x1904_8086), (HOST_BRIDGE, 0x08, 0x0600_0008), (HOST_BRIDGE, 0x0c, 0x0000_0000), (HOST_BRIDGE, 0x2c, 0x8694_1043)]
+    }
+
+    #[test]
+    fn address_encodes_bus_device_and_function() {
+        let last = address(0x12, 0x1f, 7);
+
+        assert_eq!(last.raw(), 0x12ff);
+        assert_eq!(PciAddress::from_raw(0x12ff), last);
+        assert_eq!(address(0, 0x18, 3).raw(), 0x00c3);
+        assert_eq!(last.to_string(), "12:1f.7");
+    }
+
+    #[test]
+    fn address_rejects_device_and_function_out_of_range() {
+        assert!(matches!(PciAddress::new(0, 32, 0), Err(Error::InvalidPciAddress(PciAddress { device: 32, .. }))));
+        assert!(matches!(PciAddress::new(0, 0, 8), Err(Error::InvalidPciAddress(PciAddress { function: 8, .. }))));
+        assert!(PciAddress::new(0xff, 31, 7).is_ok());
+    }
+
+    #[test]
+    fn scan_looks_at_other_functions_of_multi_function_devices_only() {
+        let graphics = address(0, 0x02, 0);
+        let hidden = address(0, 0x02, 1);
+        let isa_bridge = address(0, 0x1f, 0);
+        let missing = address(0, 0x1f, 1);
+        let smbus = address(0, 0x1f, 4);
+
+        let mut registers = host_bridge();
+        registers.extend_from_slice(&[
+            (graphics, 0x00, 0x3e92_8086), (graphics, 0x08, 0x0300_0000), (graphics, 0x0c, 0x0000_0000), (graphics, 0x2c, 0x8694_1043),
+            // Never read, function 0 of 00:02 is single function
+            (hidden, 0x00, 0x1234_8086), (hidden, 0x08, 0x0480_0000), (hidden, 0x0c, 0x0000_0000), (hidden, 0x2c, 0x0000_0000),
+            (isa_bridge, 0x00, 0xa305_8086), (isa_bridge, 0x08, 0x0601_0010), (isa_bridge, 0x0c, 0x0080_0000), (isa_bridge, 0x2c, 0x8694_1043),
+            (missing, 0x00, 0xffff_ffff),
+            (smbus, 0x00, 0xa323_8086), (smbus, 0x08, 0x0c05_0010), (smbus, 0x0c, 0x0000_0000), (smbus, 0x2c, 0x8694_1043)
+        ]);
+
+        let devices = pci_bus(&registers).scan().unwrap();
+
+        assert_eq!(addresses(&devices), vec![HOST_BRIDGE, graphics, isa_bridge, smbus]);
+        assert!(devices[2].is_multi_function());
+        assert!(!devices[1].is_multi_function());
+        assert_eq!(devices[3].class_code(), 0x0c0500);
+        assert_eq!((devices[3].subsystem_vendor_id, devices[3].subsystem_id), (0x1043, 0x8694));
+    }
+
+    #[test]
+    fn scan_follows_bridges_to_their_secondary_bus() {
+        let bridge = address(0, 0x01, 0);
+        let nvme = address(2, 0, 0);
+
+        let mut registers = host_bridge();
+        registers.extend_from_slice(&[
+            // Primary bus 0, secondary and subordinate bus 2
+            (bridge, 0x00, 0x1901_8086), (bridge, 0x08, 0x0604_0000), (bridge, 0x0c, 0x0001_0000), (bridge, 0x18, 0x0002_0200),
+            (nvme, 0x00, 0xa808_144d), (nvme, 0x08, 0x0108_0200), (nvme, 0x0c, 0x0000_0000), (nvme, 0x2c, 0xa801_144d)
+        ]);
+
+        let devices = pci_bus(&registers).scan().unwrap();
+
+        assert_eq!(addresses(&devices), vec![HOST_BRIDGE, bridge, nvme]);
+        assert_eq!(devices[1].secondary_bus, Some(2));
+        assert_eq!(devices[1].subsystem_vendor_id, 0);
+        assert_eq!(devices[2].secondary_bus, None);
+        assert_eq!((devices[2].vendor_id, devices[2].device_id, devices[2].prog_if), (0x144d, 0xa808, 0x02));
+    }
+
+    #[test]
+    fn scan_skips_addresses_reading_all_ones() {
+        let devices = pci_bus(&[]).scan().unwrap();
+        assert!(devices.is_empty());
+
+        let bus = pci_bus(&host_bridge());
+        assert_eq!(bus.device(address(0, 0x1f, 0)).unwrap().map(|device| device.address), None);
+        assert_eq!(addresses(&bus.scan().unwrap()), vec![HOST_BRIDGE]);
+    }
+}
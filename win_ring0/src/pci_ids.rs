@@ -0,0 +1,309 @@
+//! Names of PCI vendors, devices and classes
+//!
+//! Parses the format of the [PCI ID repository](https://pci-ids.ucw.cz/), as used by
+//! `lspci`. A subset covering the chipsets and sensors monitoring tools look for is
+//! bundled, see [PciIds::bundled]; a full `pci.ids` can be read with [PciIds::parse].
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use super::error::{Error, Result};
+
+/// The bundled subset of the PCI ID repository
+const BUNDLED: &str = include_str!("../pci.ids");
+
+static BUNDLED_IDS: OnceLock<PciIds> = OnceLock::new();
+
+#[derive(Debug, Clone, Default)]
+struct Vendor {
+    name: String,
+    devices: HashMap<u16, Device>
+}
+
+#[derive(Debug, Clone, Default)]
+struct Device {
+    name: String,
+    subsystems: HashMap<(u16, u16), String>
+}
+
+#[derive(Debug, Clone, Default)]
+struct Class {
+    name: String,
+    subclasses: HashMap<u8, Subclass>
+}
+
+#[derive(Debug, Clone, Default)]
+struct Subclass {
+    name: String,
+    prog_ifs: HashMap<u8, String>
+}
+
+/// A database of PCI ids in `pci.ids` format
+///
+/// # Example
+/// ```
+/// use win_ring0::PciIds;
+///
+/// let ids = PciIds::parse("
+/// 1022  Advanced Micro Devices, Inc. [AMD]
+/// \t790b  FCH SMBus Controller
+/// \t\t1043 87c0  PRIME B450M-A
+/// C 0c  Serial bus controller
+/// \t05  SMBus
+/// ").unwrap();
+///
+/// assert_eq!(ids.vendor(0x1022), Some("Advanced Micro Devices, Inc. [AMD]"));
+/// assert_eq!(ids.device(0x1022, 0x790b), Some("FCH SMBus Controller"));
+/// assert_eq!(ids.subsystem(0x1022, 0x790b, 0x1043, 0x87c0), Some("PRIME B450M-A"));
+/// assert_eq!(ids.class(0x0c, 0x05, None), Some("SMBus"));
+/// assert_eq!(ids.device(0x1022, 0x1450), None);
+///
+/// assert!(PciIds::parse("\t790b  Device without a vendor").is_err());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PciIds {
+    vendors: HashMap<u16, Vendor>,
+    classes: HashMap<u8, Class>
+}
+
+impl PciIds {
+    /// The database bundled with this crate. Only covers the vendors and devices
+    /// monitoring tools look for, like SMBus controllers, AMD data fabric functions
+    /// and Intel thermal devices, but every base class.
+    pub fn bundled() -> &'static PciIds {
+        BUNDLED_IDS.get_or_init(|| PciIds::parse(BUNDLED).expect("bundled pci.ids is valid"))
+    }
+
+    /// Parse a database in `pci.ids` format. Fails with [Error::ParsePciIds] on a
+    /// malformed line.
+    pub fn parse(text: &str) -> Result<PciIds> {
+        let mut ids = PciIds::default();
+
+        // Where the last vendor, device, class and sub class were added
+        let mut vendor: Option<u16> = None;
+        let mut device: Option<u16> = None;
+        let mut class: Option<u8> = None;
+        let mut subclass: Option<u8> = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let parse_error = |reason| Error::ParsePciIds { line: index + 1, reason };
+
+            let line = line.trim_end();
+            if line.trim_start().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let depth = line.len() - line.trim_start_matches('\t').len();
+            let line = &line[depth..];
+
+            match depth {
+                0 if line.starts_with("C ") => {
+                    let (id, name) = split_id(&line[2..]).ok_or_else(|| parse_error("expected a class id and name"))?;
+                    let id = parse_hex_u8(id).ok_or_else(|| parse_error("invalid class id"))?;
+
+                    ids.classes.insert(id, Class { name: name.to_string(), ..Class::default() });
+                    class = Some(id);
+                    subclass = None;
+                    vendor = None;
+                    device = None;
+                },
+                0 => {
+                    let (id, name) = split_id(line).ok_or_else(|| parse_error("expected a vendor id and name"))?;
+                    let id = parse_hex_u16(id).ok_or_else(|| parse_error("invalid vendor id"))?;
+
+                    ids.vendors.insert(id, Vendor { name: name.to_string(), ..Vendor::default() });
+                    vendor = Some(id);
+                    device = None;
+                    class = None;
+                    subclass = None;
+                },
+                1 => {
+                    let (id, name) = split_id(line).ok_or_else(|| parse_error("expected an id and name"))?;
+
+                    if let Some(vendor) = vendor {
+                        let id = parse_hex_u16(id).ok_or_else(|| parse_error("invalid device id"))?;
+                        ids.vendors.get_mut(&vendor).unwrap().devices
+                            .insert(id, Device { name: name.to_string(), ..Device::default() });
+                        device = Some(id);
+                    } else if let Some(class) = class {
+                        let id = parse_hex_u8(id).ok_or_else(|| parse_error("invalid sub class id"))?;
+                        ids.classes.get_mut(&class).unwrap().subclasses
+                            .insert(id, Subclass { name: name.to_string(), ..Subclass::default() });
+                        subclass = Some(id);
+                    } else {
+                        return Err(parse_error("device or sub class without a vendor or class"));
+                    }
+                },
+                2 => {
+                    if let (Some(vendor), Some(device)) = (vendor, device) {
+                        let (subvendor, rest) = split_id(line).ok_or_else(|| parse_error("expected a subsystem vendor and device id"))?;
+                        let (subdevice, name) = split_id(rest).ok_or_else(|| parse_error("expected a subsystem device id and name"))?;
+                        let subvendor = parse_hex_u16(subvendor).ok_or_else(|| parse_error("invalid subsystem vendor id"))?;
+                        let subdevice = parse_hex_u16(subdevice).ok_or_else(|| parse_error("invalid subsystem device id"))?;
+
+                        ids.vendors.get_mut(&vendor).unwrap().devices.get_mut(&device).unwrap().subsystems
+                            .insert((subvendor, subdevice), name.to_string());
+                    } else if let (Some(class), Some(subclass)) = (class, subclass) {
+                        let (id, name) = split_id(line).ok_or_else(|| parse_error("expected a programming interface and name"))?;
+                        let id = parse_hex_u8(id).ok_or_else(|| parse_error("invalid programming interface"))?;
+
+                        ids.classes.get_mut(&class).unwrap().subclasses.get_mut(&subclass).unwrap().prog_ifs
+                            .insert(id, name.to_string());
+                    } else {
+                        return Err(parse_error("subsystem or programming interface without a device or sub class"));
+                    }
+                },
+                _ => { return Err(parse_error("too deeply indented")); }
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Name of a vendor
+    pub fn vendor(&self, vendor_id: u16) -> Option<&str> {
+        self.vendors.get(&vendor_id).map(|vendor| vendor.name.as_str())
+    }
+
+    /// Name of a device
+    pub fn device(&self, vendor_id: u16, device_id: u16) -> Option<&str> {
+        self.vendors.get(&vendor_id)?
+            .devices.get(&device_id)
+            .map(|device| device.name.as_str())
+    }
+
+    /// Name of the board a device is on
+    pub fn subsystem(&self, vendor_id: u16, device_id: u16, subsystem_vendor_id: u16, subsystem_id: u16) -> Option<&str> {
+        self.vendors.get(&vendor_id)?
+            .devices.get(&device_id)?
+            .subsystems.get(&(subsystem_vendor_id, subsystem_id))
+            .map(String::as_str)
+    }
+
+    /// Most specific name known for a class: the programming interface if given and
+    /// known, else the sub class, else the base class
+    pub fn class(&self, class: u8, subclass: u8, prog_if: Option<u8>) -> Option<&str> {
+        let class = self.classes.get(&class)?;
+        let subclass = match class.subclasses.get(&subclass) {
+            Some(subclass) => subclass,
+            None => { return Some(&class.name); }
+        };
+
+        prog_if
+            .and_then(|prog_if| subclass.prog_ifs.get(&prog_if))
+            .map(String::as_str)
+            .or(Some(&subclass.name))
+    }
+}
+
+/// Split `id  name` into its parts
+fn split_id(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    let end = line.find(|c: char| c.is_whitespace())?;
+    let name = line[end..].trim();
+
+    if name.is_empty() {
+        return None;
+    }
+    Some((&line[..end], name))
+}
+
+fn parse_hex_u16(id: &str) -> Option<u16> {
+    if id.len() != 4 {
+        return None;
+    }
+    u16::from_str_radix(id, 16).ok()
+}
+
+fn parse_hex_u8(id: &str) -> Option<u8> {
+    if id.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(id, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDS: &str = "\
+# Comments and blank lines are skipped
+#\tIndented lines in comments too
+
+8086  Intel Corporation
+\ta323  Cannon Lake PCH SMBus Controller
+\t\t1043 8694  PRIME H310M-D
+\t\t1462 7b98  Z390-A PRO
+\t1904  Xeon E3-1200 v5/E3-1500 v5/6th Gen Core Processor Host Bridge/DRAM Registers
+   \t
+C 0c  Serial bus controller
+\t03  USB controller
+\t\t30  XHCI
+\t\tfe  USB Device
+\t05  SMBus
+";
+
+    fn parse_error(text: &str) -> (usize, &'static str) {
+        match PciIds::parse(text) {
+            Err(Error::ParsePciIds { line, reason }) => (line, reason),
+            other => panic!("expected a parse error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn finds_vendor_device_and_subsystem() {
+        let ids = PciIds::parse(IDS).unwrap();
+
+        assert_eq!(ids.vendor(0x8086), Some("Intel Corporation"));
+        assert_eq!(ids.device(0x8086, 0xa323), Some("Cannon Lake PCH SMBus Controller"));
+        assert_eq!(ids.subsystem(0x8086, 0xa323, 0x1462, 0x7b98), Some("Z390-A PRO"));
+        assert!(ids.device(0x8086, 0x1904).unwrap().starts_with("Xeon E3-1200"));
+    }
+
+    #[test]
+    fn class_falls_back_to_less_specific_names() {
+        let ids = PciIds::parse(IDS).unwrap();
+
+        assert_eq!(ids.class(0x0c, 0x03, Some(0x30)), Some("XHCI"));
+        assert_eq!(ids.class(0x0c, 0x03, Some(0x10)), Some("USB controller"));
+        assert_eq!(ids.class(0x0c, 0x03, None), Some("USB controller"));
+        assert_eq!(ids.class(0x0c, 0x05, Some(0x00)), Some("SMBus"));
+        assert_eq!(ids.class(0x0c, 0x80, Some(0x00)), Some("Serial bus controller"));
+    }
+
+    #[test]
+    fn unknown_ids_are_none() {
+        let ids = PciIds::parse(IDS).unwrap();
+
+        assert_eq!(ids.vendor(0x1022), None);
+        assert_eq!(ids.device(0x1022, 0xa323), None);
+        assert_eq!(ids.device(0x8086, 0x0001), None);
+        assert_eq!(ids.subsystem(0x8086, 0xa323, 0x1043, 0x0001), None);
+        assert_eq!(ids.subsystem(0x8086, 0x1904, 0x1043, 0x8694), None);
+        assert_eq!(ids.class(0x06, 0x00, None), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(parse_error("808g  Not hex"), (1, "invalid vendor id"));
+        assert_eq!(parse_error("80860  Too long"), (1, "invalid vendor id"));
+        assert_eq!(parse_error("8086"), (1, "expected a vendor id and name"));
+        assert_eq!(parse_error("8086  Intel Corporation\n\tzz23  Not hex"), (2, "invalid device id"));
+        assert_eq!(parse_error("C 0c  Serial bus controller\n\t3  Too short"), (2, "invalid sub class id"));
+        assert_eq!(parse_error("8086  Intel Corporation\n\ta323  SMBus\n\t\t1043 8694"), (3, "expected a subsystem device id and name"));
+    }
+
+    #[test]
+    fn rejects_wrong_tab_depth() {
+        assert_eq!(parse_error("\ta323  Device without a vendor"), (1, "device or sub class without a vendor or class"));
+        assert_eq!(parse_error("8086  Intel Corporation\n\t\t1043 8694  Subsystem without a device"), (2, "subsystem or programming interface without a device or sub class"));
+        assert_eq!(parse_error("8086  Intel Corporation\n\ta323  SMBus\n\t\t\t1043  Too deep"), (3, "too deeply indented"));
+    }
+
+    #[test]
+    fn bundled_database_parses() {
+        let ids = PciIds::bundled();
+
+        assert_eq!(ids.vendor(0x8086), Some("Intel Corporation"));
+        assert_eq!(ids.class(0x0c, 0x05, None), Some("SMBus"));
+    }
+}
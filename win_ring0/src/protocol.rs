@@ -0,0 +1,84 @@
+//! Typed winRing0 commands
+//!
+//! The input structures mirror the ones in `OlsIoctl.h`, which packs them to 4 bytes.
+use win_kernel_driver::{driver_protocol, Pod};
+
+use super::ioctl::DEVICE_TYPE;
+
+/// `OLS_WRITE_MSR_INPUT`
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteMsrInput {
+    pub register: u32,
+    pub value: u64
+}
+
+unsafe impl Pod for WriteMsrInput {}
+
+/// `OLS_WRITE_IO_PORT_INPUT`. Byte and word writes use the low bits of `value`.
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteIoPortInput {
+    pub port: u32,
+    pub value: u32
+}
+
+unsafe impl Pod for WriteIoPortInput {}
+
+/// `OLS_READ_PCI_CONFIG_INPUT`
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadPciConfigInput {
+    pub pci_address: u32,
+    pub offset: u32
+}
+
+unsafe impl Pod for ReadPciConfigInput {}
+
+/// `OLS_WRITE_PCI_CONFIG_INPUT` for a 32bit write
+#[repr(C, packed(4))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WritePciConfigInput {
+    pub pci_address: u32,
+    pub offset: u32,
+    pub value: u32
+}
+
+unsafe impl Pod for WritePciConfigInput {}
+
+driver_protocol! {
+    /// Typed winRing0 commands, sent through an opened
+    /// [WinKernelDriver](win_kernel_driver::WinKernelDriver) or a
+    /// [DriverSession](win_kernel_driver::DriverSession). See [Ring0::protocol](crate::Ring0::protocol).
+    pub struct Ring0Protocol;
+    device_type = DEVICE_TYPE;
+
+    /// Version of the driver, one byte each for major, minor, revision and release
+    fn get_driver_version() -> u32 = OLS_GET_DRIVER_VERSION(0x800, BUFFERED, ANY);
+    /// Number of handles open to the driver
+    fn get_refcount() -> u32 = OLS_GET_REFCOUNT(0x801, BUFFERED, ANY);
+    /// Read a model specific register
+    fn read_msr(msr: u32) -> u64 = OLS_READ_MSR(0x821, BUFFERED, ANY);
+    /// Write a model specific register
+    fn write_msr(input: WriteMsrInput) -> () = OLS_WRITE_MSR(0x822, BUFFERED, ANY);
+    /// Read a performance monitoring counter
+    fn read_pmc(counter: u32) -> u64 = OLS_READ_PMC(0x823, BUFFERED, ANY);
+    /// Halt the processor
+    fn halt() -> () = OLS_HALT(0x824, BUFFERED, ANY);
+    /// Read a byte from an IO port, returned in the low bits
+    fn read_io_port_byte(port: u32) -> u32 = OLS_READ_IO_PORT_BYTE(0x833, BUFFERED, READ);
+    /// Read a word from an IO port, returned in the low bits
+    fn read_io_port_word(port: u32) -> u32 = OLS_READ_IO_PORT_WORD(0x834, BUFFERED, READ);
+    /// Read a double word from an IO port
+    fn read_io_port_dword(port: u32) -> u32 = OLS_READ_IO_PORT_DWORD(0x835, BUFFERED, READ);
+    /// Write a byte to an IO port
+    fn write_io_port_byte(input: WriteIoPortInput) -> () = OLS_WRITE_IO_PORT_BYTE(0x836, BUFFERED, WRITE);
+    /// Write a word to an IO port
+    fn write_io_port_word(input: WriteIoPortInput) -> () = OLS_WRITE_IO_PORT_WORD(0x837, BUFFERED, WRITE);
+    /// Write a double word to an IO port
+    fn write_io_port_dword(input: WriteIoPortInput) -> () = OLS_WRITE_IO_PORT_DWORD(0x838, BUFFERED, WRITE);
+    /// Read a double word from the PCI configuration space
+    fn read_pci_config(input: ReadPciConfigInput) -> u32 = OLS_READ_PCI_CONFIG(0x851, BUFFERED, READ);
+    /// Write a double word to the PCI configuration space
+    fn write_pci_config(input: WritePciConfigInput) -> () = OLS_WRITE_PCI_CONFIG(0x852, BUFFERED, WRITE);
+}
@@ -1,63 +1,421 @@
+use std::mem::size_of;
+use std::ops::Deref;
+
 use win_kernel_driver::WinKernelDriver;
 use win_kernel_driver::DriverBuilder;
+use win_kernel_driver::Error as DriverError;
+use win_kernel_driver::{include_driver, Concurrency, DeviceIo, DriverSession, EmbeddedDriver, InstallOutcome, Machine, ThreadAffinity, Transport, Win32Transport};
 use super::ioctl::IOCTL;
-use winapi::shared::minwindef::{DWORD};
+use super::protocol::{Ring0Protocol, WriteIoPortInput, WriteMsrInput};
+use super::pci::{self, PciAddress, PciBus};
+use super::error::{Error, Result};
+
+/// The 64bit driver, compressed by the build script
+static DRIVER_X64: EmbeddedDriver = include_driver!("WinRing0x64.sys");
+/// The 32bit driver, compressed by the build script
+static DRIVER_X86: EmbeddedDriver = include_driver!("WinRing0.sys");
 
 /// WinRing0 driver
+/// 
+/// Derefs to [Ring0], which has the MSR, IO port and PCI operations.
 pub struct WinRing0 { 
-    driver: WinKernelDriver
+    ring0: Ring0<WinKernelDriver>
 }
 
-impl<'a> WinRing0 {
-    pub fn new() -> Self {
-        let driver_x64 = include_bytes!("../winRing0x64.sys");
-        let driver_x86 = include_bytes!("../winRing0.sys");
+impl WinRing0 {
+    /// Create a WinRing0 for the real driver, see [WinRing0::with_transport]
+    pub fn new() -> Result<Self> {
+        Self::with_transport(Win32Transport)
+    }
+
+    /// Create a WinRing0 that talks to the device through `transport`.
+    /// 
+    /// Pass a [MockTransport](win_kernel_driver::MockTransport) to run code using
+    /// winRing0 without the real driver.
+    /// 
+    /// Nothing is written to disk until [WinRing0::install]. Fails with
+    /// [DriverError::UnsupportedPlatform] on other architectures than x86 and x64,
+    /// which winRing0 has no driver for.
+    /// 
+    /// # Example
+    /// ```
+    /// use win_ring0::{WinRing0, IOCTL};
+    /// use win_kernel_driver::MockTransport;
+    /// 
+    /// let mock = MockTransport::new();
+    /// mock.expect(IOCTL::OLS_READ_MSR)
+    ///     .with_input(&0x1a2u32.to_le_bytes())
+    ///     .returns(&0x0064_0000u64.to_le_bytes());
+    /// 
+    /// let mut r0 = WinRing0::with_transport(mock).unwrap();
+    /// r0.open().unwrap();
+    /// assert_eq!(r0.readMsr(0x1a2).unwrap(), 0x0064_0000);
+    /// ```
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Result<Self> {
+        // The driver has to match the architecture of Windows, not of this process
+        let embedded = match Machine::host() {
+            Machine::X64 => &DRIVER_X64,
+            Machine::X86 => &DRIVER_X86,
+            _ => { return Err(DriverError::UnsupportedPlatform.into()); }
+        };
 
         let driver = DriverBuilder::new()
             .set_device_description("Rust winRing0 driver")
             .set_device_id("WinRing0_1_2_0")
             .set_device_type(40000)
-            .set_driver_bin(driver_x64.to_vec())
-            .build().unwrap();
+            .set_embedded_driver(embedded)
+            .set_transport(transport)
+            .build()?;
+
+        Ok(WinRing0 {
+            ring0: Ring0 { device: driver, affinity: ThreadAffinity::new() }
+        })
+    }
 
-        WinRing0 {
-            driver: driver
-        }
+    /// Pin threads to logical processors through `affinity` in [Ring0::read_msr_on]
+    /// and [Ring0::write_msr_on] (defaults to [ThreadAffinity::new])
+    pub fn set_affinity(mut self, affinity: ThreadAffinity) -> Self {
+        self.ring0.affinity = affinity;
+        self
     }
 
     /// Install the winRing0 driver.
-    pub fn install(&self) -> Result<(), String> {
-        return self.driver.install();
+    /// 
+    /// Reuses a winRing0 service that is already installed, see [InstallOutcome].
+    pub fn install(&mut self) -> Result<InstallOutcome> {
+        Ok(self.ring0.device.install()?)
     }
 
     /// Open the winRing0 driver for communication
-    pub fn open(&mut self) -> Result<(), String> {
-        return self.driver.open();
+    pub fn open(&mut self) -> Result<()> {
+        Ok(self.ring0.device.open()?)
     }
 
     /// Close the winRing0 driver handle
-    pub fn close(&mut self) -> Result<(), String> {
-        self.driver.close()
+    pub fn close(&mut self) -> Result<()> {
+        Ok(self.ring0.device.close()?)
     }
 
     /// Uninstall the winRing0 driver
-    pub fn uninstall(&mut self) -> Result<(), String> {
-        self.driver.uninstall()
+    pub fn uninstall(&mut self) -> Result<()> {
+        Ok(self.ring0.device.uninstall()?)
+    }
+
+    /// Open a handle that can be cloned and used from several threads at once.
+    /// Commands sent through it are serialized. It does not need [WinRing0::open].
+    /// 
+    /// # Example
+    /// ```
+    /// use std::thread;
+    /// use win_ring0::{WinRing0, IOCTL};
+    /// use win_kernel_driver::MockTransport;
+    /// 
+    /// let mock = MockTransport::new();
+    /// mock.expect(IOCTL::OLS_READ_MSR).returns(&0x0064_0000u64.to_le_bytes());
+    /// 
+    /// let r0 = WinRing0::with_transport(mock).unwrap();
+    /// let session = r0.session().unwrap();
+    /// 
+    /// let reader = session.clone();
+    /// let tj_max = thread::spawn(move || reader.readMsr(0x1a2).unwrap()).join().unwrap();
+    /// assert_eq!(tj_max, 0x0064_0000);
+    /// ```
+    pub fn session(&self) -> Result<Ring0Session> {
+        Ok(Ring0 {
+            device: self.ring0.device.session(Concurrency::Serialized)?,
+            affinity: self.ring0.affinity.clone()
+        })
+    }
+}
+
+impl Deref for WinRing0 {
+    type Target = Ring0<WinKernelDriver>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ring0
+    }
+}
+
+/// The winRing0 operations, sent through a [WinKernelDriver] by [WinRing0] or through
+/// a [DriverSession] by [Ring0Session]
+#[derive(Clone)]
+pub struct Ring0<D> {
+    device: D,
+    affinity: ThreadAffinity
+}
+
+impl<D: DeviceIo> Ring0<D> {
+    /// Typed access to every winRing0 command. A [WinRing0] has to be opened first.
+    /// 
+    /// # Example
+    /// ```
+    /// use win_ring0::{WinRing0, IOCTL};
+    /// use win_kernel_driver::MockTransport;
+    /// 
+    /// let mock = MockTransport::new();
+    /// mock.expect(IOCTL::OLS_GET_DRIVER_VERSION).returns(&0x0102_0005u32.to_le_bytes());
+    /// 
+    /// let mut r0 = WinRing0::with_transport(mock).unwrap();
+    /// r0.open().unwrap();
+    /// assert_eq!(r0.protocol().get_driver_version().unwrap(), 0x0102_0005);
+    /// ```
+    pub fn protocol(&self) -> Ring0Protocol<&D> {
+        Ring0Protocol::new(&self.device)
     }
 
     /// Read an MSR register
-    pub fn readMsr(&self, msr: DWORD) -> Result<u64, String> {
-        match self.driver.io(IOCTL::OLS_READ_MSR as u32, msr) {
-            Ok(res) => { return Ok(res); }
-            Err(err) => { return Err(format!("Error reading msr: {}", err)); }
-        }
-    }
-
-    /// Raw IO function. See [WinKernelDriver::io] for more information
-    pub fn io(&self, ioctl: IOCTL, in_buffer: u32) -> Result<u64, String> {
-        match self.driver.io(ioctl as u32, in_buffer) {
-            Ok(res) => { return Ok(res); },
-            Err(err) => { return Err(format!("Error doing IO: {}", err)); }
-        }
+    /// 
+    /// Returns [Error::MsrFault] if the driver faulted reading the register.
+    pub fn readMsr(&self, msr: u32) -> Result<u64> {
+        msr_result(msr, self.protocol().read_msr(msr))
+    }
+
+    /// Write an MSR register
+    /// 
+    /// Returns [Error::MsrFault] if the driver faulted writing the register, usually
+    /// because it does not exist or the value sets reserved bits.
+    pub fn write_msr(&self, msr: u32, value: u64) -> Result<()> {
+        write_msr(&self.protocol(), msr, value)
+    }
+
+    /// Read an MSR register on logical processor `cpu`. Most MSRs, like
+    /// `IA32_THERM_STATUS`, exist once per core or thread.
+    /// 
+    /// The calling thread is pinned to `cpu` for the read and moved back afterwards.
+    /// Fails with [InvalidProcessor](win_kernel_driver::Error::InvalidProcessor) if there is no such processor.
+    /// 
+    /// # Example
+    /// ```
+    /// use win_ring0::{WinRing0, IOCTL};
+    /// use win_kernel_driver::MockTransport;
+    /// # if cfg!(not(any(windows, target_os = "linux"))) { return; }
+    /// 
+    /// // IA32_THERM_STATUS, digital readout in bits 16-22
+    /// let mock = MockTransport::new();
+    /// mock.expect(IOCTL::OLS_READ_MSR).with_input(&0x19cu32.to_le_bytes()).returns(&0x8823_0000u64.to_le_bytes());
+    /// 
+    /// let mut r0 = WinRing0::with_transport(mock).unwrap();
+    /// r0.open().unwrap();
+    /// let readout = (r0.read_msr_on(0, 0x19c).unwrap() >> 16) & 0x7f;
+    /// assert_eq!(readout, 0x23);
+    /// assert!(r0.read_msr_on(usize::MAX, 0x19c).is_err());
+    /// ```
+    pub fn read_msr_on(&self, cpu: usize, msr: u32) -> Result<u64> {
+        self.affinity.run_on(cpu, || self.readMsr(msr))?
+    }
+
+    /// Write an MSR register on logical processor `cpu`, see [Ring0::read_msr_on]
+    /// and [Ring0::write_msr]
+    pub fn write_msr_on(&self, cpu: usize, msr: u32, value: u64) -> Result<()> {
+        self.affinity.run_on(cpu, || self.write_msr(msr, value))?
+    }
+
+    /// Change the bits of an MSR register selected by `mask` to those of `value`,
+    /// leaving the others alone. Bits of `value` outside `mask` are ignored, and the
+    /// register is not written if nothing changes. Returns the previous value.
+    /// 
+    /// The register is not locked between reading and writing it.
+    /// 
+    /// # Example
+    /// ```
+    /// use win_ring0::{WinRing0, WriteMsrInput, IOCTL};
+    /// use win_kernel_driver::MockTransport;
+    /// 
+    /// // MSR_TEMPERATURE_TARGET, TCC activation offset in bits 24-29
+    /// let mock = MockTransport::new();
+    /// mock.expect(IOCTL::OLS_READ_MSR).returns(&0x0064_0000u64.to_le_bytes());
+    /// mock.expect(IOCTL::OLS_WRITE_MSR)
+    ///     .with_typed_input(&WriteMsrInput { register: 0x1a2, value: 0x0a64_0000 })
+    ///     .returns(&[]);
+    /// 
+    /// let mut r0 = WinRing0::with_transport(mock).unwrap();
+    /// r0.open().unwrap();
+    /// let previous = r0.modify_msr(0x1a2, 0x3f00_0000, 10 << 24).unwrap();
+    /// assert_eq!(previous, 0x0064_0000);
+    /// ```
+    pub fn modify_msr(&self, msr: u32, mask: u64, value: u64) -> Result<u64> {
+        modify_msr(&self.protocol(), msr, mask, value)
+    }
+
+    /// Read a byte from an IO port
+    /// 
+    /// # Example
+    /// ```
+    /// use win_ring0::{WinRing0, WriteIoPortInput, IOCTL};
+    /// use win_kernel_driver::MockTransport;
+    /// 
+    /// // Read the CMOS seconds register through the index and data ports
+    /// let mock = MockTransport::new();
+    /// mock.expect(IOCTL::OLS_WRITE_IO_PORT_BYTE)
+    ///     .with_typed_input(&WriteIoPortInput { port: 0x70, value: 0x00 })
+    ///     .returns(&[]);
+    /// mock.expect(IOCTL::OLS_READ_IO_PORT_BYTE)
+    ///     .with_input(&0x71u32.to_le_bytes())
+    ///     .returns(&0x42u32.to_le_bytes());
+    /// 
+    /// let mut r0 = WinRing0::with_transport(mock).unwrap();
+    /// r0.open().unwrap();
+    /// r0.write_port_u8(0x70, 0x00).unwrap();
+    /// assert_eq!(r0.read_port_u8(0x71).unwrap(), 0x42);
+    /// ```
+    pub fn read_port_u8(&self, port: u16) -> Result<u8> {
+        read_port_u8(&self.protocol(), port)
+    }
+
+    /// Read a word from an IO port
+    pub fn read_port_u16(&self, port: u16) -> Result<u16> {
+        read_port_u16(&self.protocol(), port)
+    }
+
+    /// Read a double word from an IO port
+    pub fn read_port_u32(&self, port: u16) -> Result<u32> {
+        read_port_u32(&self.protocol(), port)
+    }
+
+    /// Write a byte to an IO port
+    pub fn write_port_u8(&self, port: u16, value: u8) -> Result<()> {
+        write_port_u8(&self.protocol(), port, value)
+    }
+
+    /// Write a word to an IO port
+    pub fn write_port_u16(&self, port: u16, value: u16) -> Result<()> {
+        write_port_u16(&self.protocol(), port, value)
+    }
+
+    /// Write a double word to an IO port
+    pub fn write_port_u32(&self, port: u16, value: u32) -> Result<()> {
+        write_port_u32(&self.protocol(), port, value)
+    }
+
+    /// Read a byte from the configuration space of a PCI device.
+    /// 
+    /// Fails with [Error::PciDeviceNotFound] if there is no device at `address`, and
+    /// with [Error::InvalidPciOffset] unless the access stays within the 4096 bytes of
+    /// configuration space.
+    /// 
+    /// # Example
+    /// ```
+    /// use win_ring0::{Error, PciAddress, ReadPciConfigInput, WinRing0, IOCTL};
+    /// use win_kernel_driver::MockTransport;
+    /// 
+    /// let host_bridge = PciAddress::new(0, 0, 0).unwrap();
+    /// let missing = PciAddress::new(0, 0x1f, 7).unwrap();
+    /// 
+    /// let mock = MockTransport::new();
+    /// mock.expect(IOCTL::OLS_READ_PCI_CONFIG)
+    ///     .with_typed_input(&ReadPciConfigInput { pci_address: host_bridge.raw(), offset: 0 })
+    ///     .returns(&0x1480_1022u32.to_le_bytes());
+    /// mock.expect(IOCTL::OLS_READ_PCI_CONFIG)
+    ///     .with_typed_input(&ReadPciConfigInput { pci_address: missing.raw(), offset: 0 })
+    ///     .returns(&0xffff_ffffu32.to_le_bytes());
+    /// 
+    /// let mut r0 = WinRing0::with_transport(mock).unwrap();
+    /// r0.open().unwrap();
+    /// 
+    /// // AMD vendor id
+    /// assert_eq!(r0.read_pci_config_u32(host_bridge, 0).unwrap() & 0xffff, 0x1022);
+    /// assert!(matches!(r0.read_pci_config_u32(missing, 0), Err(Error::PciDeviceNotFound(_))));
+    /// assert!(matches!(r0.read_pci_config_u32(host_bridge, 2), Err(Error::InvalidPciOffset { .. })));
+    /// ```
+    pub fn read_pci_config_u8(&self, address: PciAddress, offset: u16) -> Result<u8> {
+        Ok(pci::read_config(&self.protocol(), address, offset, 1)? as u8)
+    }
+
+    /// Read a word from the configuration space of a PCI device, see
+    /// [Ring0::read_pci_config_u8]. `offset` has to be a multiple of 2.
+    pub fn read_pci_config_u16(&self, address: PciAddress, offset: u16) -> Result<u16> {
+        Ok(pci::read_config(&self.protocol(), address, offset, 2)? as u16)
+    }
+
+    /// Read a double word from the configuration space of a PCI device, see
+    /// [Ring0::read_pci_config_u8]. `offset` has to be a multiple of 4.
+    pub fn read_pci_config_u32(&self, address: PciAddress, offset: u16) -> Result<u32> {
+        pci::read_config(&self.protocol(), address, offset, 4)
+    }
+
+    /// Write a byte to the configuration space of a PCI device. Fails with
+    /// [Error::PciDeviceNotFound] if there is no device at `address`.
+    pub fn write_pci_config_u8(&self, address: PciAddress, offset: u16, value: u8) -> Result<()> {
+        pci::write_config(&self.protocol(), address, offset, 1, value as u32)
+    }
+
+    /// Write a word to the configuration space of a PCI device. `offset` has to be a
+    /// multiple of 2.
+    pub fn write_pci_config_u16(&self, address: PciAddress, offset: u16, value: u16) -> Result<()> {
+        pci::write_config(&self.protocol(), address, offset, 2, value as u32)
+    }
+
+    /// Write a double word to the configuration space of a PCI device. `offset` has to
+    /// be a multiple of 4.
+    pub fn write_pci_config_u32(&self, address: PciAddress, offset: u16, value: u32) -> Result<()> {
+        pci::write_config(&self.protocol(), address, offset, 4, value)
+    }
+
+    /// Enumerate PCI devices, see [PciBus]
+    pub fn pci_bus(&self) -> PciBus<&D> {
+        PciBus::new(&self.device)
+    }
+
+    /// Raw IO function. Sends a single `u32` and reads back up to 8 bytes, like
+    /// [WinKernelDriver::io]
+    pub fn io(&self, ioctl: IOCTL, in_buffer: u32) -> Result<u64> {
+        let mut out_buffer = [0u8; size_of::<u64>()];
+        self.device.io_bytes(ioctl.into(), &in_buffer.to_le_bytes(), &mut out_buffer)?;
+
+        Ok(u64::from_le_bytes(out_buffer))
+    }
+}
+
+/// A winRing0 handle shared between clones and threads, made by [WinRing0::session].
+/// The handle is closed when the last clone is dropped.
+pub type Ring0Session = Ring0<DriverSession>;
+
+fn write_msr<D: DeviceIo>(protocol: &Ring0Protocol<D>, msr: u32, value: u64) -> Result<()> {
+    msr_result(msr, protocol.write_msr(WriteMsrInput { register: msr, value }))
+}
+
+fn modify_msr<D: DeviceIo>(protocol: &Ring0Protocol<D>, msr: u32, mask: u64, value: u64) -> Result<u64> {
+    let previous = msr_result(msr, protocol.read_msr(msr))?;
+    let updated = (previous & !mask) | (value & mask);
+
+    if updated != previous {
+        write_msr(protocol, msr, updated)?;
+    }
+
+    Ok(previous)
+}
+
+// The driver takes ports as 32bit values, and returns byte and word reads in the low bits
+
+fn read_port_u8<D: DeviceIo>(protocol: &Ring0Protocol<D>, port: u16) -> Result<u8> {
+    Ok(protocol.read_io_port_byte(port as u32)? as u8)
+}
+
+fn read_port_u16<D: DeviceIo>(protocol: &Ring0Protocol<D>, port: u16) -> Result<u16> {
+    Ok(protocol.read_io_port_word(port as u32)? as u16)
+}
+
+fn read_port_u32<D: DeviceIo>(protocol: &Ring0Protocol<D>, port: u16) -> Result<u32> {
+    Ok(protocol.read_io_port_dword(port as u32)?)
+}
+
+fn write_port_u8<D: DeviceIo>(protocol: &Ring0Protocol<D>, port: u16, value: u8) -> Result<()> {
+    Ok(protocol.write_io_port_byte(WriteIoPortInput { port: port as u32, value: value as u32 })?)
+}
+
+fn write_port_u16<D: DeviceIo>(protocol: &Ring0Protocol<D>, port: u16, value: u16) -> Result<()> {
+    Ok(protocol.write_io_port_word(WriteIoPortInput { port: port as u32, value: value as u32 })?)
+}
+
+fn write_port_u32<D: DeviceIo>(protocol: &Ring0Protocol<D>, port: u16, value: u32) -> Result<()> {
+    Ok(protocol.write_io_port_dword(WriteIoPortInput { port: port as u32, value })?)
+}
+
+/// The driver reports a faulting `rdmsr` or `wrmsr` as a failed command
+fn msr_result<T>(msr: u32, result: std::result::Result<T, DriverError>) -> Result<T> {
+    match result {
+        Ok(res) => Ok(res),
+        Err(DriverError::IoctlFailed { code, .. }) => Err(Error::MsrFault { msr, code }),
+        Err(err) => Err(Error::Driver(err))
     }
 }